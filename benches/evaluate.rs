@@ -0,0 +1,167 @@
+/******************************************************************************
+*
+*  Copyright 2023 Stefan Majewsky <majewsky@gmx.net>
+*
+*  Licensed under the Apache License, Version 2.0 (the "License");
+*  you may not use this file except in compliance with the License.
+*  You may obtain a copy of the License at
+*
+*      http://www.apache.org/licenses/LICENSE-2.0
+*
+*  Unless required by applicable law or agreed to in writing, software
+*  distributed under the License is distributed on an "AS IS" BASIS,
+*  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+*  See the License for the specific language governing permissions and
+*  limitations under the License.
+*
+******************************************************************************/
+
+//! Compares the evaluation performance of RuleSet and FrozenRuleSet on policies that are modeled
+//! after the default policies of OpenStack services. Run with `cargo bench`.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use oslo_policy::{AttributeValue, FrozenRuleSet, Request, RuleSet, Token};
+
+mod policies {
+    pub mod keystone;
+    pub mod nova;
+}
+
+struct BenchToken {
+    roles: Vec<&'static str>,
+    api_attrs: HashMap<String, AttributeValue>,
+}
+
+impl Token for BenchToken {
+    fn get_api_attribute(&self, name: &str) -> Option<Cow<'_, AttributeValue>> {
+        self.api_attrs.get(name).map(Cow::Borrowed)
+    }
+
+    fn has_role(&self, role_name: &str) -> bool {
+        self.roles.contains(&role_name)
+    }
+}
+
+fn attrs(pairs: &[(&str, &str)]) -> HashMap<String, AttributeValue> {
+    (pairs.iter())
+        .map(|(k, v)| ((*k).to_owned(), AttributeValue::from(*v)))
+        .collect()
+}
+
+fn load(rules: &[(&str, &str)]) -> RuleSet {
+    let mut ruleset = RuleSet::new();
+    for (name, rule) in rules {
+        ruleset.add_rule(*name, rule).unwrap();
+    }
+    ruleset.check_cycles().unwrap();
+    ruleset
+}
+
+/// Evaluates every rule of the policy for the same request, once with the RuleSet, once with the
+/// FrozenRuleSet (by rule name and by rule ID).
+fn bench_policy(
+    c: &mut Criterion,
+    policy_name: &str,
+    rules: &[(&str, &str)],
+    token: &BenchToken,
+    target: &HashMap<String, AttributeValue>,
+) {
+    let ruleset = load(rules);
+    let frozen: FrozenRuleSet = load(rules).freeze();
+    let rule_names: Vec<&str> = rules.iter().map(|(name, _)| *name).collect();
+    let rule_ids: Vec<_> = (rule_names.iter())
+        .map(|name| frozen.rule_id(name).unwrap())
+        .collect();
+    let req = Request::new(token).with_target(target);
+
+    let mut group = c.benchmark_group(policy_name);
+    group.bench_function("RuleSet::evaluate", |b| {
+        b.iter(|| {
+            for name in &rule_names {
+                black_box(ruleset.evaluate(name, &req));
+            }
+        })
+    });
+    group.bench_function("FrozenRuleSet::evaluate", |b| {
+        b.iter(|| {
+            for name in &rule_names {
+                black_box(frozen.evaluate(name, &req));
+            }
+        })
+    });
+    group.bench_function("FrozenRuleSet::evaluate_rule", |b| {
+        b.iter(|| {
+            for id in &rule_ids {
+                black_box(frozen.evaluate_rule(*id, &req));
+            }
+        })
+    });
+    group.finish();
+}
+
+fn bench_nova(c: &mut Criterion) {
+    //Nova passes flat dicts like `{"project_id": ..., "user_id": ...}` as target objects, and its
+    //policy references their keys directly (e.g. `project_id:%(project_id)s`)
+    let token = BenchToken {
+        roles: vec!["member", "reader"],
+        api_attrs: attrs(&[
+            ("user_id", "6a1b6a7e4c1d4b8f9c6d0e2f3a4b5c6d"),
+            ("project_id", "0f2c3d4e5f60718293a4b5c6d7e8f901"),
+        ]),
+    };
+    let target = attrs(&[
+        ("user_id", "6a1b6a7e4c1d4b8f9c6d0e2f3a4b5c6d"),
+        ("project_id", "0f2c3d4e5f60718293a4b5c6d7e8f901"),
+    ]);
+    bench_policy(c, "nova", policies::nova::RULES, &token, &target);
+}
+
+fn bench_keystone(c: &mut Criterion) {
+    //Keystone passes the target object as a nested dict below the key `target`, and its policy
+    //references nested values with dotted paths (e.g. `user_id:%(target.user.id)s`); the same goes
+    //for the token on the left-hand side (e.g. `token.domain.id`)
+    let token = BenchToken {
+        roles: vec!["admin", "member", "reader"],
+        api_attrs: HashMap::from([
+            ("user_id".into(), "6a1b6a7e4c1d4b8f9c6d0e2f3a4b5c6d".into()),
+            ("domain_id".into(), "default".into()),
+            (
+                "token".into(),
+                AttributeValue::from_iter([(
+                    "domain",
+                    AttributeValue::from_iter([("id", "default")]),
+                )]),
+            ),
+        ]),
+    };
+    let target_object = AttributeValue::from_iter([
+        ("domain_id", AttributeValue::from("default")),
+        (
+            "user",
+            AttributeValue::from_iter([
+                ("id", "6a1b6a7e4c1d4b8f9c6d0e2f3a4b5c6d"),
+                ("domain_id", "default"),
+            ]),
+        ),
+        (
+            "project",
+            AttributeValue::from_iter([
+                ("id", "0f2c3d4e5f60718293a4b5c6d7e8f901"),
+                ("domain_id", "default"),
+            ]),
+        ),
+        ("role", AttributeValue::from_iter([("name", "member")])),
+        (
+            "token",
+            AttributeValue::from_iter([("user_id", "6a1b6a7e4c1d4b8f9c6d0e2f3a4b5c6d")]),
+        ),
+    ]);
+    let target = HashMap::from([("target".to_owned(), target_object)]);
+    bench_policy(c, "keystone", policies::keystone::RULES, &token, &target);
+}
+
+criterion_group!(benches, bench_nova, bench_keystone);
+criterion_main!(benches);
@@ -0,0 +1,178 @@
+/******************************************************************************
+*
+*  Copyright 2023 Stefan Majewsky <majewsky@gmx.net>
+*
+*  Licensed under the Apache License, Version 2.0 (the "License");
+*  you may not use this file except in compliance with the License.
+*  You may obtain a copy of the License at
+*
+*      http://www.apache.org/licenses/LICENSE-2.0
+*
+*  Unless required by applicable law or agreed to in writing, software
+*  distributed under the License is distributed on an "AS IS" BASIS,
+*  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+*  See the License for the specific language governing permissions and
+*  limitations under the License.
+*
+******************************************************************************/
+
+//! Default policy of OpenStack Keystone, modeled after the policy file generated by
+//! `oslopolicy-sample-generator --namespace keystone` for the 2023.2 release. Some rare and
+//! deprecated rules are left out, but the structure of the rules is the same as upstream.
+
+pub const RULES: &[(&str, &str)] = &[
+    ("admin_required", "role:admin or is_admin:1"),
+    ("service_role", "role:service"),
+    ("service_or_admin", "rule:admin_required or rule:service_role"),
+    ("owner", "user_id:%(user_id)s"),
+    ("admin_or_owner", "rule:admin_required or rule:owner"),
+    ("token_subject", "user_id:%(target.token.user_id)s"),
+    ("admin_or_token_subject", "rule:admin_required or rule:token_subject"),
+    ("service_admin_or_token_subject", "rule:service_or_admin or rule:token_subject"),
+    ("domain_managed_target_role", "'member':%(target.role.name)s or 'reader':%(target.role.name)s"),
+    ("identity:get_user", "(role:reader and system_scope:all) or (role:reader and domain_id:%(target.user.domain_id)s) or user_id:%(target.user.id)s"),
+    ("identity:list_users", "(role:reader and system_scope:all) or (role:reader and domain_id:%(target.domain_id)s)"),
+    ("identity:list_groups", "(role:reader and system_scope:all) or (role:reader and domain_id:%(target.domain_id)s)"),
+    ("identity:list_projects", "(role:reader and system_scope:all) or (role:reader and domain_id:%(target.domain_id)s)"),
+    ("identity:create_user", "(role:admin and system_scope:all) or (role:admin and domain_id:%(target.user.domain_id)s)"),
+    ("identity:update_user", "(role:admin and system_scope:all) or (role:admin and domain_id:%(target.user.domain_id)s)"),
+    ("identity:delete_user", "(role:admin and system_scope:all) or (role:admin and domain_id:%(target.user.domain_id)s)"),
+    ("identity:get_project", "(role:reader and system_scope:all) or (role:reader and domain_id:%(target.project.domain_id)s) or project_id:%(target.project.id)s"),
+    ("identity:create_project", "(role:admin and system_scope:all) or (role:admin and domain_id:%(target.project.domain_id)s)"),
+    ("identity:update_project", "(role:admin and system_scope:all) or (role:admin and domain_id:%(target.project.domain_id)s)"),
+    ("identity:delete_project", "(role:admin and system_scope:all) or (role:admin and domain_id:%(target.project.domain_id)s)"),
+    ("identity:create_project_tag", "(role:admin and system_scope:all) or (role:admin and domain_id:%(target.project.domain_id)s)"),
+    ("identity:update_project_tags", "(role:admin and system_scope:all) or (role:admin and domain_id:%(target.project.domain_id)s)"),
+    ("identity:delete_project_tag", "(role:admin and system_scope:all) or (role:admin and domain_id:%(target.project.domain_id)s)"),
+    ("identity:delete_project_tags", "(role:admin and system_scope:all) or (role:admin and domain_id:%(target.project.domain_id)s)"),
+    ("identity:get_project_tag", "(role:reader and system_scope:all) or (role:reader and domain_id:%(target.project.domain_id)s) or project_id:%(target.project.id)s"),
+    ("identity:list_project_tags", "(role:reader and system_scope:all) or (role:reader and domain_id:%(target.project.domain_id)s) or project_id:%(target.project.id)s"),
+    ("identity:list_user_projects", "(role:reader and system_scope:all) or (role:reader and domain_id:%(target.user.domain_id)s) or user_id:%(target.user.id)s"),
+    ("identity:list_groups_for_user", "(role:reader and system_scope:all) or (role:reader and domain_id:%(target.user.domain_id)s) or user_id:%(target.user.id)s"),
+    ("identity:get_group", "(role:reader and system_scope:all) or (role:reader and domain_id:%(target.group.domain_id)s)"),
+    ("identity:list_users_in_group", "(role:reader and system_scope:all) or (role:reader and domain_id:%(target.group.domain_id)s)"),
+    ("identity:check_user_in_group", "(role:reader and system_scope:all) or (role:reader and domain_id:%(target.group.domain_id)s)"),
+    ("identity:create_group", "(role:admin and system_scope:all) or (role:admin and domain_id:%(target.group.domain_id)s)"),
+    ("identity:update_group", "(role:admin and system_scope:all) or (role:admin and domain_id:%(target.group.domain_id)s)"),
+    ("identity:delete_group", "(role:admin and system_scope:all) or (role:admin and domain_id:%(target.group.domain_id)s)"),
+    ("identity:add_user_to_group", "(role:admin and system_scope:all) or (role:admin and domain_id:%(target.group.domain_id)s and domain_id:%(target.user.domain_id)s)"),
+    ("identity:remove_user_from_group", "(role:admin and system_scope:all) or (role:admin and domain_id:%(target.group.domain_id)s and domain_id:%(target.user.domain_id)s)"),
+    ("identity:list_domains", "role:reader and system_scope:all"),
+    ("identity:list_regions_for_credentials", "role:reader and system_scope:all"),
+    ("identity:list_endpoints", "role:reader and system_scope:all"),
+    ("identity:get_endpoint", "role:reader and system_scope:all"),
+    ("identity:list_services", "role:reader and system_scope:all"),
+    ("identity:get_service", "role:reader and system_scope:all"),
+    ("identity:list_limits:other", "role:reader and system_scope:all"),
+    ("identity:list_registered_limits:other", "role:reader and system_scope:all"),
+    ("identity:list_role_assignments_for_tree:system", "role:reader and system_scope:all"),
+    ("identity:list_system_grants_for_user", "role:reader and system_scope:all"),
+    ("identity:list_system_grants_for_group", "role:reader and system_scope:all"),
+    ("identity:check_system_grant_for_user", "role:reader and system_scope:all"),
+    ("identity:check_system_grant_for_group", "role:reader and system_scope:all"),
+    ("identity:get_domain_config", "role:reader and system_scope:all"),
+    ("identity:get_domain_config_default", "role:reader and system_scope:all"),
+    ("identity:list_identity_providers", "role:reader and system_scope:all"),
+    ("identity:get_identity_provider", "role:reader and system_scope:all"),
+    ("identity:list_mappings", "role:reader and system_scope:all"),
+    ("identity:get_mapping", "role:reader and system_scope:all"),
+    ("identity:list_service_providers", "role:reader and system_scope:all"),
+    ("identity:get_service_provider", "role:reader and system_scope:all"),
+    ("identity:list_policies", "role:reader and system_scope:all"),
+    ("identity:get_policy", "role:reader and system_scope:all"),
+    ("identity:list_trusts:system", "role:reader and system_scope:all"),
+    ("identity:create_domain", "role:admin and system_scope:all"),
+    ("identity:update_domain", "role:admin and system_scope:all"),
+    ("identity:delete_domain", "role:admin and system_scope:all"),
+    ("identity:create_endpoint", "role:admin and system_scope:all"),
+    ("identity:update_endpoint", "role:admin and system_scope:all"),
+    ("identity:delete_endpoint", "role:admin and system_scope:all"),
+    ("identity:create_service", "role:admin and system_scope:all"),
+    ("identity:update_service", "role:admin and system_scope:all"),
+    ("identity:delete_service", "role:admin and system_scope:all"),
+    ("identity:create_region", "role:admin and system_scope:all"),
+    ("identity:update_region", "role:admin and system_scope:all"),
+    ("identity:delete_region", "role:admin and system_scope:all"),
+    ("identity:create_registered_limits", "role:admin and system_scope:all"),
+    ("identity:update_registered_limit", "role:admin and system_scope:all"),
+    ("identity:delete_registered_limit", "role:admin and system_scope:all"),
+    ("identity:create_limits", "role:admin and system_scope:all"),
+    ("identity:update_limit", "role:admin and system_scope:all"),
+    ("identity:delete_limit", "role:admin and system_scope:all"),
+    ("identity:create_system_grant_for_user", "role:admin and system_scope:all"),
+    ("identity:revoke_system_grant_for_user", "role:admin and system_scope:all"),
+    ("identity:create_system_grant_for_group", "role:admin and system_scope:all"),
+    ("identity:revoke_system_grant_for_group", "role:admin and system_scope:all"),
+    ("identity:create_domain_config", "role:admin and system_scope:all"),
+    ("identity:update_domain_config", "role:admin and system_scope:all"),
+    ("identity:delete_domain_config", "role:admin and system_scope:all"),
+    ("identity:create_identity_provider", "role:admin and system_scope:all"),
+    ("identity:update_identity_provider", "role:admin and system_scope:all"),
+    ("identity:delete_identity_provider", "role:admin and system_scope:all"),
+    ("identity:create_mapping", "role:admin and system_scope:all"),
+    ("identity:update_mapping", "role:admin and system_scope:all"),
+    ("identity:delete_mapping", "role:admin and system_scope:all"),
+    ("identity:create_service_provider", "role:admin and system_scope:all"),
+    ("identity:update_service_provider", "role:admin and system_scope:all"),
+    ("identity:delete_service_provider", "role:admin and system_scope:all"),
+    ("identity:create_policy", "role:admin and system_scope:all"),
+    ("identity:update_policy", "role:admin and system_scope:all"),
+    ("identity:delete_policy", "role:admin and system_scope:all"),
+    ("identity:create_role", "role:admin and system_scope:all"),
+    ("identity:update_role", "role:admin and system_scope:all"),
+    ("identity:delete_role", "role:admin and system_scope:all"),
+    ("identity:create_domain_role", "role:admin and system_scope:all"),
+    ("identity:update_domain_role", "role:admin and system_scope:all"),
+    ("identity:delete_domain_role", "role:admin and system_scope:all"),
+    ("identity:get_domain", "(role:reader and system_scope:all) or token.domain.id:%(target.domain.id)s or token.project.domain.id:%(target.domain.id)s"),
+    ("identity:get_region", "@"),
+    ("identity:list_regions", "@"),
+    ("identity:list_roles", "(role:reader and system_scope:all) or (role:reader and domain_id:%(target.role.domain_id)s)"),
+    ("identity:get_role", "(role:reader and system_scope:all) or (role:reader and domain_id:%(target.role.domain_id)s)"),
+    ("identity:list_domain_roles", "role:reader and system_scope:all"),
+    ("identity:get_domain_role", "role:reader and system_scope:all"),
+    ("identity:list_implied_roles", "role:reader and system_scope:all"),
+    ("identity:get_implied_role", "role:reader and system_scope:all"),
+    ("identity:list_role_inference_rules", "role:reader and system_scope:all"),
+    ("identity:list_role_assignments", "(role:reader and system_scope:all) or (role:reader and domain_id:%(target.domain_id)s) or (role:reader and project_id:%(target.project_id)s)"),
+    ("identity:list_role_assignments_for_tree", "(role:reader and system_scope:all) or (role:reader and domain_id:%(target.project.domain_id)s) or (role:reader and project_id:%(target.project.id)s)"),
+    ("identity:check_grant", "(role:reader and system_scope:all) or (role:reader and domain_id:%(target.user.domain_id)s and domain_id:%(target.project.domain_id)s) or (role:reader and domain_id:%(target.group.domain_id)s and domain_id:%(target.project.domain_id)s)"),
+    ("identity:list_grants", "(role:reader and system_scope:all) or (role:reader and domain_id:%(target.user.domain_id)s and domain_id:%(target.project.domain_id)s) or (role:reader and domain_id:%(target.group.domain_id)s and domain_id:%(target.project.domain_id)s)"),
+    ("identity:create_grant", "(role:admin and system_scope:all) or (role:admin and domain_id:%(target.user.domain_id)s and domain_id:%(target.project.domain_id)s and rule:domain_managed_target_role) or (role:admin and domain_id:%(target.group.domain_id)s and domain_id:%(target.project.domain_id)s and rule:domain_managed_target_role)"),
+    ("identity:revoke_grant", "(role:admin and system_scope:all) or (role:admin and domain_id:%(target.user.domain_id)s and domain_id:%(target.project.domain_id)s and rule:domain_managed_target_role) or (role:admin and domain_id:%(target.group.domain_id)s and domain_id:%(target.project.domain_id)s and rule:domain_managed_target_role)"),
+    ("identity:get_credential", "(role:reader and system_scope:all) or user_id:%(target.credential.user_id)s"),
+    ("identity:list_credentials", "(role:reader and system_scope:all) or user_id:%(target.credential.user_id)s"),
+    ("identity:create_credential", "(role:admin and system_scope:all) or user_id:%(target.credential.user_id)s"),
+    ("identity:update_credential", "(role:admin and system_scope:all) or user_id:%(target.credential.user_id)s"),
+    ("identity:delete_credential", "(role:admin and system_scope:all) or user_id:%(target.credential.user_id)s"),
+    ("identity:get_application_credential", "(role:reader and system_scope:all) or user_id:%(target.user.id)s"),
+    ("identity:list_application_credentials", "(role:reader and system_scope:all) or user_id:%(target.user.id)s"),
+    ("identity:get_access_rule", "(role:reader and system_scope:all) or user_id:%(target.user.id)s"),
+    ("identity:list_access_rules", "(role:reader and system_scope:all) or user_id:%(target.user.id)s"),
+    ("identity:ec2_get_credential", "(role:reader and system_scope:all) or user_id:%(target.user.id)s"),
+    ("identity:ec2_list_credentials", "(role:reader and system_scope:all) or user_id:%(target.user.id)s"),
+    ("identity:list_user_trusts:trustor", "(role:reader and system_scope:all) or user_id:%(target.user.id)s"),
+    ("identity:create_application_credential", "user_id:%(user_id)s"),
+    ("identity:ec2_create_credential", "user_id:%(user_id)s"),
+    ("identity:delete_application_credential", "(role:admin and system_scope:all) or user_id:%(target.user.id)s"),
+    ("identity:delete_access_rule", "(role:admin and system_scope:all) or user_id:%(target.user.id)s"),
+    ("identity:ec2_delete_credential", "(role:admin and system_scope:all) or user_id:%(target.user.id)s"),
+    ("identity:get_trust", "(role:reader and system_scope:all) or user_id:%(target.trust.trustor_user_id)s or user_id:%(target.trust.trustee_user_id)s"),
+    ("identity:list_roles_for_trust", "(role:reader and system_scope:all) or user_id:%(target.trust.trustor_user_id)s or user_id:%(target.trust.trustee_user_id)s"),
+    ("identity:get_role_for_trust", "(role:reader and system_scope:all) or user_id:%(target.trust.trustor_user_id)s or user_id:%(target.trust.trustee_user_id)s"),
+    ("identity:delete_trust", "user_id:%(target.trust.trustor_user_id)s"),
+    ("identity:create_trust", "user_id:%(trust.trustor_user_id)s"),
+    ("identity:check_token", "rule:service_admin_or_token_subject"),
+    ("identity:validate_token", "rule:service_admin_or_token_subject"),
+    ("identity:revoke_token", "rule:admin_or_token_subject"),
+    ("identity:revocation_list", "rule:service_or_admin"),
+    ("identity:get_limit", "(role:reader and system_scope:all) or (role:reader and domain_id:%(target.limit.domain.id)s) or (role:reader and domain_id:%(target.limit.project.domain_id)s) or (role:reader and project_id:%(target.limit.project_id)s)"),
+    ("identity:get_limit_model", "@"),
+    ("identity:list_limits", "@"),
+    ("identity:get_registered_limit", "@"),
+    ("identity:list_registered_limits", "@"),
+    ("identity:get_auth_catalog", "rule:owner"),
+    ("identity:get_auth_projects", "rule:owner"),
+    ("identity:get_auth_domains", "rule:owner"),
+    ("identity:get_auth_system", "rule:owner"),
+];
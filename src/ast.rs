@@ -16,25 +16,91 @@
 *
 ******************************************************************************/
 
-#[cfg(test)]
-use std::fmt;
+use std::fmt::{self, Write};
+use std::str::FromStr;
+
+use crate::parser::{parse_rule, ParseError};
+
+/// A parsed policy rule.
+///
+/// Rules are usually added to a [RuleSet](crate::RuleSet) directly in string form, but they can
+/// also be parsed separately by using [FromStr]. This allows for inspecting a rule's syntax tree,
+/// or for adding the same rule to multiple RuleSets without parsing it multiple times.
+///
+/// The [Display](std::fmt::Display) impl serializes the rule back into the policy language in a
+/// normalized form, with only as much whitespace and parentheses as necessary.
+///
+/// ```
+/// # use oslo_policy::{Expression, Rule};
+/// let rule: Rule = "(role:admin) or (role:member  and project_id:%(project_id)s)".parse()?;
+/// assert_eq!(rule.to_string(), "role:admin or role:member and project_id:%(project_id)s");
+/// assert!(matches!(rule.expression(), Expression::Or(_, _)));
+/// # Ok::<(), oslo_policy::ParseError>(())
+/// ```
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Rule {
+    pub(crate) expr: Expression,
+}
+
+impl Rule {
+    /// Returns the syntax tree of this rule.
+    pub fn expression(&self) -> &Expression {
+        &self.expr
+    }
+}
+
+impl FromStr for Rule {
+    type Err = ParseError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        parse_rule(None, input)
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.expr.fmt(f)
+    }
+}
 
-// NOTE: The types in here must be `pub` because peg::parser chokes if its output types are not
-// `pub`. However, this entire module is `pub(crate)`, so these types do not actually appear in the
-// public API.
+// NOTE: The types below must be `pub` anyway because peg::parser chokes if its output types are
+// not `pub`.
 
 /// A policy rule expression. This is the top-level type in the rule grammar.
+///
+/// Within a [Rule], expressions are always nested to the left if the same operator is used
+/// multiple times in a row: For example, `a and b and c` is parsed as `(a and b) and c`.
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub enum Expression {
+    /// A constant: `@` for true, or `!` for false.
     Const(bool),
+    /// A check like `role:admin`, consisting of a left-hand side and a right-hand side.
     Check(LeftHandSide, String),
+    /// A conjunction: `x and y`.
     And(Box<Expression>, Box<Expression>),
+    /// A disjunction: `x or y`.
     Or(Box<Expression>, Box<Expression>),
+    /// A negation: `not x`.
     Not(Box<Expression>),
 }
 
-/// Helper for quickly comparing [Expression] objects in unit tests.
-#[cfg(test)]
+impl Expression {
+    /// Calls the given function with the left-hand and right-hand side of each check within this
+    /// expression, in order of appearance.
+    pub fn visit_checks<'a>(&'a self, f: &mut impl FnMut(&'a LeftHandSide, &'a str)) {
+        use Expression::*;
+        match self {
+            Const(_) => {}
+            Check(lhs, rhs) => f(lhs, rhs),
+            And(x, y) | Or(x, y) => {
+                x.visit_checks(f);
+                y.visit_checks(f);
+            }
+            Not(x) => x.visit_checks(f),
+        }
+    }
+}
+
 impl fmt::Display for Expression {
     ///Generates the expression's simplest representation in the policy language.
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -45,7 +111,11 @@ impl fmt::Display for Expression {
             Const(true) => f.write_str("@"),
             Const(false) => f.write_str("!"),
             Check(lhs, rhs) => write!(f, "{lhs}:{rhs}"),
-            Not(e) => write!(f, "not {e}"),
+            Not(e) => match &**e {
+                //`not` binds more strongly than both `and` and `or`
+                And(_, _) | Or(_, _) => write!(f, "not ({e})"),
+                _ => write!(f, "not {e}"),
+            },
             Or(lhs, rhs) => write!(f, "{lhs} or {rhs}"),
             And(lhs, rhs) => match (&**lhs, &**rhs) {
                 (Or(_, _), Or(_, _)) => write!(f, "({lhs}) and ({rhs})"),
@@ -68,18 +138,40 @@ impl From<bool> for Expression {
 /// The left-hand side of a check.
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub enum LeftHandSide {
+    /// A string literal, e.g. `'foo'` in `'foo':%(name)s`.
     Literal(String),
+    /// An identifier that names either a [Checker](crate::Checker) or an API attribute, e.g.
+    /// `role` in `role:admin`.
     Identifier(String),
 }
 
-/// Helper for quickly comparing [Expression] objects in unit tests.
-#[cfg(test)]
 impl fmt::Display for LeftHandSide {
     ///Generates the LHS's simplest representation in the policy language.
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         use LeftHandSide::*;
         match self {
-            Literal(s) => write!(f, "'{s}'"),
+            Literal(s) => {
+                //escape everything that the parser does not accept within a literal (esp.
+                //whitespace and colons), as well as everything that is not printable
+                f.write_char('\'')?;
+                for c in s.chars() {
+                    match c {
+                        '\\' => f.write_str("\\\\")?,
+                        '\'' => f.write_str("\\'")?,
+                        '\n' => f.write_str("\\n")?,
+                        '\r' => f.write_str("\\r")?,
+                        '\t' => f.write_str("\\t")?,
+                        ':' => f.write_str("\\x3a")?,
+                        c if c.is_whitespace() || c.is_control() => match c as u32 {
+                            x @ 0..=0xFF => write!(f, "\\x{x:02x}")?,
+                            x @ 0..=0xFFFF => write!(f, "\\u{x:04x}")?,
+                            x => write!(f, "\\U{x:08x}")?,
+                        },
+                        c => f.write_char(c)?,
+                    }
+                }
+                f.write_char('\'')
+            }
             Identifier(s) => f.write_str(s),
         }
     }
@@ -110,9 +202,10 @@ pub mod build {
 #[cfg(test)]
 mod tests {
     use super::build::*;
+    use super::Rule;
 
-    //This serialization is only used internally, but we want it to be correct because it is
-    //used to write the parser test suite in a compact way.
+    //This serialization is used in evaluation traces, and also to write the parser test suite in
+    //a compact way.
     #[test]
     fn test_serialization() {
         //This test suite is inspired by the reference implementation's test suite.
@@ -144,5 +237,36 @@ mod tests {
 
         let expr = make_and(make_or(true, false), make_not(true));
         assert_eq!(expr.to_string(), "(@ or !) and not @");
+
+        let expr = make_not(make_or(true, false));
+        assert_eq!(expr.to_string(), "not (@ or !)");
+        let expr = make_not(make_and(true, make_not(false)));
+        assert_eq!(expr.to_string(), "not (@ and not !)");
+    }
+
+    #[test]
+    fn test_rule_roundtrip() {
+        let inputs = [
+            "@",
+            "not !",
+            "role:admin or role:member and project_id:%(project_id)s",
+            "(role:admin or role:member) and not (@ or !)",
+            "not not role:admin and ('foo':%(bar)s or \"foo\":%(bar)s)",
+            "rule:a or rule:b or (rule:c and rule:d and rule:e) or not rule:f",
+        ];
+        for input in inputs {
+            let rule: Rule = input.parse().unwrap();
+            let serialized = rule.to_string();
+            let reparsed: Rule = serialized.parse().unwrap();
+            assert_eq!(rule, reparsed, "input was {input:?}");
+            assert_eq!(serialized, reparsed.to_string(), "input was {input:?}");
+        }
+
+        let err = "role:admin or".parse::<Rule>().unwrap_err();
+        assert_eq!(err.rule_name(), None);
+        assert_eq!(
+            err.to_string(),
+            "could not parse rule: error at 1:14: expected one of \"not\", check or opening parenthesis"
+        );
     }
 }
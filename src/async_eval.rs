@@ -0,0 +1,382 @@
+/******************************************************************************
+*
+*  Copyright 2023 Stefan Majewsky <majewsky@gmx.net>
+*
+*  Licensed under the Apache License, Version 2.0 (the "License");
+*  you may not use this file except in compliance with the License.
+*  You may obtain a copy of the License at
+*
+*      http://www.apache.org/licenses/LICENSE-2.0
+*
+*  Unless required by applicable law or agreed to in writing, software
+*  distributed under the License is distributed on an "AS IS" BASIS,
+*  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+*  See the License for the specific language governing permissions and
+*  limitations under the License.
+*
+******************************************************************************/
+
+use std::future::Future;
+use std::pin::Pin;
+
+use crate::ast::{Expression, LeftHandSide};
+use crate::checkers::CheckError;
+use crate::interpolation::resolve_target_attr_refs;
+use crate::request::Request;
+use crate::ruleset::{and_outcome, or_outcome, Decision, Outcome, RuleSet, MAX_RULE_DEPTH};
+
+impl RuleSet {
+    /// Like [RuleSet::evaluate], but awaits [AsyncCheckers](crate::AsyncChecker) that are
+    /// encountered during evaluation. Like in synchronous evaluation, `and` and `or` only evaluate
+    /// their right operand if the left operand does not decide the result already, so async
+    /// checkers are only awaited when necessary.
+    ///
+    /// Synchronous checkers are executed as usual. For `rule:` checks, the referenced rule is also
+    /// evaluated asynchronously, unless the default [RuleChecker](crate::RuleChecker) was replaced
+    /// with a custom checker.
+    ///
+    /// The returned future is not [Send], see [AsyncChecker](crate::AsyncChecker) for details.
+    pub async fn evaluate_async(&self, rule_name: &str, req: &Request<'_>) -> bool {
+        self.evaluate_rule_async(rule_name, req)
+            .await
+            .unwrap_or(false)
+    }
+
+    /// Like [RuleSet::decide], but awaits [AsyncCheckers](crate::AsyncChecker) like
+    /// [RuleSet::evaluate_async] does.
+    pub async fn decide_async(&self, rule_name: &str, req: &Request<'_>) -> Decision {
+        let (decision, _) = self.decide_impl_async(rule_name, req).await;
+        decision
+    }
+
+    /// Like [RuleSet::try_decide], but awaits [AsyncCheckers](crate::AsyncChecker) like
+    /// [RuleSet::evaluate_async] does. Errors from async checkers are reported in the same way as
+    /// errors from [FallibleCheckers](crate::FallibleChecker).
+    pub async fn try_decide_async(
+        &self,
+        rule_name: &str,
+        req: &Request<'_>,
+    ) -> Result<Decision, CheckError> {
+        match self.decide_impl_async(rule_name, req).await {
+            (_, Some(err)) => Err(err),
+            (decision, None) => Ok(decision),
+        }
+    }
+
+    async fn decide_impl_async(
+        &self,
+        rule_name: &str,
+        req: &Request<'_>,
+    ) -> (Decision, Option<CheckError>) {
+        if let Some(decision) = self.decide_without_evaluation(rule_name, req) {
+            return (decision, None);
+        }
+        let outcome = self.evaluate_rule_async(rule_name, req).await;
+        self.finish_decision(req, outcome)
+    }
+
+    //NOTE: The recursive functions below return boxed futures because async fns cannot recurse
+    //into themselves directly.
+
+    fn evaluate_rule_async<'a>(
+        &'a self,
+        rule_name: &'a str,
+        req: &'a Request<'_>,
+    ) -> Pin<Box<dyn Future<Output = Outcome> + 'a>> {
+        Box::pin(async move {
+            if let Some(result) = req.state.memoized_rule_result(self, rule_name) {
+                return Ok(result);
+            }
+            let Some(_guard) = req.state.descend(MAX_RULE_DEPTH) else {
+                //fail closed if rules are nested too deeply (most likely because of a cycle)
+                return Ok(false);
+            };
+            let checkpoint = req.state.memoization_checkpoint();
+            let outcome = match self.rules.get(rule_name) {
+                Some(rule) => self.evaluate_expr_async(req, rule_name, &rule.expr).await,
+                None => {
+                    if self.has_strict_references() {
+                        req.state.record_unknown_rule(rule_name);
+                    }
+                    Ok(false)
+                }
+            };
+            if let Ok(result) = outcome {
+                req.state
+                    .memoize_rule_result(self, rule_name, checkpoint, result);
+            }
+            outcome
+        })
+    }
+
+    fn evaluate_expr_async<'a>(
+        &'a self,
+        req: &'a Request<'_>,
+        rule_name: &'a str,
+        expr: &'a Expression,
+    ) -> Pin<Box<dyn Future<Output = Outcome> + 'a>> {
+        use Expression::*;
+        Box::pin(async move {
+            match expr {
+                Const(val) => Ok(*val),
+                Check(lhs, rhs) => self.evaluate_check_async(req, rule_name, lhs, rhs).await,
+                And(x, y) => {
+                    let x = self.evaluate_expr_async(req, rule_name, x).await;
+                    if matches!(x, Ok(false)) {
+                        return x;
+                    }
+                    let y = self.evaluate_expr_async(req, rule_name, y).await;
+                    and_outcome(x, || y)
+                }
+                Or(x, y) => {
+                    let x = self.evaluate_expr_async(req, rule_name, x).await;
+                    if matches!(x, Ok(true)) {
+                        return x;
+                    }
+                    let y = self.evaluate_expr_async(req, rule_name, y).await;
+                    or_outcome(x, || y)
+                }
+                Not(x) => self
+                    .evaluate_expr_async(req, rule_name, x)
+                    .await
+                    .map(|v| !v),
+            }
+        })
+    }
+
+    async fn evaluate_check_async(
+        &self,
+        req: &Request<'_>,
+        rule_name: &str,
+        lhs: &LeftHandSide,
+        rhs: &str,
+    ) -> Outcome {
+        //only `rule:` checks and async checkers need special treatment, everything else is
+        //evaluated in the same way as in synchronous evaluation
+        let LeftHandSide::Identifier(id) = lhs else {
+            return self.evaluate_check(req, rule_name, lhs, rhs, None);
+        };
+        let is_rule_check = id == "rule" && self.has_default_rule_checker();
+        let checker = self.async_checkers.get(id);
+        if checker.is_none() && !is_rule_check {
+            return self.evaluate_check(req, rule_name, lhs, rhs, None);
+        }
+        //If an interpolated variable is missing, the entire check fails.
+        let Ok(resolved_rhs) = resolve_target_attr_refs(rhs, req.target) else {
+            return Ok(false);
+        };
+        let Some(checker) = checker else {
+            return self.evaluate_rule_async(&resolved_rhs, req).await;
+        };
+        let memoize = checker.is_deterministic();
+        if memoize {
+            if let Some(result) = req.state.memoized_check_result(self, id, &resolved_rhs) {
+                return Ok(result);
+            }
+        }
+        let checkpoint = req.state.memoization_checkpoint();
+        match checker.check(self, req, &resolved_rhs).await {
+            Ok(result) => {
+                if memoize {
+                    (req.state).memoize_check_result(self, id, &resolved_rhs, checkpoint, result);
+                } else {
+                    req.state.record_unmemoizable_result();
+                }
+                Ok(result)
+            }
+            Err(source) => Err(CheckError {
+                rule_name: rule_name.to_owned(),
+                check: format!("{lhs}:{rhs}"),
+                source,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::error::Error;
+    use std::future::Future;
+    use std::pin::{pin, Pin};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread::{self, Thread};
+
+    use crate::checkers::AsyncChecker;
+    use crate::request::test::{PermissiveRuleChecker, Token};
+    use crate::request::Request;
+    use crate::ruleset::{Decision, RuleSet, MAX_RULE_DEPTH};
+
+    /// A minimal single-threaded executor, to show that no particular async runtime is needed.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        struct ThreadWaker(Thread);
+        impl Wake for ThreadWaker {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = pin!(future);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    /// A future that is pending once before completing, to simulate waiting for I/O.
+    struct YieldOnce(bool);
+
+    impl Future for YieldOnce {
+        type Output = ();
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    /// An async checker that matches if the quota for the resource named in the right-hand side
+    /// is positive, and counts how often it was called.
+    struct QuotaChecker {
+        quotas: HashMap<String, i64>,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl AsyncChecker for QuotaChecker {
+        fn check<'a>(
+            &'a self,
+            _ruleset: &'a RuleSet,
+            _req: &'a Request<'_>,
+            rhs: &'a str,
+        ) -> Pin<Box<dyn Future<Output = Result<bool, Box<dyn Error + Send + Sync>>> + 'a>>
+        {
+            Box::pin(async move {
+                self.calls.fetch_add(1, Ordering::SeqCst);
+                YieldOnce(false).await;
+                let quota = self.quotas.get(rhs).ok_or("no such resource")?;
+                Ok(*quota > 0)
+            })
+        }
+    }
+
+    #[test]
+    fn test_evaluate_async() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut ruleset = RuleSet::new();
+        ruleset.add_async_checker(
+            "quota",
+            QuotaChecker {
+                quotas: [("cores".to_owned(), 4), ("ram".to_owned(), 0)]
+                    .into_iter()
+                    .collect(),
+                calls: Arc::clone(&calls),
+            },
+        );
+        let rules = [
+            ("create", "role:member and quota:cores"),
+            ("resize", "role:member and quota:%(resource)s"),
+            ("grow", "rule:create and quota:ram"),
+            ("admin_create", "role:admin or rule:create"),
+            ("broken", "quota:gpus and role:member"),
+            ("irrelevant", "quota:gpus or role:member"),
+            ("cycle", "quota:cores and (rule:cycle or rule:cycle)"),
+        ];
+        for (name, rule) in rules {
+            ruleset.add_rule(name, rule).unwrap();
+        }
+
+        let member = Token {
+            roles: vec!["member".into()],
+            api_attrs: HashMap::new(),
+        };
+        let admin = Token {
+            roles: vec!["admin".into()],
+            api_attrs: HashMap::new(),
+        };
+        let req = Request::new(&member);
+        assert!(block_on(ruleset.evaluate_async("create", &req)));
+        assert!(!block_on(ruleset.evaluate_async("grow", &req)));
+        assert!(block_on(ruleset.evaluate_async("admin_create", &req)));
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+        let target: HashMap<String, String> = [("resource".into(), "ram".into())].into();
+        let req_with_target = Request::new(&member).with_target(&target);
+        assert!(!block_on(
+            ruleset.evaluate_async("resize", &req_with_target)
+        ));
+        assert_eq!(calls.load(Ordering::SeqCst), 5);
+
+        //async checkers are not awaited if the result is already decided
+        let req = Request::new(&admin);
+        assert_eq!(
+            block_on(ruleset.decide_async("admin_create", &req)),
+            Decision::Allowed
+        );
+        assert_eq!(
+            block_on(ruleset.decide_async("create", &req)),
+            Decision::Denied
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 5);
+
+        //once a cycle exceeds the maximum nesting depth, its other branches are not evaluated
+        let req = Request::new(&member);
+        assert!(!block_on(ruleset.evaluate_async("cycle", &req)));
+        assert_eq!(calls.load(Ordering::SeqCst), 5 + MAX_RULE_DEPTH);
+
+        //errors from async checkers are reported like for fallible checkers
+        let req = Request::new(&member);
+        assert_eq!(
+            block_on(ruleset.decide_async("broken", &req)),
+            Decision::Denied
+        );
+        assert_eq!(
+            block_on(ruleset.try_decide_async("irrelevant", &req)).unwrap(),
+            Decision::Allowed
+        );
+        let err = block_on(ruleset.try_decide_async("broken", &req)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"check "quota:gpus" in rule "broken" failed: no such resource"#
+        );
+        assert_eq!(
+            block_on(ruleset.try_decide_async("unknown", &req)).unwrap(),
+            Decision::UnknownRule {
+                rule_name: "unknown".into()
+            }
+        );
+
+        //synchronous evaluation cannot use async checkers
+        assert!(!ruleset.evaluate("create", &req));
+        let err = ruleset.try_decide("create", &req).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"check "quota:cores" in rule "create" failed: async checker cannot be used in synchronous evaluation"#
+        );
+    }
+
+    #[test]
+    fn test_evaluate_async_with_custom_rule_checker() {
+        let mut ruleset = RuleSet::new();
+        ruleset.add_rule("a", "rule:b").unwrap();
+        ruleset.add_rule("b", "role:admin").unwrap();
+        ruleset.add_checker("rule", PermissiveRuleChecker);
+
+        let token = Token {
+            roles: vec![],
+            api_attrs: HashMap::new(),
+        };
+        let req = Request::new(&token);
+        assert!(ruleset.evaluate("a", &req));
+        assert!(block_on(ruleset.evaluate_async("a", &req)));
+    }
+}
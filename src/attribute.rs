@@ -0,0 +1,597 @@
+/******************************************************************************
+*
+*  Copyright 2023 Stefan Majewsky <majewsky@gmx.net>
+*
+*  Licensed under the Apache License, Version 2.0 (the "License");
+*  you may not use this file except in compliance with the License.
+*  You may obtain a copy of the License at
+*
+*      http://www.apache.org/licenses/LICENSE-2.0
+*
+*  Unless required by applicable law or agreed to in writing, software
+*  distributed under the License is distributed on an "AS IS" BASIS,
+*  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+*  See the License for the specific language governing permissions and
+*  limitations under the License.
+*
+******************************************************************************/
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+/// The value of an API attribute of a [Token](crate::Token), or of an attribute of a
+/// [Target](crate::Target).
+///
+/// Attribute values can be nested. Checks can refer to values within nested maps with a dotted
+/// path: For example, if the token has an API attribute `project` that is a map containing the key
+/// `domain`, which is itself a map containing the key `id`, then the check `project.domain.id:foo`
+/// compares the innermost value with `foo`. When resolving a dotted path, keys that contain dots
+/// themselves take precedence, so a flat attribute named `project.domain.id` is found as well.
+/// Lists within the path are traversed as described for [AttributeValue::get_path].
+///
+/// When a value is compared with the right-hand side of a check, it is formatted like Python's
+/// `str()` would format it, e.g. `is_admin:True` matches a [Bool](AttributeValue::Bool) value of
+/// `true`. The only exception are [lists](AttributeValue::List), which match if any of their
+/// elements match.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AttributeValue {
+    /// A string value.
+    String(String),
+    /// A boolean value. Formatted as `True` or `False`, like in Python.
+    Bool(bool),
+    /// An integer value.
+    Int(i64),
+    /// A floating-point value. Formatted like in Python, e.g. `1.0` or `1e+20`.
+    Float(f64),
+    /// A list of values.
+    List(Vec<AttributeValue>),
+    /// A map of named values.
+    Map(BTreeMap<String, AttributeValue>),
+    /// An explicitly empty value, like Python's `None`.
+    Null,
+}
+
+impl AttributeValue {
+    /// Returns the value at the given dotted path within this value, or `None` if this value is
+    /// not a map or list, or does not contain anything at that path.
+    ///
+    /// Like in oslo.policy, lists within the path are traversed by resolving the rest of the path
+    /// within each of their elements. For example, `servers.id` resolves to `s-1` within
+    /// `{"servers": [{"id": "s-1"}]}`. If the path resolves within multiple elements, the result is
+    /// a list of all values that were found, which matches a check if any of them matches.
+    pub fn get_path(&self, path: &str) -> Option<Cow<'_, AttributeValue>> {
+        match self {
+            AttributeValue::Map(map) => {
+                if let Some(value) = map.get(path) {
+                    return Some(Cow::Borrowed(value));
+                }
+                path.rmatch_indices('.')
+                    .find_map(|(idx, _)| map.get(&path[..idx])?.get_path(&path[idx + 1..]))
+            }
+            AttributeValue::List(items) => {
+                let mut found: Vec<_> = items.iter().filter_map(|i| i.get_path(path)).collect();
+                if found.len() <= 1 {
+                    return found.pop();
+                }
+                let mut values = Vec::with_capacity(found.len());
+                for value in found {
+                    match value.into_owned() {
+                        AttributeValue::List(items) => values.extend(items),
+                        value => values.push(value),
+                    }
+                }
+                Some(Cow::Owned(AttributeValue::List(values)))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns whether this value matches the right-hand side of a check. Lists match if any of
+    /// their elements match, all other values match if their Python `str()` is equal to `rhs`.
+    pub(crate) fn matches(&self, rhs: &str) -> bool {
+        self.matches_with(|s| s == rhs)
+    }
+
+    /// Like [AttributeValue::matches], but `is_rhs` decides whether a string is equal to the
+    /// right-hand side. This avoids building the right-hand side when it needs to be interpolated.
+    pub(crate) fn matches_with(&self, is_rhs: impl Fn(&str) -> bool) -> bool {
+        match self {
+            AttributeValue::String(s) => is_rhs(s),
+            AttributeValue::List(items) => items.iter().any(|item| match item {
+                AttributeValue::String(s) => is_rhs(s),
+                //like in the reference implementation, membership does not recurse into sublists
+                other => is_rhs(&other.to_string()),
+            }),
+            other => is_rhs(&other.to_string()),
+        }
+    }
+
+    /// Returns the integer that this value represents, for use with the `%d` conversion. Strings
+    /// are parsed, floats are truncated towards zero and booleans become 0 or 1, like in Python.
+    pub(crate) fn as_int(&self) -> Option<i128> {
+        match self {
+            AttributeValue::String(s) => s.parse().ok(),
+            AttributeValue::Bool(b) => Some(i128::from(*b)),
+            AttributeValue::Int(i) => Some(i128::from(*i)),
+            AttributeValue::Float(f) if f.is_finite() => Some(f.trunc() as i128),
+            _ => None,
+        }
+    }
+
+    /// Returns whether this value is considered true in a boolean context, like Python's `bool()`:
+    /// Empty strings, lists and maps are false, as well as zero, `false` and null.
+    pub(crate) fn is_truthy(&self) -> bool {
+        match self {
+            AttributeValue::String(s) => !s.is_empty(),
+            AttributeValue::Bool(b) => *b,
+            AttributeValue::Int(i) => *i != 0,
+            AttributeValue::Float(f) => *f != 0.0,
+            AttributeValue::List(items) => !items.is_empty(),
+            AttributeValue::Map(map) => !map.is_empty(),
+            AttributeValue::Null => false,
+        }
+    }
+
+    /// Appends the Python literal representation of this value to `buf`, like Python's `repr()`.
+    pub(crate) fn write_repr(&self, buf: &mut String) {
+        match self {
+            AttributeValue::String(s) => write_str_repr(buf, s),
+            AttributeValue::Bool(true) => buf.push_str("True"),
+            AttributeValue::Bool(false) => buf.push_str("False"),
+            AttributeValue::Int(i) => buf.push_str(&i.to_string()),
+            AttributeValue::Float(f) => write_float_repr(buf, *f),
+            AttributeValue::Null => buf.push_str("None"),
+            AttributeValue::List(items) => {
+                buf.push('[');
+                for (idx, item) in items.iter().enumerate() {
+                    if idx > 0 {
+                        buf.push_str(", ");
+                    }
+                    item.write_repr(buf);
+                }
+                buf.push(']');
+            }
+            AttributeValue::Map(map) => {
+                buf.push('{');
+                for (idx, (key, value)) in map.iter().enumerate() {
+                    if idx > 0 {
+                        buf.push_str(", ");
+                    }
+                    write_str_repr(buf, key);
+                    buf.push_str(": ");
+                    value.write_repr(buf);
+                }
+                buf.push('}');
+            }
+        }
+    }
+}
+
+impl fmt::Display for AttributeValue {
+    /// Formats this value like Python's `str()` does. This is how the reference implementation
+    /// compares attribute values with the right-hand side of a check.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AttributeValue::String(s) => f.write_str(s),
+            other => {
+                let mut buf = String::new();
+                other.write_repr(&mut buf);
+                f.write_str(&buf)
+            }
+        }
+    }
+}
+
+impl From<String> for AttributeValue {
+    fn from(value: String) -> Self {
+        AttributeValue::String(value)
+    }
+}
+
+impl From<&str> for AttributeValue {
+    fn from(value: &str) -> Self {
+        AttributeValue::String(value.to_owned())
+    }
+}
+
+impl From<bool> for AttributeValue {
+    fn from(value: bool) -> Self {
+        AttributeValue::Bool(value)
+    }
+}
+
+impl From<i64> for AttributeValue {
+    fn from(value: i64) -> Self {
+        AttributeValue::Int(value)
+    }
+}
+
+impl From<i32> for AttributeValue {
+    fn from(value: i32) -> Self {
+        AttributeValue::Int(value.into())
+    }
+}
+
+impl From<f64> for AttributeValue {
+    fn from(value: f64) -> Self {
+        AttributeValue::Float(value)
+    }
+}
+
+impl<T: Into<AttributeValue>> From<Vec<T>> for AttributeValue {
+    fn from(value: Vec<T>) -> Self {
+        AttributeValue::List(value.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<T: Into<AttributeValue>> From<Option<T>> for AttributeValue {
+    /// Converts `None` into [AttributeValue::Null].
+    fn from(value: Option<T>) -> Self {
+        value.map_or(AttributeValue::Null, Into::into)
+    }
+}
+
+impl<K: Into<String>, V: Into<AttributeValue>> FromIterator<(K, V)> for AttributeValue {
+    /// Collects key-value pairs into an [AttributeValue::Map].
+    fn from_iter<T: IntoIterator<Item = (K, V)>>(iter: T) -> Self {
+        AttributeValue::Map(
+            iter.into_iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect(),
+        )
+    }
+}
+
+/// The value of an attribute of a [Target](crate::Target), as returned by
+/// [Target::get_attribute](crate::Target::get_attribute).
+///
+/// Targets that store plain strings (like `HashMap<String, String>`) can lend them as
+/// [AttributeRef::Str] instead of copying them into an [AttributeValue::String]. A string value
+/// behaves exactly like the same [AttributeValue::String].
+#[derive(Clone, Debug, PartialEq)]
+pub enum AttributeRef<'a> {
+    /// A string value that is borrowed from the target.
+    Str(&'a str),
+    /// Any other value, either borrowed from the target or computed during lookup.
+    Value(Cow<'a, AttributeValue>),
+}
+
+impl AttributeRef<'_> {
+    /// Converts this into an owned [AttributeValue], copying it if necessary.
+    pub fn into_owned(self) -> AttributeValue {
+        match self {
+            AttributeRef::Str(s) => AttributeValue::String(s.to_owned()),
+            AttributeRef::Value(value) => value.into_owned(),
+        }
+    }
+
+    /// Like [AttributeValue::as_int].
+    pub(crate) fn as_int(&self) -> Option<i128> {
+        match self {
+            AttributeRef::Str(s) => s.parse().ok(),
+            AttributeRef::Value(value) => value.as_int(),
+        }
+    }
+
+    /// Like [AttributeValue::write_repr].
+    pub(crate) fn write_repr(&self, buf: &mut String) {
+        match self {
+            AttributeRef::Str(s) => write_str_repr(buf, s),
+            AttributeRef::Value(value) => value.write_repr(buf),
+        }
+    }
+}
+
+impl fmt::Display for AttributeRef<'_> {
+    /// Formats this value like [AttributeValue] does.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AttributeRef::Str(s) => f.write_str(s),
+            AttributeRef::Value(value) => value.fmt(f),
+        }
+    }
+}
+
+impl<'a> From<&'a str> for AttributeRef<'a> {
+    fn from(value: &'a str) -> Self {
+        AttributeRef::Str(value)
+    }
+}
+
+impl<'a> From<&'a AttributeValue> for AttributeRef<'a> {
+    fn from(value: &'a AttributeValue) -> Self {
+        AttributeRef::Value(Cow::Borrowed(value))
+    }
+}
+
+impl From<AttributeValue> for AttributeRef<'_> {
+    fn from(value: AttributeValue) -> Self {
+        AttributeRef::Value(Cow::Owned(value))
+    }
+}
+
+/// Resolves a dotted path like [AttributeValue::get_path], but on the top level, keys are looked
+/// up with `get`. The entire path is tried as a key first, then successively shorter prefixes
+/// (with the rest of the path being resolved within the value found for the prefix).
+pub(crate) fn lookup_path<'a>(
+    path: &str,
+    get: impl Fn(&str) -> Option<Cow<'a, AttributeValue>>,
+) -> Option<Cow<'a, AttributeValue>> {
+    get(path).or_else(|| lookup_nested_path(path, get))
+}
+
+/// Like [lookup_path], but for [Target](crate::Target) attributes.
+pub(crate) fn lookup_target_path<'a>(
+    path: &str,
+    get: impl Fn(&str) -> Option<AttributeRef<'a>>,
+) -> Option<AttributeRef<'a>> {
+    get(path).or_else(|| {
+        let value = lookup_nested_path(path, |k| match get(k)? {
+            //strings do not contain nested values
+            AttributeRef::Str(_) => None,
+            AttributeRef::Value(value) => Some(value),
+        });
+        value.map(AttributeRef::Value)
+    })
+}
+
+/// The part of [lookup_path] that resolves the path within the values of its prefixes.
+fn lookup_nested_path<'a>(
+    path: &str,
+    get: impl Fn(&str) -> Option<Cow<'a, AttributeValue>>,
+) -> Option<Cow<'a, AttributeValue>> {
+    path.rmatch_indices('.').find_map(|(idx, _)| {
+        let tail = &path[idx + 1..];
+        match get(&path[..idx])? {
+            Cow::Borrowed(value) => value.get_path(tail),
+            Cow::Owned(value) => value.get_path(tail).map(|v| Cow::Owned(v.into_owned())),
+        }
+    })
+}
+
+/// Flattens nested maps of attributes into a single map, like oslo.policy does for target
+/// objects. The keys of nested values are joined with dots, e.g. `{"target": {"user_id": "foo"}}`
+/// becomes `{"target.user_id": "foo"}`.
+///
+/// The result implements [Target](crate::Target). Flattening is not required for lookups, since
+/// dotted paths are also resolved within nested maps, but it can help when porting code that
+/// expects flattened attributes.
+pub fn flatten_attributes<K: Into<String>>(
+    attrs: impl IntoIterator<Item = (K, AttributeValue)>,
+) -> HashMap<String, AttributeValue> {
+    fn visit(prefix: String, value: AttributeValue, result: &mut HashMap<String, AttributeValue>) {
+        match value {
+            AttributeValue::Map(map) => {
+                for (key, value) in map {
+                    visit(format!("{prefix}.{key}"), value, result);
+                }
+            }
+            other => {
+                result.insert(prefix, other);
+            }
+        }
+    }
+
+    let mut result = HashMap::new();
+    for (key, value) in attrs {
+        visit(key.into(), value, &mut result);
+    }
+    result
+}
+
+/// Appends the Python literal representation of a float value to `buf`, like Python's `repr()`.
+fn write_float_repr(buf: &mut String, value: f64) {
+    if value.is_nan() {
+        buf.push_str("nan");
+        return;
+    }
+    if value.is_infinite() {
+        buf.push_str(if value > 0.0 { "inf" } else { "-inf" });
+        return;
+    }
+
+    //Rust's `{:e}` produces the shortest digit sequence that roundtrips, same as Python's repr(),
+    //but we need to lay out those digits like Python does
+    let formatted = format!("{value:e}");
+    let (mantissa, exponent) = formatted
+        .split_once('e')
+        .expect("{:e} format always contains an exponent");
+    let exponent: i32 = exponent
+        .parse()
+        .expect("{:e} format has an integer exponent");
+    let (sign, mantissa) = match mantissa.strip_prefix('-') {
+        Some(m) => ("-", m),
+        None => ("", mantissa),
+    };
+    let digits: String = mantissa.chars().filter(|&c| c != '.').collect();
+    buf.push_str(sign);
+
+    if (-4..16).contains(&exponent) {
+        //fixed-point notation, always with at least one digit after the decimal point
+        if exponent < 0 {
+            buf.push_str("0.");
+            buf.push_str(&"0".repeat((-exponent - 1) as usize));
+            buf.push_str(&digits);
+        } else {
+            let int_len = exponent as usize + 1;
+            if digits.len() > int_len {
+                buf.push_str(&digits[..int_len]);
+                buf.push('.');
+                buf.push_str(&digits[int_len..]);
+            } else {
+                buf.push_str(&digits);
+                buf.push_str(&"0".repeat(int_len - digits.len()));
+                buf.push_str(".0");
+            }
+        }
+    } else {
+        //scientific notation with at least two exponent digits, e.g. `1e+20` or `1.5e-07`
+        buf.push_str(&digits[..1]);
+        if digits.len() > 1 {
+            buf.push('.');
+            buf.push_str(&digits[1..]);
+        }
+        let exp_sign = if exponent < 0 { '-' } else { '+' };
+        buf.push_str(&format!("e{exp_sign}{:02}", exponent.abs()));
+    }
+}
+
+/// Appends the Python literal representation of a string value to `buf`, like Python's `repr()`.
+fn write_str_repr(buf: &mut String, value: &str) {
+    //Python prefers single quotes, unless the string contains single quotes and no double quotes
+    let quote = if value.contains('\'') && !value.contains('"') {
+        '"'
+    } else {
+        '\''
+    };
+    buf.push(quote);
+    for c in value.chars() {
+        match c {
+            '\\' => buf.push_str("\\\\"),
+            '\n' => buf.push_str("\\n"),
+            '\r' => buf.push_str("\\r"),
+            '\t' => buf.push_str("\\t"),
+            c if c == quote => {
+                buf.push('\\');
+                buf.push(c);
+            }
+            c if c.is_control() || (c.is_whitespace() && c != ' ') => {
+                let escaped = match c as u32 {
+                    x @ 0..=0xFF => format!("\\x{x:02x}"),
+                    x @ 0..=0xFFFF => format!("\\u{x:04x}"),
+                    x => format!("\\U{x:08x}"),
+                };
+                buf.push_str(&escaped);
+            }
+            c => buf.push(c),
+        }
+    }
+    buf.push(quote);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example() -> AttributeValue {
+        AttributeValue::from_iter([
+            ("id", AttributeValue::from("p-1")),
+            (
+                "domain",
+                AttributeValue::from_iter([("id", "d-1"), ("name", "it's")]),
+            ),
+            ("tags.first", AttributeValue::from("foo")),
+        ])
+    }
+
+    #[test]
+    fn test_get_path() {
+        let value = example();
+        let get = |path| value.get_path(path).map(|v| v.to_string());
+        assert_eq!(get("id"), Some("p-1".into()));
+        assert_eq!(get("domain.id"), Some("d-1".into()));
+        assert_eq!(get("domain.name"), Some("it's".into()));
+        assert_eq!(get("tags.first"), Some("foo".into()));
+        assert_eq!(
+            get("domain"),
+            Some(r#"{'id': 'd-1', 'name': "it's"}"#.into())
+        );
+        assert_eq!(get("domain.id.foo"), None);
+        assert_eq!(get("domain.missing"), None);
+        assert_eq!(get("missing"), None);
+
+        //lists are traversed mid-path
+        let value = AttributeValue::from_iter([(
+            "servers",
+            AttributeValue::from(vec![
+                AttributeValue::from_iter([("id", "s-1"), ("zone", "a")]),
+                AttributeValue::from_iter([("id", "s-2")]),
+                AttributeValue::from("not a map"),
+            ]),
+        )]);
+        let get = |path| value.get_path(path).map(|v| v.to_string());
+        assert_eq!(get("servers.zone"), Some("a".into()));
+        assert_eq!(get("servers.id"), Some("['s-1', 's-2']".into()));
+        assert!(value.get_path("servers.id").unwrap().matches("s-2"));
+        assert_eq!(get("servers.missing"), None);
+    }
+
+    #[test]
+    fn test_python_formatting() {
+        let test_cases = [
+            (AttributeValue::from("foo"), "foo"),
+            (AttributeValue::from(true), "True"),
+            (AttributeValue::from(false), "False"),
+            (AttributeValue::from(-42), "-42"),
+            (AttributeValue::Null, "None"),
+            (AttributeValue::from(1.0), "1.0"),
+            (AttributeValue::from(-0.0), "-0.0"),
+            (AttributeValue::from(0.1), "0.1"),
+            (AttributeValue::from(123.456), "123.456"),
+            (AttributeValue::from(1e15), "1000000000000000.0"),
+            (AttributeValue::from(1e16), "1e+16"),
+            (AttributeValue::from(1.5e20), "1.5e+20"),
+            (AttributeValue::from(0.0001), "0.0001"),
+            (AttributeValue::from(0.00001234), "1.234e-05"),
+            (AttributeValue::from(f64::INFINITY), "inf"),
+            (AttributeValue::from(f64::NAN), "nan"),
+            (
+                AttributeValue::from(vec![
+                    AttributeValue::from("a"),
+                    AttributeValue::from(1),
+                    AttributeValue::Null,
+                    AttributeValue::from(vec![true]),
+                ]),
+                "['a', 1, None, [True]]",
+            ),
+            (
+                AttributeValue::from_iter([("b", AttributeValue::from(2.5)), ("a", "x".into())]),
+                "{'a': 'x', 'b': 2.5}",
+            ),
+        ];
+        for (value, expected) in test_cases {
+            assert_eq!(value.to_string(), expected, "value was {value:?}");
+        }
+    }
+
+    #[test]
+    fn test_matches() {
+        let tags = AttributeValue::from(vec![
+            AttributeValue::from("foo"),
+            AttributeValue::from(42),
+            AttributeValue::from(vec!["bar"]),
+        ]);
+        assert!(tags.matches("foo"));
+        assert!(tags.matches("42"));
+        assert!(tags.matches("['bar']"));
+        assert!(!tags.matches("bar"));
+        assert!(!tags.matches("qux"));
+
+        assert!(AttributeValue::from(true).matches("True"));
+        assert!(!AttributeValue::from(true).matches("true"));
+        assert!(AttributeValue::Null.matches("None"));
+        assert!(AttributeValue::from(3).matches("3"));
+        assert!(!AttributeValue::from(3).matches("3.0"));
+    }
+
+    #[test]
+    fn test_flatten_attributes() {
+        let AttributeValue::Map(map) = example() else {
+            unreachable!()
+        };
+        let flat = flatten_attributes([("project", AttributeValue::Map(map))]);
+        let mut keys: Vec<_> = flat.keys().map(|k| k.as_str()).collect();
+        keys.sort_unstable();
+        assert_eq!(
+            keys,
+            vec![
+                "project.domain.id",
+                "project.domain.name",
+                "project.id",
+                "project.tags.first"
+            ]
+        );
+        assert_eq!(flat["project.domain.id"], AttributeValue::from("d-1"));
+    }
+}
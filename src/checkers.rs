@@ -16,6 +16,11 @@
 *
 ******************************************************************************/
 
+use std::error::Error as StdError;
+use std::future::Future;
+use std::pin::Pin;
+use thiserror::Error;
+
 use crate::request::Request;
 use crate::ruleset::RuleSet;
 
@@ -33,6 +38,152 @@ pub trait Checker: Send + Sync + 'static {
     /// rule's registered name. The right-hand side of the check is supplied in the `rhs` argument.
     /// The Checker can also inspect the [Request] that was made by the user.
     fn check(&self, ruleset: &RuleSet, req: &Request, rhs: &str) -> bool;
+
+    /// Returns whether this checker always returns the same result for the same Request and
+    /// right-hand side. If so, results can be reused when the same check appears multiple times
+    /// during the evaluation of a request with [memoization](Request::with_memoization). Checkers
+    /// whose result depends on anything else (e.g. the current time) must return false.
+    fn is_deterministic(&self) -> bool {
+        true
+    }
+
+    /// Returns whether the result of this checker can depend on the target object of the
+    /// [Request], other than through target object attributes that are interpolated into the
+    /// right-hand side. If not, [RuleSet::partially_evaluate] can evaluate checks whose right-hand
+    /// side does not reference the target object before the target object is known. Otherwise,
+    /// such checks are retained in the residual rule. Checkers that inspect [Request::target] (or
+    /// that evaluate other rules) must return true.
+    fn depends_on_target(&self) -> bool {
+        true
+    }
+}
+
+/// Like [Checker], but for checks that can fail, e.g. because the right-hand side is malformed or
+/// because a lookup table is unavailable.
+///
+/// If a check fails, its result is unknown. If the result of a rule depends on it, the rule counts
+/// as false, and [RuleSet::try_decide] reports a [CheckError] (see [RuleSet::try_evaluate]).
+/// Errors of type [CheckError] (e.g. from calling [RuleSet::try_evaluate] within a checker) are
+/// passed through unchanged.
+/// Every [Checker] is also a FallibleChecker that never fails, so both kinds of checkers can be
+/// registered with [RuleSet::add_checker].
+pub trait FallibleChecker: Send + Sync + 'static {
+    /// Execute a check like [Checker::check], or return an error if the check could not be
+    /// executed.
+    fn try_check(
+        &self,
+        ruleset: &RuleSet,
+        req: &Request,
+        rhs: &str,
+    ) -> Result<bool, Box<dyn StdError + Send + Sync>>;
+
+    /// Like [Checker::is_deterministic]. Errors are never memoized.
+    fn is_deterministic(&self) -> bool {
+        true
+    }
+
+    /// Like [Checker::depends_on_target].
+    fn depends_on_target(&self) -> bool {
+        true
+    }
+}
+
+impl<C: Checker> FallibleChecker for C {
+    fn try_check(
+        &self,
+        ruleset: &RuleSet,
+        req: &Request,
+        rhs: &str,
+    ) -> Result<bool, Box<dyn StdError + Send + Sync>> {
+        Ok(self.check(ruleset, req, rhs))
+    }
+
+    fn is_deterministic(&self) -> bool {
+        Checker::is_deterministic(self)
+    }
+
+    fn depends_on_target(&self) -> bool {
+        Checker::depends_on_target(self)
+    }
+}
+
+/// Like [FallibleChecker], but for checks that need to wait for I/O, e.g. for consulting a quota
+/// service or for loading a cache.
+///
+/// Async checkers are registered with [RuleSet::add_async_checker] and are only executed by the
+/// async evaluation methods like [RuleSet::decide_async]. This trait does not depend on any
+/// particular async runtime. When an async checker is encountered during synchronous evaluation, the
+/// check fails with a [CheckError].
+///
+/// The returned futures, and the futures returned by the async evaluation methods, are not [Send]:
+/// [Request] carries evaluation state that is not thread-safe, and [Token](crate::Token) and
+/// [Target](crate::Target) are not required to be [Sync]. Therefore, they cannot be passed to
+/// multi-threaded executors like `tokio::spawn`, and cannot be awaited directly in a handler
+/// that needs to be [Send], e.g. an axum handler. Use a single-threaded executor like
+/// `tokio::task::LocalSet` instead, or build the Request and await the decision on a dedicated
+/// thread and send the [Decision](crate::Decision) back over a channel. Within an async checker,
+/// I/O can still be delegated to a multi-threaded runtime by awaiting a `JoinHandle`.
+///
+/// Since the returned future may borrow from its arguments, implementations usually look like this:
+///
+/// ```
+/// # use oslo_policy::{AsyncChecker, Request, RuleSet};
+/// # use std::{error::Error, future::Future, pin::Pin};
+/// # type BoxError = Box<dyn Error + Send + Sync>;
+/// # async fn lookup_quota(project_id: &str, resource: &str) -> Result<u64, BoxError> { Ok(1) }
+/// struct QuotaChecker;
+///
+/// impl AsyncChecker for QuotaChecker {
+///     fn check<'a>(
+///         &'a self,
+///         _ruleset: &'a RuleSet,
+///         req: &'a Request<'_>,
+///         rhs: &'a str,
+///     ) -> Pin<Box<dyn Future<Output = Result<bool, Box<dyn Error + Send + Sync>>> + 'a>> {
+///         Box::pin(async move {
+///             let project_id = req.token.get_api_attribute("project_id");
+///             let project_id = project_id.ok_or("token is not project-scoped")?;
+///             Ok(lookup_quota(&project_id.to_string(), rhs).await? > 0)
+///         })
+///     }
+/// }
+/// ```
+pub trait AsyncChecker: Send + Sync + 'static {
+    /// Execute a check like [FallibleChecker::try_check], but asynchronously.
+    #[allow(clippy::type_complexity)]
+    fn check<'a>(
+        &'a self,
+        ruleset: &'a RuleSet,
+        req: &'a Request<'_>,
+        rhs: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<bool, Box<dyn StdError + Send + Sync>>> + 'a>>;
+
+    /// Like [Checker::is_deterministic]. Errors are never memoized.
+    fn is_deterministic(&self) -> bool {
+        true
+    }
+}
+
+/// Error type for when a [FallibleChecker] or [AsyncChecker] fails, as returned by
+/// [RuleSet::try_decide] and [RuleSet::try_decide_async].
+#[derive(Error, Debug)]
+#[error("check {check:?} in rule {rule_name:?} failed: {source}")]
+pub struct CheckError {
+    pub(crate) rule_name: String,
+    pub(crate) check: String,
+    pub(crate) source: Box<dyn StdError + Send + Sync>,
+}
+
+impl CheckError {
+    /// Returns the name of the rule containing the failed check.
+    pub fn rule_name(&self) -> &str {
+        &self.rule_name
+    }
+
+    /// Returns the failed check as written in the policy, e.g. `quota:%(project_id)s`.
+    pub fn check(&self) -> &str {
+        &self.check
+    }
 }
 
 /// A [Checker] that matches if the user has a certain role.
@@ -47,12 +198,18 @@ impl Checker for RoleChecker {
     fn check(&self, _ruleset: &RuleSet, req: &Request, rhs: &str) -> bool {
         req.token.has_role(rhs)
     }
+
+    fn depends_on_target(&self) -> bool {
+        false
+    }
 }
 
 /// A [Checker] that recurses into a different rule.
 ///
 /// For example, the check `rule:foo` will return the result of evaluating the rule `foo`, or false
-/// if no rule with that name exists in the [RuleSet].
+/// if no rule with that name exists in the [RuleSet]. If the result of the rule `foo` is unknown
+/// because a check within it failed, the [RuleSet] reports the [CheckError] of that check for the
+/// rule containing the `rule:` check.
 ///
 /// By default, this check is registered under the name "rule".
 pub struct RuleChecker;
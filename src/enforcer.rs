@@ -0,0 +1,341 @@
+/******************************************************************************
+*
+*  Copyright 2023 Stefan Majewsky <majewsky@gmx.net>
+*
+*  Licensed under the Apache License, Version 2.0 (the "License");
+*  you may not use this file except in compliance with the License.
+*  You may obtain a copy of the License at
+*
+*      http://www.apache.org/licenses/LICENSE-2.0
+*
+*  Unless required by applicable law or agreed to in writing, software
+*  distributed under the License is distributed on an "AS IS" BASIS,
+*  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+*  See the License for the specific language governing permissions and
+*  limitations under the License.
+*
+******************************************************************************/
+
+use std::fmt;
+use thiserror::Error;
+
+use crate::checkers::{AsyncChecker, CheckError, FallibleChecker};
+use crate::request::Request;
+use crate::ruleset::{Decision, RuleSet};
+
+/// A wrapper around [RuleSet] for use in API handlers, similar to the `Enforcer` class in
+/// oslo.policy.
+///
+/// Whereas [RuleSet::evaluate] only returns a boolean, [Enforcer::authorize] returns a
+/// [PolicyError] that explains why a request was rejected, and that can be converted into an HTTP
+/// response with [PolicyError::http_status_code].
+///
+/// ```
+/// # use oslo_policy::{Enforcer, RuleSet};
+/// # fn handle(token: &dyn oslo_policy::Token) -> Result<(), oslo_policy::PolicyError> {
+/// let mut ruleset = RuleSet::new();
+/// ruleset.add_rule("instance:create", "role:member").unwrap();
+/// let enforcer = Enforcer::new(ruleset).with_raise_on_unknown_rule(true);
+///
+/// let req = oslo_policy::Request::new(token);
+/// enforcer.authorize("instance:create", &req)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Enforcer {
+    ruleset: RuleSet,
+    raise_on_unknown_rule: bool,
+}
+
+impl Enforcer {
+    /// Wraps the given RuleSet. Its checkers, defaults and settings (e.g.
+    /// [RuleSet::set_enforce_scope]) are used as-is.
+    pub fn new(ruleset: RuleSet) -> Self {
+        Self {
+            ruleset,
+            raise_on_unknown_rule: false,
+        }
+    }
+
+    /// Controls how [Enforcer::authorize] reacts to rule names that do not exist in the RuleSet.
+    /// By default, such requests are rejected like any other request that is not authorized. If
+    /// enabled, [PolicyErrorReason::UnknownRule] is reported instead, which usually indicates a
+    /// typo in the application rather than a problem with the user's request.
+    pub fn with_raise_on_unknown_rule(mut self, enabled: bool) -> Self {
+        self.raise_on_unknown_rule = enabled;
+        self
+    }
+
+    /// Registers a custom [Checker](crate::Checker) or [FallibleChecker] with the wrapped RuleSet,
+    /// see [RuleSet::add_checker].
+    pub fn add_check(&mut self, name: impl Into<String>, check: impl FallibleChecker) {
+        self.ruleset.add_checker(name, check);
+    }
+
+    /// Registers a custom [AsyncChecker] with the wrapped RuleSet, see
+    /// [RuleSet::add_async_checker].
+    pub fn add_async_check(&mut self, name: impl Into<String>, check: impl AsyncChecker) {
+        self.ruleset.add_async_checker(name, check);
+    }
+
+    /// Returns the wrapped RuleSet.
+    pub fn ruleset(&self) -> &RuleSet {
+        &self.ruleset
+    }
+
+    /// Returns the wrapped RuleSet for modification, e.g. for loading additional policy files.
+    pub fn ruleset_mut(&mut self) -> &mut RuleSet {
+        &mut self.ruleset
+    }
+
+    /// Unwraps the RuleSet.
+    pub fn into_ruleset(self) -> RuleSet {
+        self.ruleset
+    }
+
+    /// Checks whether the named rule allows the given Request, using [RuleSet::try_decide].
+    pub fn authorize(&self, rule_name: &str, req: &Request) -> Result<(), PolicyError> {
+        self.make_result(rule_name, self.ruleset.try_decide(rule_name, req))
+    }
+
+    /// Like [Enforcer::authorize], but uses [RuleSet::try_decide_async] to await
+    /// [AsyncCheckers](AsyncChecker). The returned future is not [Send], see [AsyncChecker] for
+    /// details.
+    pub async fn authorize_async(
+        &self,
+        rule_name: &str,
+        req: &Request<'_>,
+    ) -> Result<(), PolicyError> {
+        self.make_result(
+            rule_name,
+            self.ruleset.try_decide_async(rule_name, req).await,
+        )
+    }
+
+    fn make_result(
+        &self,
+        rule_name: &str,
+        decision: Result<Decision, CheckError>,
+    ) -> Result<(), PolicyError> {
+        let decision = match decision {
+            Ok(decision) => decision,
+            Err(err) => {
+                return Err(PolicyError {
+                    rule_name: err.rule_name.clone(),
+                    reason: PolicyErrorReason::CheckFailed,
+                    source: Some(err),
+                })
+            }
+        };
+        let (rule_name, reason) = match decision {
+            Decision::Allowed => return Ok(()),
+            Decision::Denied => (rule_name.to_owned(), PolicyErrorReason::NotAuthorized),
+            Decision::InvalidScope => (rule_name.to_owned(), PolicyErrorReason::InvalidScope),
+            //if the requested rule itself is unknown, that is only an error if requested
+            Decision::UnknownRule { rule_name: unknown } => {
+                if unknown == rule_name && !self.raise_on_unknown_rule {
+                    (unknown, PolicyErrorReason::NotAuthorized)
+                } else {
+                    (unknown, PolicyErrorReason::UnknownRule)
+                }
+            }
+        };
+        Err(PolicyError {
+            rule_name,
+            reason,
+            source: None,
+        })
+    }
+}
+
+/// Error type returned by [Enforcer::authorize].
+#[derive(Error, Debug)]
+#[error("rule {rule_name:?} {reason}")]
+pub struct PolicyError {
+    rule_name: String,
+    reason: PolicyErrorReason,
+    #[source]
+    source: Option<CheckError>,
+}
+
+impl PolicyError {
+    /// Returns the name of the rule that rejected the request. For
+    /// [PolicyErrorReason::UnknownRule], this is the name of the rule that does not exist, which
+    /// may also be a rule that was referenced by the requested rule, see
+    /// [RuleSet::set_strict_references]. For [PolicyErrorReason::CheckFailed], this is the name of
+    /// the rule containing the failed check.
+    pub fn rule_name(&self) -> &str {
+        &self.rule_name
+    }
+
+    /// Returns why the request was rejected.
+    pub fn reason(&self) -> PolicyErrorReason {
+        self.reason
+    }
+
+    /// Returns the HTTP status code that an API should respond with, see
+    /// [PolicyErrorReason::http_status_code].
+    pub fn http_status_code(&self) -> u16 {
+        self.reason.http_status_code()
+    }
+}
+
+/// The reason for a [PolicyError].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum PolicyErrorReason {
+    /// The rule was evaluated and denies the request. This corresponds to `PolicyNotAuthorized` in
+    /// oslo.policy.
+    NotAuthorized,
+    /// The rule does not exist, see [Enforcer::with_raise_on_unknown_rule] and
+    /// [RuleSet::set_strict_references]. This corresponds to `PolicyNotRegistered` in oslo.policy.
+    UnknownRule,
+    /// The token does not have one of the scope types of the rule, see
+    /// [Decision::InvalidScope]. This corresponds to `InvalidScope` in oslo.policy.
+    InvalidScope,
+    /// A [FallibleChecker] failed during evaluation, and the decision depends on its result. The
+    /// [CheckError] is available as the [source](std::error::Error::source) of the PolicyError.
+    CheckFailed,
+}
+
+impl PolicyErrorReason {
+    /// Returns the HTTP status code that an API should respond with, as is customary in OpenStack
+    /// services: 403 (Forbidden) for requests that are not authorized or that have a token with
+    /// the wrong scope, and 500 (Internal Server Error) for unknown rules and failed checks, since
+    /// those indicate a bug in the application or a problem with the deployment.
+    pub fn http_status_code(self) -> u16 {
+        match self {
+            PolicyErrorReason::NotAuthorized | PolicyErrorReason::InvalidScope => 403,
+            PolicyErrorReason::UnknownRule | PolicyErrorReason::CheckFailed => 500,
+        }
+    }
+}
+
+impl fmt::Display for PolicyErrorReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PolicyErrorReason::NotAuthorized => "does not allow this request",
+            PolicyErrorReason::UnknownRule => "does not exist",
+            PolicyErrorReason::InvalidScope => "does not allow requests with a token of this scope",
+            PolicyErrorReason::CheckFailed => "could not be evaluated",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::checkers::Checker;
+    use crate::registry::{RuleDefault, RuleRegistry, ScopeType};
+    use crate::request::test::Token;
+
+    #[test]
+    fn test_authorize() {
+        let mut registry = RuleRegistry::new();
+        registry
+            .register(
+                RuleDefault::new("domain:list", "role:admin")
+                    .unwrap()
+                    .with_scope_types(vec![ScopeType::System]),
+            )
+            .unwrap();
+        let mut ruleset = RuleSet::new();
+        ruleset.add_rule("server:list", "role:member").unwrap();
+        ruleset.add_rule("server:delete", "nobody:True").unwrap();
+        ruleset.apply_defaults(&registry);
+        ruleset.set_enforce_scope(true);
+        let mut enforcer = Enforcer::new(ruleset);
+        enforcer.add_check("nobody", DenyAllChecker);
+
+        let token = Token {
+            roles: vec!["member".into(), "admin".into()],
+            api_attrs: HashMap::new(),
+        };
+        let req = Request::new(&token);
+        assert!(enforcer.authorize("server:list", &req).is_ok());
+
+        let err = enforcer.authorize("server:delete", &req).unwrap_err();
+        assert_eq!(err.rule_name(), "server:delete");
+        assert_eq!(err.reason(), PolicyErrorReason::NotAuthorized);
+        assert_eq!(err.http_status_code(), 403);
+        assert_eq!(
+            err.to_string(),
+            r#"rule "server:delete" does not allow this request"#
+        );
+
+        let err = enforcer.authorize("domain:list", &req).unwrap_err();
+        assert_eq!(err.reason(), PolicyErrorReason::InvalidScope);
+        assert_eq!(err.http_status_code(), 403);
+
+        //unknown rules are only reported as such if requested
+        let err = enforcer.authorize("server:lsit", &req).unwrap_err();
+        assert_eq!(err.reason(), PolicyErrorReason::NotAuthorized);
+        let enforcer = enforcer.with_raise_on_unknown_rule(true);
+        let err = enforcer.authorize("server:lsit", &req).unwrap_err();
+        assert_eq!(err.reason(), PolicyErrorReason::UnknownRule);
+        assert_eq!(err.http_status_code(), 500);
+        assert_eq!(err.to_string(), r#"rule "server:lsit" does not exist"#);
+
+        //in strict mode, unknown references within rules are always reported
+        let mut enforcer = enforcer.with_raise_on_unknown_rule(false);
+        let ruleset = enforcer.ruleset_mut();
+        ruleset.add_rule("server:show", "rule:server:lsit").unwrap();
+        ruleset.set_strict_references(true);
+        let err = enforcer.authorize("server:show", &req).unwrap_err();
+        assert_eq!(err.rule_name(), "server:lsit");
+        assert_eq!(err.reason(), PolicyErrorReason::UnknownRule);
+
+        //failed checks are reported with the error from the checker
+        let ruleset = enforcer.ruleset_mut();
+        ruleset
+            .add_rule("server:resize", "quota:instances")
+            .unwrap();
+        enforcer.add_check("quota", UnavailableChecker);
+        let err = enforcer.authorize("server:resize", &req).unwrap_err();
+        assert_eq!(err.rule_name(), "server:resize");
+        assert_eq!(err.reason(), PolicyErrorReason::CheckFailed);
+        assert_eq!(err.http_status_code(), 500);
+        assert_eq!(
+            std::error::Error::source(&err).unwrap().to_string(),
+            r#"check "quota:instances" in rule "server:resize" failed: quota service is unavailable"#
+        );
+
+        //failed checks do not matter if the rule is decided without them
+        let ruleset = enforcer.ruleset_mut();
+        ruleset
+            .add_rule("server:migrate", "quota:instances or role:admin")
+            .unwrap();
+        ruleset
+            .add_rule("server:shelve", "not quota:instances")
+            .unwrap();
+        assert!(enforcer.authorize("server:migrate", &req).is_ok());
+        let err = enforcer.authorize("server:shelve", &req).unwrap_err();
+        assert_eq!(err.reason(), PolicyErrorReason::CheckFailed);
+    }
+
+    /// A custom checker that allows nothing.
+    struct DenyAllChecker;
+
+    impl Checker for DenyAllChecker {
+        fn check(&self, _ruleset: &RuleSet, _req: &Request, _rhs: &str) -> bool {
+            false
+        }
+    }
+
+    /// A custom checker that always fails.
+    struct UnavailableChecker;
+
+    impl FallibleChecker for UnavailableChecker {
+        fn try_check(
+            &self,
+            _ruleset: &RuleSet,
+            _req: &Request,
+            _rhs: &str,
+        ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+            Err("quota service is unavailable".into())
+        }
+    }
+}
@@ -0,0 +1,626 @@
+/******************************************************************************
+*
+*  Copyright 2023 Stefan Majewsky <majewsky@gmx.net>
+*
+*  Licensed under the Apache License, Version 2.0 (the "License");
+*  you may not use this file except in compliance with the License.
+*  You may obtain a copy of the License at
+*
+*      http://www.apache.org/licenses/LICENSE-2.0
+*
+*  Unless required by applicable law or agreed to in writing, software
+*  distributed under the License is distributed on an "AS IS" BASIS,
+*  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+*  See the License for the specific language governing permissions and
+*  limitations under the License.
+*
+******************************************************************************/
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::ast::{Expression, LeftHandSide};
+use crate::attribute::lookup_path;
+use crate::checkers::{CheckError, FallibleChecker};
+use crate::interpolation::Format;
+use crate::request::Request;
+use crate::ruleset::{
+    and_outcome, is_bool_literal, or_outcome, Decision, Outcome, RuleSet, MAX_RULE_DEPTH,
+};
+use crate::trace::Trace;
+
+/// A [RuleSet] that was compiled into an immutable, indexed form for fast evaluation by
+/// [RuleSet::freeze].
+///
+/// In a frozen rule set, `rule:` checks refer to the referenced rule by index, checks refer to
+/// their checker by index, all strings on the left-hand side of checks are interned, and the
+/// right-hand sides of checks are split into literal strings and references to target object
+/// attributes ahead of time. Checks that do not depend on the Request (e.g. `'foo':foo`) are folded
+/// into constants. Therefore, evaluation does not involve any hash map lookups by name or any
+/// parsing.
+///
+/// Evaluation does not allocate either, unless memoization is enabled or the
+/// [Token](crate::Token) or [Target](crate::Target) implementation allocates when looking up
+/// attributes (e.g. by returning an owned [AttributeValue](crate::AttributeValue)). The exceptions
+/// are attribute values other than strings (which are converted into strings for comparison), the
+/// `%(name)r` conversion, and checks for [checkers](crate::Checker) whose right-hand side combines
+/// target object attributes with other text (e.g. `quota:%(project_id)s_%(kind)s`), since the
+/// checker needs the interpolated right-hand side as a single string.
+///
+/// Evaluation yields the same results as on the original RuleSet, except that custom
+/// [checkers](crate::Checker) receive the original RuleSet (and thus evaluate `rule:` checks the
+/// slow way) since they only know about that type.
+pub struct FrozenRuleSet {
+    ruleset: RuleSet,
+    index: HashMap<String, RuleId>,
+    /// Indexed by [RuleId]. Also contains rules that are referenced by `rule:` checks, but not
+    /// defined (with `root == None`), so that strict references can be reported.
+    rules: Vec<FrozenRule>,
+    nodes: Vec<Node>,
+    checkers: Vec<(StrId, Arc<dyn FallibleChecker>)>,
+    strings: Vec<Box<str>>,
+}
+
+/// Refers to a rule within a [FrozenRuleSet].
+///
+/// Rule IDs can be obtained with [FrozenRuleSet::rule_id], e.g. once during the startup of an
+/// application, to avoid the lookup by name on each evaluation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RuleId(u32);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct NodeId(u32);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct StrId(u32);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct CheckerId(u32);
+
+struct FrozenRule {
+    name: StrId,
+    root: Option<NodeId>,
+}
+
+/// A node of an [Expression] within a [FrozenRuleSet]. Checks have one variant per branch in
+/// [RuleSet::evaluate_check], which is decided when freezing.
+enum Node {
+    Const(bool),
+    And(NodeId, NodeId),
+    Or(NodeId, NodeId),
+    Not(NodeId),
+    /// A `rule:` check with a right-hand side that does not reference target object attributes.
+    Rule(RuleId),
+    /// A `rule:` check with a right-hand side that references target object attributes, so that
+    /// the referenced rule can only be looked up by name during evaluation.
+    DynamicRule(Format),
+    /// A check with a literal on the left-hand side.
+    Literal {
+        value: StrId,
+        rhs: Format,
+    },
+    Checker {
+        checker: CheckerId,
+        /// The right-hand side before interpolation, for error messages.
+        raw_rhs: StrId,
+        rhs: Format,
+    },
+    ApiAttribute {
+        name: StrId,
+        rhs: Format,
+    },
+    /// A check that is evaluated by the original RuleSet (e.g. a check with an async checker,
+    /// which always fails during synchronous evaluation).
+    Fallback {
+        lhs: LeftHandSide,
+        rhs: String,
+    },
+}
+
+impl RuleSet {
+    /// Compiles this RuleSet into a [FrozenRuleSet] for faster evaluation. Rules and checkers
+    /// cannot be added to a frozen rule set, so this should be done once all rules are loaded and
+    /// all [defaults](RuleSet::apply_defaults) are applied.
+    pub fn freeze(self) -> FrozenRuleSet {
+        let mut builder = Builder {
+            ruleset: &self,
+            index: HashMap::new(),
+            rules: Vec::new(),
+            nodes: Vec::new(),
+            checker_ids: HashMap::new(),
+            checkers: Vec::new(),
+            string_ids: HashMap::new(),
+            strings: Vec::new(),
+        };
+
+        //sort rule names to make the rule IDs deterministic
+        let mut rule_names: Vec<_> = self.rules.keys().map(|name| name.as_str()).collect();
+        rule_names.sort_unstable();
+        for name in &rule_names {
+            builder.rule_id(name);
+        }
+        for name in &rule_names {
+            let root = builder.compile_expr(&self.rules[*name].expr);
+            let id = builder.index[*name];
+            builder.rules[id.0 as usize].root = Some(root);
+        }
+
+        let Builder {
+            index,
+            rules,
+            nodes,
+            checkers,
+            strings,
+            ..
+        } = builder;
+        let index = (rule_names
+            .iter()
+            .map(|name| ((*name).to_owned(), index[*name])))
+        .collect();
+        FrozenRuleSet {
+            ruleset: self,
+            index,
+            rules,
+            nodes,
+            checkers,
+            strings,
+        }
+    }
+}
+
+struct Builder<'a> {
+    ruleset: &'a RuleSet,
+    /// Contains rule IDs for all defined and referenced rules.
+    index: HashMap<&'a str, RuleId>,
+    rules: Vec<FrozenRule>,
+    nodes: Vec<Node>,
+    checker_ids: HashMap<&'a str, CheckerId>,
+    checkers: Vec<(StrId, Arc<dyn FallibleChecker>)>,
+    string_ids: HashMap<&'a str, StrId>,
+    strings: Vec<Box<str>>,
+}
+
+impl<'a> Builder<'a> {
+    fn intern(&mut self, s: &'a str) -> StrId {
+        if let Some(id) = self.string_ids.get(s) {
+            return *id;
+        }
+        let id = StrId(self.strings.len() as u32);
+        self.strings.push(s.into());
+        self.string_ids.insert(s, id);
+        id
+    }
+
+    fn rule_id(&mut self, name: &'a str) -> RuleId {
+        if let Some(id) = self.index.get(name) {
+            return *id;
+        }
+        let id = RuleId(self.rules.len() as u32);
+        let name_id = self.intern(name);
+        self.rules.push(FrozenRule {
+            name: name_id,
+            root: None,
+        });
+        self.index.insert(name, id);
+        id
+    }
+
+    fn checker_id(&mut self, name: &'a str, checker: &Arc<dyn FallibleChecker>) -> CheckerId {
+        if let Some(id) = self.checker_ids.get(name) {
+            return *id;
+        }
+        let id = CheckerId(self.checkers.len() as u32);
+        let name_id = self.intern(name);
+        self.checkers.push((name_id, Arc::clone(checker)));
+        self.checker_ids.insert(name, id);
+        id
+    }
+
+    fn push(&mut self, node: Node) -> NodeId {
+        let id = NodeId(self.nodes.len() as u32);
+        self.nodes.push(node);
+        id
+    }
+
+    fn compile_expr(&mut self, expr: &'a Expression) -> NodeId {
+        use Expression::*;
+        let node = match expr {
+            Const(value) => Node::Const(*value),
+            Check(lhs, rhs) => self.compile_check(lhs, rhs),
+            And(x, y) => Node::And(self.compile_expr(x), self.compile_expr(y)),
+            Or(x, y) => Node::Or(self.compile_expr(x), self.compile_expr(y)),
+            Not(x) => Node::Not(self.compile_expr(x)),
+        };
+        self.push(node)
+    }
+
+    /// Decides which branch of [RuleSet::evaluate_check] the given check takes.
+    fn compile_check(&mut self, lhs: &'a LeftHandSide, rhs: &'a str) -> Node {
+        //right-hand sides without `%` are not changed by interpolation
+        let is_constant = !rhs.contains('%');
+        //If the format string is malformed, the entire check fails.
+        let Some(format) = Format::parse(rhs) else {
+            return Node::Const(false);
+        };
+        let value = match lhs {
+            LeftHandSide::Literal(value) => value,
+            LeftHandSide::Identifier(name)
+                if name == "rule" && self.ruleset.has_default_rule_checker() =>
+            {
+                return if is_constant {
+                    Node::Rule(self.rule_id(rhs))
+                } else {
+                    Node::DynamicRule(format)
+                };
+            }
+            LeftHandSide::Identifier(name) => {
+                if let Some(checker) = self.ruleset.checkers.get(name) {
+                    let checker = self.checker_id(name, checker);
+                    let raw_rhs = self.intern(rhs);
+                    return Node::Checker {
+                        checker,
+                        raw_rhs,
+                        rhs: format,
+                    };
+                }
+                if self.ruleset.async_checkers.contains_key(name) {
+                    return Node::Fallback {
+                        lhs: lhs.clone(),
+                        rhs: rhs.to_owned(),
+                    };
+                }
+                if !is_bool_literal(name) {
+                    let name = self.intern(name);
+                    return Node::ApiAttribute { name, rhs: format };
+                }
+                name
+            }
+        };
+        if is_constant {
+            Node::Const(value == rhs)
+        } else {
+            let value = self.intern(value);
+            Node::Literal { value, rhs: format }
+        }
+    }
+}
+
+impl FrozenRuleSet {
+    /// Returns the original RuleSet.
+    pub fn ruleset(&self) -> &RuleSet {
+        &self.ruleset
+    }
+
+    /// Discards the precompiled form and returns the original RuleSet, e.g. to add more rules.
+    pub fn into_ruleset(self) -> RuleSet {
+        self.ruleset
+    }
+
+    /// Returns the ID of the rule with the given name, or None if no such rule exists.
+    pub fn rule_id(&self, rule_name: &str) -> Option<RuleId> {
+        self.index.get(rule_name).copied()
+    }
+
+    /// Returns the name of the rule with the given ID.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the ID was obtained from a different FrozenRuleSet.
+    pub fn rule_name(&self, id: RuleId) -> &str {
+        &self.strings[self.rules[id.0 as usize].name.0 as usize]
+    }
+
+    /// Like [RuleSet::decide].
+    pub fn decide(&self, rule_name: &str, req: &Request) -> Decision {
+        let (decision, _) = self.decide_impl(rule_name, req);
+        decision
+    }
+
+    /// Like [RuleSet::try_decide].
+    pub fn try_decide(&self, rule_name: &str, req: &Request) -> Result<Decision, CheckError> {
+        match self.decide_impl(rule_name, req) {
+            (_, Some(err)) => Err(err),
+            (decision, None) => Ok(decision),
+        }
+    }
+
+    fn decide_impl(&self, rule_name: &str, req: &Request) -> (Decision, Option<CheckError>) {
+        if let Some(decision) = self.ruleset.decide_without_evaluation(rule_name, req) {
+            return (decision, None);
+        }
+        let outcome = self.try_evaluate(rule_name, req);
+        self.ruleset.finish_decision(req, outcome)
+    }
+
+    /// Like [RuleSet::evaluate].
+    pub fn evaluate(&self, rule_name: &str, req: &Request) -> bool {
+        self.try_evaluate(rule_name, req).unwrap_or(false)
+    }
+
+    /// Like [RuleSet::try_evaluate].
+    pub fn try_evaluate(&self, rule_name: &str, req: &Request) -> Result<bool, CheckError> {
+        match self.index.get(rule_name) {
+            Some(id) => self.try_evaluate_rule(*id, req),
+            None => self.ruleset.try_evaluate(rule_name, req),
+        }
+    }
+
+    /// Like [FrozenRuleSet::evaluate], but takes a rule ID instead of a rule name.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the ID was obtained from a different FrozenRuleSet.
+    pub fn evaluate_rule(&self, id: RuleId, req: &Request) -> bool {
+        self.try_evaluate_rule(id, req).unwrap_or(false)
+    }
+
+    /// Like [FrozenRuleSet::try_evaluate], but takes a rule ID instead of a rule name.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the ID was obtained from a different FrozenRuleSet.
+    pub fn try_evaluate_rule(&self, id: RuleId, req: &Request) -> Result<bool, CheckError> {
+        let rule = &self.rules[id.0 as usize];
+        let rule_name = self.string(rule.name);
+        if req.state.is_tracing() {
+            return self.ruleset.try_evaluate(rule_name, req);
+        }
+
+        //this follows the structure of RuleSet::try_evaluate()
+        if let Some(result) = req.state.memoized_rule_result(&self.ruleset, rule_name) {
+            return Ok(result);
+        }
+        let Some(_guard) = req.state.descend(MAX_RULE_DEPTH) else {
+            return Ok(false);
+        };
+        let checkpoint = req.state.memoization_checkpoint();
+        let outcome = match rule.root {
+            Some(root) => self.evaluate_node(req, rule_name, root),
+            None => {
+                if self.ruleset.has_strict_references() {
+                    req.state.record_unknown_rule(rule_name);
+                }
+                Ok(false)
+            }
+        };
+        if let Ok(result) = outcome {
+            req.state
+                .memoize_rule_result(&self.ruleset, rule_name, checkpoint, result);
+        }
+        outcome
+    }
+
+    /// Like [RuleSet::evaluate_explained]. Traces are always produced by the original RuleSet,
+    /// since the precompiled form does not retain the original expressions.
+    pub fn evaluate_explained(&self, rule_name: &str, req: &Request) -> Trace {
+        self.ruleset.evaluate_explained(rule_name, req)
+    }
+
+    fn string(&self, id: StrId) -> &str {
+        &self.strings[id.0 as usize]
+    }
+
+    fn evaluate_node(&self, req: &Request, rule_name: &str, id: NodeId) -> Outcome {
+        match &self.nodes[id.0 as usize] {
+            Node::Const(value) => Ok(*value),
+            Node::And(x, y) => and_outcome(self.evaluate_node(req, rule_name, *x), || {
+                self.evaluate_node(req, rule_name, *y)
+            }),
+            Node::Or(x, y) => or_outcome(self.evaluate_node(req, rule_name, *x), || {
+                self.evaluate_node(req, rule_name, *y)
+            }),
+            Node::Not(x) => self.evaluate_node(req, rule_name, *x).map(|v| !v),
+            Node::Rule(id) => self.try_evaluate_rule(*id, req),
+            //If an interpolated variable is missing, the entire check fails.
+            Node::DynamicRule(rhs) => match rhs.resolve(req.target) {
+                Some(name) => self.try_evaluate(&name, req),
+                None => Ok(false),
+            },
+            Node::Literal { value, rhs } => Ok(rhs.matches(req.target, self.string(*value))),
+            Node::Checker {
+                checker,
+                raw_rhs,
+                rhs,
+            } => {
+                let (name, checker) = &self.checkers[checker.0 as usize];
+                let Some(rhs) = rhs.resolve(req.target) else {
+                    return Ok(false);
+                };
+                let (name, raw_rhs) = (self.string(*name), self.string(*raw_rhs));
+                let (outcome, _) = (self.ruleset)
+                    .run_checker(req, rule_name, name, &**checker, raw_rhs, &rhs, false);
+                outcome
+            }
+            Node::ApiAttribute { name, rhs } => {
+                let value = lookup_path(self.string(*name), |k| req.token.get_api_attribute(k));
+                Ok(value.is_some_and(|val| val.matches_with(|s| rhs.matches(req.target, s))))
+            }
+            Node::Fallback { lhs, rhs } => {
+                (self.ruleset).evaluate_check(req, rule_name, lhs, rhs, None)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+
+    use super::*;
+    use crate::attribute::AttributeValue;
+    use crate::request::test::{PermissiveRuleChecker, Token};
+
+    fn build_ruleset() -> RuleSet {
+        let mut ruleset = RuleSet::new();
+        let rules = [
+            ("admin", "role:admin or is_admin:True"),
+            ("owner", "user_id:%(user_id)s"),
+            ("admin_or_owner", "rule:admin or rule:owner"),
+            ("member", "role:member and project_id:%(project_id)s"),
+            (
+                "reader",
+                "(role:reader or rule:member) and not rule:blocked",
+            ),
+            ("blocked", "'True':%(locked)s"),
+            ("constant", "'foo':foo and not 'foo':bar"),
+            ("indirect", "rule:%(rule_name)s"),
+            ("typo", "rule:amdin or rule:owner"),
+            ("cycle", "rule:cycle or role:admin"),
+            ("nested", "project.domain.id:%(domain_id)s"),
+            ("always", "@"),
+            ("never", "!"),
+        ];
+        for (name, rule) in rules {
+            ruleset.add_rule(name, rule).unwrap();
+        }
+        ruleset
+    }
+
+    #[test]
+    fn test_freeze() {
+        let ruleset = build_ruleset();
+        let frozen = build_ruleset().freeze();
+
+        let tokens = [
+            Token {
+                roles: vec!["admin".into()],
+                api_attrs: HashMap::new(),
+            },
+            Token {
+                roles: vec!["reader".into()],
+                api_attrs: HashMap::from([
+                    ("user_id".into(), "u-1".into()),
+                    ("is_admin".into(), AttributeValue::Bool(false)),
+                ]),
+            },
+            Token {
+                roles: vec!["member".into()],
+                api_attrs: HashMap::from([
+                    ("project_id".into(), "p-1".into()),
+                    (
+                        "project".into(),
+                        AttributeValue::Map(BTreeMap::from([(
+                            "domain".into(),
+                            AttributeValue::Map(BTreeMap::from([("id".into(), "d-1".into())])),
+                        )])),
+                    ),
+                ]),
+            },
+        ];
+        let targets: [HashMap<String, AttributeValue>; 3] = [
+            HashMap::new(),
+            HashMap::from([
+                ("user_id".into(), "u-1".into()),
+                ("project_id".into(), "p-1".into()),
+                ("domain_id".into(), "d-1".into()),
+                ("rule_name".into(), "owner".into()),
+                ("locked".into(), AttributeValue::Bool(false)),
+            ]),
+            HashMap::from([
+                ("user_id".into(), "u-2".into()),
+                ("project_id".into(), "p-1".into()),
+                ("rule_name".into(), "member".into()),
+                ("locked".into(), AttributeValue::Bool(true)),
+            ]),
+        ];
+
+        //the frozen rule set must yield the same results as the original
+        for token in &tokens {
+            for target in &targets {
+                let req = Request::new(token).with_target(target);
+                for (name, _) in ruleset.rules() {
+                    assert_eq!(
+                        frozen.evaluate(name, &req),
+                        ruleset.evaluate(name, &req),
+                        "rule {name:?}"
+                    );
+                    assert_eq!(frozen.decide(name, &req), ruleset.decide(name, &req));
+                }
+            }
+        }
+
+        //rule IDs are stable and can be used instead of rule names
+        let id = frozen.rule_id("admin_or_owner").unwrap();
+        assert_eq!(frozen.rule_name(id), "admin_or_owner");
+        assert!(frozen.evaluate_rule(id, &Request::new(&tokens[0])));
+        assert_eq!(frozen.rule_id("amdin"), None);
+        assert!(!frozen.evaluate("amdin", &Request::new(&tokens[0])));
+        assert_eq!(
+            frozen.decide("amdin", &Request::new(&tokens[0])),
+            Decision::UnknownRule {
+                rule_name: "amdin".into()
+            }
+        );
+
+        //strict references are reported for rules that are referenced, but not defined
+        let mut ruleset = frozen.into_ruleset();
+        ruleset.set_strict_references(true);
+        let frozen = ruleset.freeze();
+        let req = Request::new(&tokens[1]);
+        assert_eq!(
+            frozen.decide("typo", &req),
+            Decision::UnknownRule {
+                rule_name: "amdin".into()
+            }
+        );
+        assert_eq!(
+            frozen.evaluate_explained("constant", &req).to_string(),
+            frozen
+                .ruleset()
+                .evaluate_explained("constant", &req)
+                .to_string()
+        );
+    }
+
+    struct FailingChecker;
+
+    impl FallibleChecker for FailingChecker {
+        fn try_check(
+            &self,
+            _ruleset: &RuleSet,
+            _req: &Request,
+            rhs: &str,
+        ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+            Err(format!("cannot check {rhs}").into())
+        }
+    }
+
+    #[test]
+    fn test_freeze_with_failing_checker() {
+        let mut ruleset = RuleSet::new();
+        ruleset.add_checker("fail", FailingChecker);
+        ruleset.add_rule("a", "role:admin or rule:b").unwrap();
+        ruleset.add_rule("b", "fail:%(id)s").unwrap();
+        let frozen = ruleset.freeze();
+
+        let token = Token {
+            roles: vec![],
+            api_attrs: HashMap::new(),
+        };
+        let target = HashMap::from([("id".to_owned(), AttributeValue::from("x-1"))]);
+        let req = Request::new(&token).with_target(&target);
+        let err = frozen.try_decide("a", &req).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"check "fail:%(id)s" in rule "b" failed: cannot check x-1"#
+        );
+        assert_eq!(frozen.decide("a", &req), Decision::Denied);
+    }
+
+    #[test]
+    fn test_freeze_with_custom_rule_checker() {
+        let mut ruleset = RuleSet::new();
+        ruleset.add_rule("a", "rule:b").unwrap();
+        ruleset.add_rule("b", "role:admin").unwrap();
+        ruleset.add_checker("rule", PermissiveRuleChecker);
+        let frozen = ruleset.freeze();
+
+        let token = Token {
+            roles: vec![],
+            api_attrs: HashMap::new(),
+        };
+        let req = Request::new(&token);
+        assert!(frozen.evaluate("a", &req));
+        assert!(frozen.ruleset().evaluate("a", &req));
+    }
+}
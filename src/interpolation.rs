@@ -0,0 +1,437 @@
+/******************************************************************************
+*
+*  Copyright 2023 Stefan Majewsky <majewsky@gmx.net>
+*
+*  Licensed under the Apache License, Version 2.0 (the "License");
+*  you may not use this file except in compliance with the License.
+*  You may obtain a copy of the License at
+*
+*      http://www.apache.org/licenses/LICENSE-2.0
+*
+*  Unless required by applicable law or agreed to in writing, software
+*  distributed under the License is distributed on an "AS IS" BASIS,
+*  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+*  See the License for the specific language governing permissions and
+*  limitations under the License.
+*
+******************************************************************************/
+
+use std::borrow::Cow;
+use std::fmt::{self, Write};
+use thiserror::Error;
+
+use crate::attribute::{lookup_target_path, AttributeRef, AttributeValue};
+use crate::request::Target;
+
+/// Error type for when the right-hand side of a check could not be resolved. Appears within
+/// [Trace::Check](crate::Trace::Check).
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum InterpolationError {
+    /// The format string is not valid, or uses a conversion that we do not support.
+    #[error("malformed format string")]
+    InvalidFormat,
+    /// The format string references a target object attribute that does not exist.
+    #[error("missing target object attribute {0:?}")]
+    MissingAttribute(String),
+    /// The format string uses the `%d` conversion on a target object attribute whose value is not
+    /// an integer.
+    #[error("target object attribute {0:?} is not an integer")]
+    NotAnInteger(String),
+}
+
+/// One piece of a format string, as returned by [parse_format].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Segment<S> {
+    /// A string that appears in the output verbatim.
+    Literal(S),
+    /// A placeholder like `%(name)s`.
+    Placeholder(S, Conversion),
+}
+
+/// The conversions that we support within placeholders.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Conversion {
+    /// `%(name)s`: the value as-is, like Python's `str()`
+    Str,
+    /// `%(name)r`: the value as a Python literal, like Python's `repr()`
+    Repr,
+    /// `%(name)d` or `%(name)i`: the value as a decimal integer
+    Int,
+}
+
+/// Splits a format string for the Python `%` operator into segments. Returns None if the format
+/// string is malformed, or uses features that we do not support.
+///
+/// Since the right-hand side of a check is always formatted with a dict of target object
+/// attributes, we only support placeholders that take a key from a dict (`%(name)s`), plus the
+/// `%%` escape for a literal percent sign. We do not support flags, field widths or precisions.
+fn parse_format(input: &str) -> Option<Vec<Segment<&str>>> {
+    let mut segments = Vec::new();
+    let mut rest = input;
+    while let Some(idx) = rest.find('%') {
+        if idx > 0 {
+            segments.push(Segment::Literal(&rest[..idx]));
+        }
+        rest = &rest[idx + 1..];
+
+        if let Some(remainder) = rest.strip_prefix('%') {
+            segments.push(Segment::Literal("%"));
+            rest = remainder;
+            continue;
+        }
+
+        //like Python, allow nested parentheses within the key (e.g. `%(foo(bar))s`)
+        rest = rest.strip_prefix('(')?;
+        let mut depth = 1;
+        let key_len = rest.find(|c| {
+            match c {
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                _ => {}
+            }
+            depth == 0
+        })?;
+        let key = &rest[..key_len];
+        rest = &rest[key_len + 1..];
+
+        let conversion = match rest.chars().next()? {
+            's' => Conversion::Str,
+            'r' => Conversion::Repr,
+            'd' | 'i' => Conversion::Int,
+            _ => return None,
+        };
+        segments.push(Segment::Placeholder(key, conversion));
+        rest = &rest[1..];
+    }
+    if !rest.is_empty() {
+        segments.push(Segment::Literal(rest));
+    }
+    Some(segments)
+}
+
+/// Returns whether [resolve_target_attr_refs] can possibly succeed for this input.
+pub(crate) fn is_valid_format(input: &str) -> bool {
+    !input.contains('%') || parse_format(input).is_some()
+}
+
+/// Returns whether this input references any target object attributes, i.e. whether the result
+/// of [resolve_target_attr_refs] depends on the target. Malformed inputs do not reference anything.
+pub(crate) fn references_target(input: &str) -> bool {
+    input.contains('%')
+        && parse_format(input).is_some_and(|segments| {
+            (segments.iter()).any(|segment| matches!(segment, Segment::Placeholder(_, _)))
+        })
+}
+
+/// If this input consists of exactly one `%(name)s` placeholder, returns the attribute name.
+pub(crate) fn as_single_placeholder(input: &str) -> Option<&str> {
+    match parse_format(input)?.as_slice() {
+        [Segment::Placeholder(key, Conversion::Str)] => Some(key),
+        _ => None,
+    }
+}
+
+/// Resolves references to target object attributes on the right-hand side of a check. This works
+/// like Python's `input % target`.
+pub(crate) fn resolve_target_attr_refs<'r, 'i: 'r, 't: 'r>(
+    input: &'i str,
+    target: &'t dyn Target,
+) -> Result<Cow<'r, str>, InterpolationError> {
+    //fast path: no interpolation at all
+    if !input.contains('%') {
+        return Ok(Cow::Borrowed(input));
+    }
+    let segments = parse_format(input).ok_or(InterpolationError::InvalidFormat)?;
+    Ok(resolve_segments(&segments, target)?)
+}
+
+/// A format string that was split into segments ahead of time, so that it can be interpolated
+/// repeatedly without parsing it again. This is used by [FrozenRuleSet](crate::FrozenRuleSet).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Format(Box<[Segment<Box<str>>]>);
+
+impl Format {
+    /// Parses a format string like [resolve_target_attr_refs] would. Returns None if the format
+    /// string is malformed.
+    pub(crate) fn parse(input: &str) -> Option<Self> {
+        let segments = parse_format(input)?;
+        let segments = (segments.into_iter())
+            .map(|segment| match segment {
+                Segment::Literal(s) => Segment::Literal(s.into()),
+                Segment::Placeholder(key, conversion) => {
+                    Segment::Placeholder(key.into(), conversion)
+                }
+            })
+            .collect();
+        Some(Self(segments))
+    }
+
+    /// Like [resolve_target_attr_refs], but returns None instead of an error. This only allocates
+    /// if the result is assembled from multiple segments, or if a placeholder refers to an
+    /// attribute value that is not a string.
+    pub(crate) fn resolve<'r>(&'r self, target: &'r dyn Target) -> Option<Cow<'r, str>> {
+        match &*self.0 {
+            [] => Some(Cow::Borrowed("")),
+            [Segment::Literal(s)] => Some(Cow::Borrowed(s)),
+            segments => resolve_segments(segments, target).ok(),
+        }
+    }
+
+    /// Returns whether [Format::resolve] would return the `expected` string. This does not
+    /// allocate unless a placeholder refers to an attribute value that is not a string, or uses
+    /// the `%(name)r` conversion.
+    pub(crate) fn matches(&self, target: &dyn Target, expected: &str) -> bool {
+        let mut comparator = Comparator(expected);
+        match interpolate(&self.0, target, &mut comparator) {
+            Ok(result) => result.is_ok() && comparator.0.is_empty(),
+            Err(_) => false,
+        }
+    }
+}
+
+/// A sink for [interpolate] that compares the interpolated string with an expected string
+/// without building it. Writing fails as soon as a mismatch is found.
+struct Comparator<'a>(&'a str);
+
+impl Write for Comparator<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0 = self.0.strip_prefix(s).ok_or(fmt::Error)?;
+        Ok(())
+    }
+}
+
+/// Like [InterpolationError], but borrows the attribute name, so that failing does not allocate.
+enum Failure<'k> {
+    MissingAttribute(&'k str),
+    NotAnInteger(&'k str),
+}
+
+impl From<Failure<'_>> for InterpolationError {
+    fn from(failure: Failure<'_>) -> Self {
+        match failure {
+            Failure::MissingAttribute(key) => InterpolationError::MissingAttribute(key.to_owned()),
+            Failure::NotAnInteger(key) => InterpolationError::NotAnInteger(key.to_owned()),
+        }
+    }
+}
+
+fn resolve_segments<'r, 'k, S: AsRef<str>>(
+    segments: &'k [Segment<S>],
+    target: &'r dyn Target,
+) -> Result<Cow<'r, str>, Failure<'k>> {
+    //fast path: exactly one %(foo)s interpolation that spans the entire string
+    if let [Segment::Placeholder(key, Conversion::Str)] = segments {
+        return Ok(match get_attribute(target, key.as_ref())? {
+            AttributeRef::Str(s) => Cow::Borrowed(s),
+            AttributeRef::Value(Cow::Borrowed(AttributeValue::String(s))) => Cow::Borrowed(s),
+            AttributeRef::Value(Cow::Owned(AttributeValue::String(s))) => Cow::Owned(s),
+            value => Cow::Owned(value.to_string()),
+        });
+    }
+
+    let mut result = String::new();
+    //writing into a String cannot fail
+    let _ = interpolate(segments, target, &mut result)?;
+    Ok(Cow::Owned(result))
+}
+
+/// Writes the result of interpolating target object attributes into the format string to `out`.
+/// Stops at the first error returned by `out`, and returns that error.
+fn interpolate<'k, S: AsRef<str>>(
+    segments: &'k [Segment<S>],
+    target: &dyn Target,
+    out: &mut impl Write,
+) -> Result<fmt::Result, Failure<'k>> {
+    for segment in segments {
+        let result = match segment {
+            Segment::Literal(s) => out.write_str(s.as_ref()),
+            //for strings, Display does not allocate
+            Segment::Placeholder(key, Conversion::Str) => {
+                write!(out, "{}", get_attribute(target, key.as_ref())?)
+            }
+            Segment::Placeholder(key, Conversion::Repr) => {
+                let mut buf = String::new();
+                get_attribute(target, key.as_ref())?.write_repr(&mut buf);
+                out.write_str(&buf)
+            }
+            Segment::Placeholder(key, Conversion::Int) => {
+                //NOTE: Python would reject string values for `%d`, but since many applications
+                //store all attributes as strings, it makes more sense to accept strings that
+                //contain integers.
+                let key = key.as_ref();
+                let value = get_attribute(target, key)?
+                    .as_int()
+                    .ok_or(Failure::NotAnInteger(key))?;
+                write!(out, "{value}")
+            }
+        };
+        if result.is_err() {
+            return Ok(result);
+        }
+    }
+    Ok(Ok(()))
+}
+
+fn get_attribute<'t, 'k>(
+    target: &'t dyn Target,
+    key: &'k str,
+) -> Result<AttributeRef<'t>, Failure<'k>> {
+    lookup_target_path(key, |k| target.get_attribute(k)).ok_or(Failure::MissingAttribute(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_parse_format() {
+        use Conversion::*;
+        use Segment::*;
+        assert_eq!(parse_format(""), Some(vec![]));
+        assert_eq!(parse_format("foo"), Some(vec![Literal("foo")]));
+        assert_eq!(parse_format("%(foo)s"), Some(vec![Placeholder("foo", Str)]));
+        assert_eq!(
+            parse_format("project-%(id)s_%(name)r:%(count)d%%"),
+            Some(vec![
+                Literal("project-"),
+                Placeholder("id", Str),
+                Literal("_"),
+                Placeholder("name", Repr),
+                Literal(":"),
+                Placeholder("count", Int),
+                Literal("%"),
+            ])
+        );
+        assert_eq!(
+            parse_format("%(foo(bar))i%(target.user_id)s"),
+            Some(vec![
+                Placeholder("foo(bar)", Int),
+                Placeholder("target.user_id", Str)
+            ])
+        );
+
+        //malformed or unsupported
+        for input in [
+            "50%",
+            "%s",
+            "%d",
+            "%(foo)",
+            "%(foo",
+            "%(foo)x",
+            "%(foo)5s",
+            "%(foo)-s",
+            "%(foo(bar)s",
+        ] {
+            assert_eq!(parse_format(input), None, "input was {input:?}");
+        }
+    }
+
+    #[test]
+    fn test_references_target() {
+        assert!(references_target("%(foo)s"));
+        assert!(references_target("foo_%(bar)d%%"));
+        assert!(!references_target("foo"));
+        assert!(!references_target("100%%"));
+        //malformed format strings cannot be resolved, so they do not depend on the target
+        assert!(!references_target("%(foo)"));
+
+        assert_eq!(as_single_placeholder("%(foo.bar)s"), Some("foo.bar"));
+        assert_eq!(as_single_placeholder("%(foo)d"), None);
+        assert_eq!(as_single_placeholder("x%(foo)s"), None);
+        assert_eq!(as_single_placeholder("foo"), None);
+    }
+
+    #[test]
+    fn test_resolve_target_attr_refs() {
+        let target: HashMap<String, String> = [
+            ("id", "p-1"),
+            ("name", "it's"),
+            ("count", "042"),
+            ("quote", "say \"hi\"\n"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_owned(), v.to_owned()))
+        .collect();
+
+        let test_cases = [
+            ("foo", Ok("foo")),
+            ("%(id)s", Ok("p-1")),
+            ("project-%(id)s", Ok("project-p-1")),
+            ("%(id)s_%(name)s", Ok("p-1_it's")),
+            ("%(count)d%%", Ok("42%")),
+            ("%(count)i", Ok("42")),
+            ("%(id)r", Ok("'p-1'")),
+            ("%(name)r", Ok("\"it's\"")),
+            ("%(quote)r", Ok("'say \"hi\"\\n'")),
+            (
+                "%(missing)s",
+                Err(InterpolationError::MissingAttribute("missing".into())),
+            ),
+            ("%(id)d", Err(InterpolationError::NotAnInteger("id".into()))),
+            ("100%", Err(InterpolationError::InvalidFormat)),
+            ("%(id)x", Err(InterpolationError::InvalidFormat)),
+        ];
+        for (input, expected) in test_cases {
+            let actual = resolve_target_attr_refs(input, &target);
+            assert_eq!(
+                actual.as_deref().map_err(Clone::clone),
+                expected,
+                "input was {input:?}"
+            );
+
+            //pre-parsed format strings yield the same results
+            let Some(format) = Format::parse(input) else {
+                assert_eq!(expected, Err(InterpolationError::InvalidFormat));
+                continue;
+            };
+            assert_eq!(
+                format.resolve(&target).as_deref(),
+                expected.as_ref().ok().copied(),
+                "input was {input:?}"
+            );
+            match expected {
+                Ok(expected) => {
+                    assert!(format.matches(&target, expected));
+                    assert!(!format.matches(&target, &format!("{expected}x")));
+                    assert!(!format.matches(&target, &expected[..expected.len() - 1]));
+                }
+                Err(_) => assert!(!format.matches(&target, "")),
+            }
+        }
+
+        //string attributes are lent without copying them
+        assert!(matches!(
+            resolve_target_attr_refs("%(id)s", &target),
+            Ok(Cow::Borrowed("p-1"))
+        ));
+
+        //string attributes can have dotted names, but do not contain nested values
+        let target: HashMap<String, String> = [("a.b", "x"), ("c", "y")]
+            .into_iter()
+            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+            .collect();
+        assert_eq!(
+            resolve_target_attr_refs("%(a.b)s", &target).as_deref(),
+            Ok("x")
+        );
+        assert_eq!(
+            resolve_target_attr_refs("%(c.d)s", &target).as_deref(),
+            Err(&InterpolationError::MissingAttribute("c.d".into()))
+        );
+
+        //lists within a dotted path are traversed like in oslo.policy
+        let target: HashMap<String, AttributeValue> = [(
+            "a".to_owned(),
+            AttributeValue::from(vec![AttributeValue::from_iter([("b", 1)])]),
+        )]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            resolve_target_attr_refs("%(a.b)s", &target).as_deref(),
+            Ok("1")
+        );
+    }
+}
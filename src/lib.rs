@@ -19,19 +19,67 @@
 #![doc = include_str!("../README.md")]
 
 /// Types for the syntax tree produced by the parser.
-pub(crate) mod ast;
+mod ast;
+pub use ast::{Expression, LeftHandSide, Rule};
+
+/// Asynchronous evaluation of rules with checkers that need to wait for I/O.
+mod async_eval;
+
+/// Structured values of token and target attributes.
+mod attribute;
+pub use attribute::{flatten_attributes, AttributeRef, AttributeValue};
 
 /// Checker implementations.
 mod checkers;
 pub use checkers::*;
 
+/// Wrapper around a RuleSet with error reporting for API handlers.
+mod enforcer;
+pub use enforcer::*;
+
+/// Precompiled form of a RuleSet for fast evaluation.
+mod frozen;
+pub use frozen::*;
+
+/// Interpolation of target object attributes into the right-hand side of checks.
+mod interpolation;
+pub use interpolation::InterpolationError;
+
+/// Loaders for policy files in YAML and JSON format.
+mod loader;
+pub use loader::*;
+
+/// Partial evaluation of rules without a target object.
+mod partial;
+pub use partial::*;
+
 /// Container and evaluation engine for policy rules.
 mod ruleset;
 pub use ruleset::*;
 
 /// Parser implementation.
-pub(crate) mod parser;
+mod parser;
+pub use parser::{ParseError, ParseErrors};
+
+/// Registry of default rules that are defined in code.
+mod registry;
+pub use registry::*;
 
 /// Data model for a request's attributes.
 mod request;
 pub use request::*;
+
+/// Simplification of rule expressions.
+mod simplify;
+
+/// Compilation of residual rules into SQL predicates.
+mod sql;
+pub use sql::*;
+
+/// Traces for explaining policy decisions.
+mod trace;
+pub use trace::*;
+
+/// Static analysis of policy rules.
+mod validation;
+pub use validation::*;
@@ -0,0 +1,680 @@
+/******************************************************************************
+*
+*  Copyright 2023 Stefan Majewsky <majewsky@gmx.net>
+*
+*  Licensed under the Apache License, Version 2.0 (the "License");
+*  you may not use this file except in compliance with the License.
+*  You may obtain a copy of the License at
+*
+*      http://www.apache.org/licenses/LICENSE-2.0
+*
+*  Unless required by applicable law or agreed to in writing, software
+*  distributed under the License is distributed on an "AS IS" BASIS,
+*  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+*  See the License for the specific language governing permissions and
+*  limitations under the License.
+*
+******************************************************************************/
+
+use std::fmt;
+use thiserror::Error;
+
+use crate::parser::ParseError;
+
+//The loaders themselves are only available with the respective features, but SourceLocation is
+//always available since it is recorded in the RuleSet.
+#[cfg(any(feature = "yaml", feature = "json"))]
+use {
+    crate::parser::parse_rule, crate::ruleset::RuleSet, std::collections::HashMap, std::io::Read,
+    std::path::Path,
+};
+
+/// A location within a policy file, as reported by [LoadError] and
+/// [RuleSet::rule_source](crate::RuleSet::rule_source).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceLocation {
+    file: String,
+    line: usize,
+    column: usize,
+}
+
+impl SourceLocation {
+    /// Returns the name of the policy file.
+    pub fn file(&self) -> &str {
+        &self.file
+    }
+
+    /// Returns the line number within the policy file (1-based).
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// Returns the column number within the line (1-based, counted in chars).
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    /// Computes the location of the given byte offset within `input`.
+    #[cfg(feature = "json")]
+    fn from_offset(file: &str, input: &str, offset: usize) -> Self {
+        let before = &input[..offset];
+        let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+        Self {
+            file: file.to_owned(),
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+/// Error type for the policy file loaders, e.g. `RuleSet::load_yaml` (requires the `yaml` feature).
+#[derive(Error, Debug)]
+pub enum LoadError {
+    /// The policy file could not be read.
+    #[error("could not read {file}: {source}")]
+    Io {
+        file: String,
+        source: std::io::Error,
+    },
+    /// The policy file is not a valid document, or its top level is not a map.
+    #[error("{location}: {message}")]
+    Syntax {
+        location: SourceLocation,
+        message: String,
+    },
+    /// The policy file defines the same rule more than once.
+    #[error("{location}: rule {rule_name:?} was already defined at line {}", .previous.line)]
+    DuplicateRule {
+        location: SourceLocation,
+        rule_name: String,
+        previous: SourceLocation,
+    },
+    /// The value of a rule in the policy file is not a string.
+    #[error("{location}: rule {rule_name:?} is not a string")]
+    NotAString {
+        location: SourceLocation,
+        rule_name: String,
+    },
+    /// Some of the rules in the policy file could not be parsed. Each [ParseError] comes with the
+    /// location of the respective rule within the policy file.
+    #[error("{}", fmt_invalid_rules(.0))]
+    InvalidRules(Vec<(SourceLocation, ParseError)>),
+}
+
+fn fmt_invalid_rules(errors: &[(SourceLocation, ParseError)]) -> String {
+    if let [(location, err)] = errors {
+        return format!("{location}: {err}");
+    }
+    let mut result = format!("could not parse {} rules:", errors.len());
+    for (location, err) in errors {
+        result.push_str(&format!("\n- {location}: {err}"));
+    }
+    result
+}
+
+/// A rule as it appears in a policy file, before it is parsed.
+#[cfg(any(feature = "yaml", feature = "json"))]
+struct RawRule {
+    name: String,
+    rule_str: String,
+    location: SourceLocation,
+}
+
+/// Checks that no rule is defined twice within the same policy file.
+#[cfg(any(feature = "yaml", feature = "json"))]
+fn check_duplicates(raw_rules: &[RawRule]) -> Result<(), LoadError> {
+    let mut seen: HashMap<&str, &SourceLocation> = HashMap::new();
+    for raw in raw_rules {
+        if let Some(previous) = seen.insert(&raw.name, &raw.location) {
+            return Err(LoadError::DuplicateRule {
+                location: raw.location.clone(),
+                rule_name: raw.name.clone(),
+                previous: previous.clone(),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(any(feature = "yaml", feature = "json"))]
+impl RuleSet {
+    /// Parses the rules found by one of the loaders and adds them to this RuleSet. Like
+    /// [RuleSet::add_rules], this either adds all rules or none of them. If a rule name appears
+    /// multiple times (from different files), the last definition wins.
+    fn add_raw_rules(&mut self, raw_rules: Vec<RawRule>) -> Result<(), LoadError> {
+        let mut parsed = Vec::with_capacity(raw_rules.len());
+        let mut errors = Vec::new();
+        for raw in raw_rules {
+            match parse_rule(Some(&raw.name), &raw.rule_str) {
+                Ok(rule) => parsed.push((raw.name, rule, raw.location)),
+                Err(err) => errors.push((raw.location, err)),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(LoadError::InvalidRules(errors));
+        }
+        let mut rules = Vec::with_capacity(parsed.len());
+        for (name, rule, location) in parsed {
+            self.sources.insert(name.clone(), location);
+            rules.push((name, rule));
+        }
+        self.insert_rules(rules);
+        Ok(())
+    }
+}
+
+/// Reads a policy file into a string. This is shared by all loaders for readers and paths.
+#[cfg(any(feature = "yaml", feature = "json"))]
+fn read_to_string(mut reader: impl Read, file_name: &str) -> Result<String, LoadError> {
+    let mut buf = String::new();
+    match reader.read_to_string(&mut buf) {
+        Ok(_) => Ok(buf),
+        Err(source) => Err(LoadError::Io {
+            file: file_name.to_owned(),
+            source,
+        }),
+    }
+}
+
+/// Opens a policy file for reading. This is shared by all loaders for paths.
+#[cfg(any(feature = "yaml", feature = "json"))]
+fn open_file(path: &Path) -> Result<(std::fs::File, String), LoadError> {
+    let file_name = path.display().to_string();
+    match std::fs::File::open(path) {
+        Ok(file) => Ok((file, file_name)),
+        Err(source) => Err(LoadError::Io {
+            file: file_name,
+            source,
+        }),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// YAML
+
+#[cfg(feature = "yaml")]
+impl RuleSet {
+    /// Loads rules from a policy file in YAML format, i.e. a map of rule names to rule strings.
+    /// The `file_name` is only used for error messages.
+    ///
+    /// Like [RuleSet::add_rules], this either adds all rules in the file or none of them. Rules
+    /// that already exist in this RuleSet are overwritten.
+    pub fn load_yaml_str(&mut self, input: &str, file_name: &str) -> Result<(), LoadError> {
+        let raw_rules = scan_yaml(input, file_name)?;
+        check_duplicates(&raw_rules)?;
+        self.add_raw_rules(raw_rules)
+    }
+
+    /// Like [RuleSet::load_yaml_str], but reads the policy file from the given reader.
+    pub fn load_yaml(&mut self, reader: impl Read, file_name: &str) -> Result<(), LoadError> {
+        let input = read_to_string(reader, file_name)?;
+        self.load_yaml_str(&input, file_name)
+    }
+
+    /// Like [RuleSet::load_yaml_str], but reads the policy file from the given path.
+    pub fn load_yaml_file(&mut self, path: impl AsRef<Path>) -> Result<(), LoadError> {
+        let (file, file_name) = open_file(path.as_ref())?;
+        self.load_yaml(file, &file_name)
+    }
+
+    /// Loads all policy files from a policy directory like `/etc/myservice/policy.d`, with the
+    /// same semantics as oslo.policy's `policy_dirs` option. This is usually called after
+    /// [RuleSet::load_yaml_file] has loaded the main policy file.
+    ///
+    /// - Files are applied in lexical order of their names. Each file overrides the rules that were
+    ///   defined by earlier files (or were already present in this RuleSet).
+    /// - Subdirectories and files whose name starts with a dot are ignored.
+    /// - All files are parsed as YAML, regardless of their extension. (Policy files in JSON format
+    ///   are valid YAML, too.)
+    /// - If the directory does not exist, nothing is loaded and no error is returned.
+    ///
+    /// Like [RuleSet::add_rules], this either adds the rules from all files or none of them. Use
+    /// [RuleSet::rule_source] to find out which file a rule was loaded from.
+    pub fn load_yaml_dir(&mut self, path: impl AsRef<Path>) -> Result<(), LoadError> {
+        let path = path.as_ref();
+        let io_error = |source| LoadError::Io {
+            file: path.display().to_string(),
+            source,
+        };
+        let entries = match std::fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(io_error(err)),
+        };
+
+        let mut file_paths = Vec::new();
+        for entry in entries {
+            let entry = entry.map_err(io_error)?;
+            //NOTE: This follows symlinks, same as os.walk() does for its list of files.
+            let is_file = entry.path().is_file();
+            let is_hidden = entry.file_name().to_string_lossy().starts_with('.');
+            if is_file && !is_hidden {
+                file_paths.push(entry.path());
+            }
+        }
+        file_paths.sort_unstable();
+
+        let mut raw_rules = Vec::new();
+        for file_path in file_paths {
+            let (file, file_name) = open_file(&file_path)?;
+            let input = read_to_string(file, &file_name)?;
+            let file_rules = scan_yaml(&input, &file_name)?;
+            check_duplicates(&file_rules)?;
+            raw_rules.extend(file_rules);
+        }
+        self.add_raw_rules(raw_rules)
+    }
+}
+
+/// Extracts all rules from a YAML document, but does not parse them yet.
+#[cfg(feature = "yaml")]
+fn scan_yaml(input: &str, file_name: &str) -> Result<Vec<RawRule>, LoadError> {
+    use yaml_rust2::parser::{Event, Parser};
+    use yaml_rust2::scanner::{Marker, TScalarStyle};
+    use yaml_rust2::Yaml;
+
+    let location = |mark: Marker| SourceLocation {
+        file: file_name.to_owned(),
+        line: mark.line(),
+        column: mark.col() + 1,
+    };
+    let syntax_error = |mark: Marker, message: &str| LoadError::Syntax {
+        location: location(mark),
+        message: message.to_owned(),
+    };
+
+    let mut parser = Parser::new_from_str(input);
+    let mut next = || {
+        parser.next_token().map_err(|err| LoadError::Syntax {
+            location: location(*err.marker()),
+            message: err.info().to_owned(),
+        })
+    };
+
+    let mut rules = Vec::new();
+    let mut document_count = 0;
+    loop {
+        let (event, mark) = next()?;
+        match event {
+            Event::StreamStart | Event::DocumentEnd => {}
+            Event::StreamEnd => break,
+            Event::DocumentStart => {
+                document_count += 1;
+                if document_count > 1 {
+                    return Err(syntax_error(mark, "expected only a single YAML document"));
+                }
+            }
+            //an empty document (or one that only contains null) does not contain any rules
+            Event::Scalar(ref value, TScalarStyle::Plain, _, None)
+                if matches!(Yaml::from_str(value), Yaml::Null) => {}
+            Event::MappingStart(_, _) => loop {
+                let (event, mark) = next()?;
+                let name = match event {
+                    Event::MappingEnd => break,
+                    Event::Scalar(name, _, _, _) => name,
+                    _ => return Err(syntax_error(mark, "expected rule name")),
+                };
+                let (event, mark) = next()?;
+                let rule_str = match event {
+                    Event::Scalar(value, style, _, tag) => {
+                        //plain scalars like `true` or `42` are not strings, unless tagged `!!str`
+                        let is_str_tag = tag.is_some_and(|t| t.suffix == "str");
+                        if style != TScalarStyle::Plain
+                            || is_str_tag
+                            || matches!(Yaml::from_str(&value), Yaml::String(_))
+                        {
+                            Some(value)
+                        } else {
+                            None
+                        }
+                    }
+                    _ => None,
+                };
+                match rule_str {
+                    Some(rule_str) => rules.push(RawRule {
+                        name,
+                        rule_str,
+                        location: location(mark),
+                    }),
+                    None => {
+                        return Err(LoadError::NotAString {
+                            location: location(mark),
+                            rule_name: name,
+                        })
+                    }
+                }
+            },
+            _ => {
+                return Err(syntax_error(
+                    mark,
+                    "expected a map of rule names to rule strings",
+                ))
+            }
+        }
+    }
+    Ok(rules)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// JSON
+
+#[cfg(feature = "json")]
+impl RuleSet {
+    /// Loads rules from a policy file in JSON format, i.e. an object mapping rule names to rule
+    /// strings. The `file_name` is only used for error messages.
+    ///
+    /// Like [RuleSet::add_rules], this either adds all rules in the file or none of them. Rules
+    /// that already exist in this RuleSet are overwritten.
+    pub fn load_json_str(&mut self, input: &str, file_name: &str) -> Result<(), LoadError> {
+        let raw_rules = scan_json(input, file_name)?;
+        check_duplicates(&raw_rules)?;
+        self.add_raw_rules(raw_rules)
+    }
+
+    /// Like [RuleSet::load_json_str], but reads the policy file from the given reader.
+    pub fn load_json(&mut self, reader: impl Read, file_name: &str) -> Result<(), LoadError> {
+        let input = read_to_string(reader, file_name)?;
+        self.load_json_str(&input, file_name)
+    }
+
+    /// Like [RuleSet::load_json_str], but reads the policy file from the given path.
+    pub fn load_json_file(&mut self, path: impl AsRef<Path>) -> Result<(), LoadError> {
+        let (file, file_name) = open_file(path.as_ref())?;
+        self.load_json(file, &file_name)
+    }
+}
+
+/// Extracts all rules from a JSON document, but does not parse them yet.
+#[cfg(feature = "json")]
+fn scan_json(input: &str, file_name: &str) -> Result<Vec<RawRule>, LoadError> {
+    use serde_json::value::RawValue;
+
+    /// Deserializes a JSON object into its entries, without losing duplicate keys.
+    struct Entries<'a>(Vec<(String, &'a RawValue)>);
+
+    impl<'de> serde::Deserialize<'de> for Entries<'de> {
+        fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+            d.deserialize_map(EntriesVisitor)
+        }
+    }
+
+    struct EntriesVisitor;
+
+    impl<'de> serde::de::Visitor<'de> for EntriesVisitor {
+        type Value = Entries<'de>;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("an object mapping rule names to rule strings")
+        }
+
+        fn visit_map<A: serde::de::MapAccess<'de>>(
+            self,
+            mut map: A,
+        ) -> Result<Self::Value, A::Error> {
+            let mut entries = Vec::new();
+            while let Some(entry) = map.next_entry()? {
+                entries.push(entry);
+            }
+            Ok(Entries(entries))
+        }
+    }
+
+    let entries: Entries = serde_json::from_str(input).map_err(|err| {
+        //the error message contains the location, but we want to report it in our own format
+        let suffix = format!(" at line {} column {}", err.line(), err.column());
+        let message = err.to_string();
+        LoadError::Syntax {
+            location: SourceLocation {
+                file: file_name.to_owned(),
+                line: err.line(),
+                column: err.column().max(1),
+            },
+            message: message.strip_suffix(&suffix).unwrap_or(&message).to_owned(),
+        }
+    })?;
+
+    let mut rules = Vec::with_capacity(entries.0.len());
+    for (name, value) in entries.0 {
+        //since we deserialize from a string, `value` borrows from `input`
+        let offset = value.get().as_ptr() as usize - input.as_ptr() as usize;
+        let location = SourceLocation::from_offset(file_name, input, offset);
+        match serde_json::from_str::<String>(value.get()) {
+            Ok(rule_str) => rules.push(RawRule {
+                name,
+                rule_str,
+                location,
+            }),
+            Err(_) => {
+                return Err(LoadError::NotAString {
+                    location,
+                    rule_name: name,
+                })
+            }
+        }
+    }
+    Ok(rules)
+}
+
+#[cfg(all(test, any(feature = "yaml", feature = "json")))]
+mod tests {
+    use super::*;
+
+    fn rule_names(ruleset: &RuleSet) -> Vec<&str> {
+        let mut names: Vec<&str> = ruleset.rules().map(|(name, _)| name).collect();
+        names.sort_unstable();
+        names
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn test_load_yaml() {
+        let input = "# comment\n\"admin_required\": \"role:admin\"\nowner: 'user_id:%(user_id)s'\nadmin_or_owner: rule:admin_required or rule:owner\n";
+        let mut ruleset = RuleSet::new();
+        ruleset.load_yaml(input.as_bytes(), "policy.yaml").unwrap();
+        assert_eq!(
+            rule_names(&ruleset),
+            vec!["admin_or_owner", "admin_required", "owner"]
+        );
+        assert_eq!(
+            ruleset.get_rule("owner").unwrap().to_string(),
+            "user_id:%(user_id)s"
+        );
+
+        //empty documents are fine
+        for input in ["", "---\n", "# nothing here\n", "{}"] {
+            let mut ruleset = RuleSet::new();
+            ruleset.load_yaml_str(input, "policy.yaml").unwrap();
+            assert_eq!(rule_names(&ruleset), Vec::<&str>::new());
+        }
+
+        //error cases
+        let test_cases = [
+            (
+                "foo: role:admin\nbar: role:member\nfoo: role:reader\n",
+                "policy.yaml:3:6: rule \"foo\" was already defined at line 1",
+            ),
+            (
+                "foo: role:admin\nbar: true\n",
+                "policy.yaml:2:6: rule \"bar\" is not a string",
+            ),
+            (
+                "foo: role:admin\nbar:\n  - role:member\n",
+                "policy.yaml:3:3: rule \"bar\" is not a string",
+            ),
+            (
+                "foo: role:admin\nbar: 42\n",
+                "policy.yaml:2:6: rule \"bar\" is not a string",
+            ),
+            (
+                "- role:admin\n",
+                "policy.yaml:1:1: expected a map of rule names to rule strings",
+            ),
+            (
+                "foo: role:admin\n---\nbar: role:member\n",
+                "policy.yaml:2:1: expected only a single YAML document",
+            ),
+            (
+                "foo: \"role:admin\n",
+                "policy.yaml:1:6: while scanning a quoted scalar, found unexpected end of stream",
+            ),
+        ];
+        for (input, expected) in test_cases {
+            let mut ruleset = RuleSet::new();
+            let err = ruleset.load_yaml_str(input, "policy.yaml").unwrap_err();
+            assert_eq!(err.to_string(), expected, "input was {input:?}");
+            assert_eq!(rule_names(&ruleset), Vec::<&str>::new());
+        }
+
+        //quoted scalars and scalars with an explicit `!!str` tag are always strings
+        let mut ruleset = RuleSet::new();
+        ruleset
+            .load_yaml_str("foo: '@'\nbar: !!str role:admin\n", "policy.yaml")
+            .unwrap();
+        assert_eq!(rule_names(&ruleset), vec!["bar", "foo"]);
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn test_load_yaml_with_parse_errors() {
+        let input = "good: role:admin\nbad1: role:admin or\n\nbad2: >\n  and role:member\n";
+        let mut ruleset = RuleSet::new();
+        let err = ruleset.load_yaml_str(input, "policy.yaml").unwrap_err();
+        let LoadError::InvalidRules(errors) = &err else {
+            panic!("unexpected error: {err}");
+        };
+        let locations: Vec<_> = errors
+            .iter()
+            .map(|(loc, err)| (loc.line(), loc.column(), err.rule_name().unwrap()))
+            .collect();
+        //for block scalars, the location points to the start of the scalar's content
+        assert_eq!(locations, vec![(2, 7, "bad1"), (5, 3, "bad2")]);
+        assert!(err.to_string().starts_with(
+            "could not parse 2 rules:\n- policy.yaml:2:7: could not parse rule \"bad1\": "
+        ));
+        //nothing was added
+        assert_eq!(rule_names(&ruleset), Vec::<&str>::new());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_load_json() {
+        let input = "{\n  \"admin_required\": \"role:admin\",\n  \"owner\":\"user_id:%(user_id)s\",\n  \"admin_or_owner\": \"rule:admin_required or rule:owner\"\n}\n";
+        let mut ruleset = RuleSet::new();
+        ruleset.load_json(input.as_bytes(), "policy.json").unwrap();
+        assert_eq!(
+            rule_names(&ruleset),
+            vec!["admin_or_owner", "admin_required", "owner"]
+        );
+
+        //error cases
+        let test_cases = [
+            (
+                "{\"foo\": \"role:admin\",\n \"foo\": \"role:member\"}",
+                "policy.json:2:9: rule \"foo\" was already defined at line 1",
+            ),
+            (
+                "{\"foo\": \"role:admin\",\n \"bar\": [\"role:member\"]}",
+                "policy.json:2:9: rule \"bar\" is not a string",
+            ),
+            (
+                "{\"foo\": \"role:admin\",\n \"bar\": null}",
+                "policy.json:2:9: rule \"bar\" is not a string",
+            ),
+            (
+                "[\"role:admin\"]",
+                "policy.json:1:1: invalid type: sequence, expected an object mapping rule names to rule strings",
+            ),
+            (
+                "{\"foo\": \"role:admin\",\n}",
+                "policy.json:2:1: trailing comma",
+            ),
+            (
+                "{\"foo\": \"role:admin or\", \"bar\": \"and role:admin\"}",
+                concat!(
+                    "could not parse 2 rules:\n",
+                    "- policy.json:1:9: could not parse rule \"foo\": error at 1:14: expected one of \"not\", check or opening parenthesis\n",
+                    "- policy.json:1:33: could not parse rule \"bar\": error at 1:1: expected one of \"not\", check or opening parenthesis",
+                ),
+            ),
+        ];
+        for (input, expected) in test_cases {
+            let mut ruleset = RuleSet::new();
+            let err = ruleset.load_json_str(input, "policy.json").unwrap_err();
+            assert_eq!(err.to_string(), expected, "input was {input:?}");
+            assert_eq!(rule_names(&ruleset), Vec::<&str>::new());
+        }
+    }
+
+    #[test]
+    fn test_load_from_missing_file() {
+        let path = "/this/file/does/not/exist.yaml";
+        let err = open_file(Path::new(path)).unwrap_err();
+        assert!(matches!(err, LoadError::Io { ref file, .. } if file == path));
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn test_load_yaml_dir() {
+        let dir = std::env::temp_dir().join(format!("oslo-policy-test-{}", std::process::id()));
+        let policy_d = dir.join("policy.d");
+        std::fs::create_dir_all(policy_d.join("subdir")).unwrap();
+        let write = |name: &str, contents: &str| std::fs::write(dir.join(name), contents).unwrap();
+        write(
+            "policy.yaml",
+            "admin_required: role:admin\nowner: user_id:%(user_id)s\nreader: role:reader\n",
+        );
+        write(
+            "policy.d/20-owner.yaml",
+            "owner: project_id:%(project_id)s\n",
+        );
+        write(
+            "policy.d/10-admin.json",
+            "{\"admin_required\": \"role:cloud_admin\", \"owner\": \"!\"}",
+        );
+        write("policy.d/30-empty.yaml", "");
+        write("policy.d/.hidden.yaml", "reader: '!'\n");
+        write("policy.d/subdir/40-ignored.yaml", "reader: '!'\n");
+
+        let mut ruleset = RuleSet::new();
+        ruleset.load_yaml_file(dir.join("policy.yaml")).unwrap();
+        ruleset.load_yaml_dir(&policy_d).unwrap();
+        //missing policy directories are ignored
+        ruleset.load_yaml_dir(dir.join("missing.d")).unwrap();
+
+        let rule_str = |name| ruleset.get_rule(name).unwrap().to_string();
+        assert_eq!(rule_str("admin_required"), "role:cloud_admin");
+        assert_eq!(rule_str("owner"), "project_id:%(project_id)s");
+        assert_eq!(rule_str("reader"), "role:reader");
+
+        let source = |name| {
+            let loc = ruleset.rule_source(name).unwrap();
+            let file = Path::new(loc.file()).strip_prefix(&dir).unwrap().to_owned();
+            (file.display().to_string(), loc.line())
+        };
+        assert_eq!(
+            source("admin_required"),
+            ("policy.d/10-admin.json".into(), 1)
+        );
+        assert_eq!(source("owner"), ("policy.d/20-owner.yaml".into(), 1));
+        assert_eq!(source("reader"), ("policy.yaml".into(), 3));
+
+        //rules that are added without a policy file do not have a source
+        ruleset.add_rule("reader", "role:viewer").unwrap();
+        assert_eq!(ruleset.rule_source("reader"), None);
+
+        //if any file is broken, nothing is loaded
+        write("policy.d/50-broken.yaml", "reader: role:viewer or\n");
+        let mut ruleset = RuleSet::new();
+        let err = ruleset.load_yaml_dir(&policy_d).unwrap_err();
+        assert!(err.to_string().contains("50-broken.yaml:1:9: "), "{err}");
+        assert_eq!(rule_names(&ruleset), Vec::<&str>::new());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -16,25 +16,34 @@
 *
 ******************************************************************************/
 
+use std::fmt;
+use std::ops::Range;
+use thiserror::Error;
+
 use crate::ast::*;
 
 peg::parser! {
     grammar policy_parser() for str {
-        rule _ = [' ' | '\t' | '\n']*
+        rule _ = quiet!{[' ' | '\t' | '\n']*}
 
+        // NOTE: This must not be quiet!{}, otherwise parse errors would not report the position
+        // and expected tokens of the actual failure within the expression.
         pub rule expr() -> Expression
-            = quiet!{_ e:expr_inner() _ { e }}
-            / expected!("expression")
+            = _ e:expr_inner() _ { e }
 
         rule expr_inner() -> Expression = precedence!{
-            x:(@) _ "or" _ y:@ { Expression::Or(Box::new(x), Box::new(y)) }
+            x:(@) _ "or" keyword_end() _ y:@ { Expression::Or(Box::new(x), Box::new(y)) }
             --
-            x:(@) _ "and" _ y:@ { Expression::And(Box::new(x), Box::new(y)) }
+            x:(@) _ "and" keyword_end() _ y:@ { Expression::And(Box::new(x), Box::new(y)) }
             --
-            "not" e:@ { Expression::Not(Box::new(e)) }
+            "not" keyword_end() _ e:@ { Expression::Not(Box::new(e)) }
             e:atom() { e }
         }
 
+        // Keywords must be separate tokens, e.g. `orange:foo` is a check, not `or ange:foo`.
+        rule keyword_end()
+            = !(!([' ' | '\t' | '\n' | '(' | ')']) [_])
+
         rule atom() -> Expression
             = quiet!{_ c:atom_inner() _ { c }}
             / expected!("check or opening parenthesis")
@@ -52,28 +61,230 @@ peg::parser! {
         // quoted strings. The rule 'foo':%(bar)s works as expected, but 'foo foo':%(bar)s does
         // not, because it gets split on the whitespace between the foo's, even though it looks
         // like the quoting would prevent token splitting here. We mimic this insane behavior here.
+        //
+        // Following from the tokenization logic, opening parentheses at the start of a check and
+        // closing parentheses at the end of a check are not part of the check. For example,
+        // `(role:foo)` is the same as `( role:foo )`, but `role:%(foo)s` is a single check.
         rule check_lhs() -> LeftHandSide
-            = "'"  s:check_lhs_inner() "'"  { LeftHandSide::Literal(s) }
-            / "\"" s:check_lhs_inner() "\"" { LeftHandSide::Literal(s) }
-            /      s:check_lhs_inner()      { LeftHandSide::Identifier(s) }
+            = "'"  s:literal_inner('\'') "'"  { LeftHandSide::Literal(s) }
+            / "\"" s:literal_inner('"')  "\"" { LeftHandSide::Literal(s) }
+            / !"(" s:check_lhs_inner()        { LeftHandSide::Identifier(s) }
         // We forbid:
         // - whitespace in any part of the check (as explained above)
         // - colons on the LHS (the first colon in the check splits LHS and RHS)
-        // - quotes and backslashes on the LHS (we only support simple string literals without
-        //   escape sequences)
+        // - quotes and backslashes in identifiers on the LHS
         rule check_lhs_inner() -> String
             = s:$([^' ' | '\t' | '\n' | ':' | '\'' | '"' | '\\']+) { s.to_owned() }
+
+        // String literals on the LHS are evaluated by the reference implementation with Python's
+        // ast.literal_eval(), so we support the same escape sequences as a Python string literal.
+        // The same restrictions as for identifiers apply, except that the respective other quote
+        // character is allowed.
+        rule literal_inner(quote: char) -> String
+            = parts:literal_part(quote)* { parts.concat() }
+        rule literal_part(quote: char) -> String
+            = "\\" s:escape_sequence() { s }
+            / c:[c if c != quote && !matches!(c, ' ' | '\t' | '\n' | ':' | '\\')] { c.to_string() }
+        rule escape_sequence() -> String
+            = "\\" { "\\".to_owned() }
+            / "'" { "'".to_owned() }
+            / "\"" { "\"".to_owned() }
+            / "a" { "\x07".to_owned() }
+            / "b" { "\x08".to_owned() }
+            / "f" { "\x0C".to_owned() }
+            / "n" { "\n".to_owned() }
+            / "r" { "\r".to_owned() }
+            / "t" { "\t".to_owned() }
+            / "v" { "\x0B".to_owned() }
+            / "x" digits:$(hex_digit()*<2>) {? codepoint(digits, 16) }
+            / "u" digits:$(hex_digit()*<4>) {? codepoint(digits, 16) }
+            / "U" digits:$(hex_digit()*<8>) {? codepoint(digits, 16) }
+            / digits:$(['0'..='7']*<1,3>) {? codepoint(digits, 8) }
+            // Python retains the backslash for unknown escape sequences. Malformed numeric escape
+            // sequences are errors, as are named escape sequences like `\N{DIGIT ONE}` (we do not
+            // want to carry a copy of the Unicode name database around).
+            / c:[c if !matches!(c, ' ' | '\t' | '\n' | ':' | 'x' | 'u' | 'U' | 'N')] { format!("\\{c}") }
+        rule hex_digit() = ['0'..='9' | 'a'..='f' | 'A'..='F']
+
         rule check_rhs() -> String
-            = s:$([^' ' | '\t' | '\n']+) { s.to_owned() }
+            = s:$((!check_rhs_end() [^' ' | '\t' | '\n'])+) { s.to_owned() }
+        rule check_rhs_end()
+            = ")"* ([' ' | '\t' | '\n'] / ![_])
     }
 }
 
+/// Helper for parsing numeric escape sequences in string literals.
+fn codepoint(digits: &str, radix: u32) -> Result<String, &'static str> {
+    u32::from_str_radix(digits, radix)
+        .ok()
+        .and_then(char::from_u32)
+        .map(|c| c.to_string())
+        .ok_or("valid Unicode codepoint")
+}
+
 // The policy_parser module is private, so we need to expose an explicit interface to the outside.
-pub(crate) type InternalParseError = peg::error::ParseError<peg::str::LineCol>;
+type InternalParseError = peg::error::ParseError<peg::str::LineCol>;
 pub(crate) fn parse_expression(input: &str) -> Result<Expression, InternalParseError> {
     policy_parser::expr(input)
 }
 
+pub(crate) fn parse_rule(name: Option<&str>, input: &str) -> Result<Rule, ParseError> {
+    match parse_expression(input) {
+        Ok(expr) => Ok(Rule { expr }),
+        Err(err) => Err(ParseError {
+            rule_name: name.map(|n| n.to_owned()),
+            input: input.to_owned(),
+            error: err,
+        }),
+    }
+}
+
+///Error type returned by [RuleSet::add_rule](crate::RuleSet::add_rule) and by parsing a [Rule].
+///
+///This type hides the internal error type that the policy language parser returns.
+#[derive(Error, Debug)]
+pub struct ParseError {
+    rule_name: Option<String>,
+    input: String,
+    error: InternalParseError,
+}
+
+impl ParseError {
+    /// Returns the name of the rule that could not be parsed, or `None` if the error occurred
+    /// while parsing a [Rule] without a name.
+    pub fn rule_name(&self) -> Option<&str> {
+        self.rule_name.as_deref()
+    }
+
+    /// Returns the rule string that could not be parsed.
+    pub fn rule(&self) -> &str {
+        &self.input
+    }
+
+    /// Returns the line number (counting from 1) within [the rule string](Self::rule) at which
+    /// the parse error occurred. This is only ever larger than 1 if the rule string contains line
+    /// breaks.
+    pub fn line(&self) -> usize {
+        self.error.location.line
+    }
+
+    /// Returns the column number (counting from 1) within [the rule string](Self::rule) at which
+    /// the parse error occurred.
+    pub fn column(&self) -> usize {
+        self.error.location.column
+    }
+
+    /// Returns the byte offset within [the rule string](Self::rule) at which the parse error
+    /// occurred.
+    pub fn offset(&self) -> usize {
+        self.error.location.offset
+    }
+
+    /// Returns the byte range within [the rule string](Self::rule) that covers the character at
+    /// which the parse error occurred. If the parse error occurred at the end of the rule string,
+    /// this range is empty.
+    pub fn span(&self) -> Range<usize> {
+        let offset = self.offset();
+        let len = self.input[offset..]
+            .chars()
+            .next()
+            .map_or(0, char::len_utf8);
+        offset..(offset + len)
+    }
+
+    /// Returns the set of tokens that the parser would have accepted at the error location, in
+    /// sorted order. Literal tokens are shown in quotes, e.g. `"and"`, whereas descriptive token
+    /// names (like `expression`) appear without quotes.
+    pub fn expected(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.error.expected.tokens()
+    }
+
+    /// Renders this error into a multi-line string that shows the erroneous line of the rule
+    /// string, with a caret pointing at the location of the error. For example:
+    ///
+    /// ```text
+    /// could not parse rule "foo": error at 1:15: expected one of "not", check or opening parenthesis
+    ///     role:admin and
+    ///                   ^
+    /// ```
+    pub fn render(&self) -> String {
+        let offset = self.offset();
+        let line_start = self.input[..offset].rfind('\n').map_or(0, |idx| idx + 1);
+        let line_end = self.input[offset..]
+            .find('\n')
+            .map_or(self.input.len(), |idx| offset + idx);
+        //indent the caret with the same whitespace as the line itself, so that tabs line up
+        let caret_indent: String = self.input[line_start..offset]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        format!(
+            "{self}\n    {}\n    {caret_indent}^",
+            &self.input[line_start..line_end]
+        )
+    }
+}
+
+///Error type returned by [RuleSet::add_rules](crate::RuleSet::add_rules).
+///
+///This contains one [ParseError] for each rule that could not be parsed, sorted by rule name. It is
+///never empty.
+#[derive(Error, Debug)]
+pub struct ParseErrors(pub(crate) Vec<ParseError>);
+
+impl ParseErrors {
+    /// Iterates over the contained [ParseError]s.
+    pub fn iter(&self) -> std::slice::Iter<'_, ParseError> {
+        self.0.iter()
+    }
+
+    /// Returns the number of contained [ParseError]s.
+    #[allow(clippy::len_without_is_empty)] //is never empty
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.rule_name {
+            Some(name) => write!(f, "could not parse rule {name:?}: {}", self.error),
+            None => write!(f, "could not parse rule: {}", self.error),
+        }
+    }
+}
+
+impl fmt::Display for ParseErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let [err] = self.0.as_slice() {
+            return err.fmt(f);
+        }
+        write!(f, "could not parse {} rules:", self.0.len())?;
+        for err in &self.0 {
+            write!(f, "\n- {err}")?;
+        }
+        Ok(())
+    }
+}
+
+impl IntoIterator for ParseErrors {
+    type Item = ParseError;
+    type IntoIter = std::vec::IntoIter<ParseError>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a ParseErrors {
+    type Item = &'a ParseError;
+    type IntoIter = std::slice::Iter<'a, ParseError>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::parse_expression;
@@ -88,6 +299,8 @@ mod tests {
             parse_expression("    @    or   !  "),
             Ok(make_or(true, false))
         );
+        let expected = make_and(make_not(make_not(true)), make_not(make_not(false)));
+        assert_eq!(parse_expression("not not @ and not  not !"), Ok(expected));
     }
 
     fn assert_all_identical(inputs: &[&'static str]) {
@@ -173,6 +386,30 @@ mod tests {
         //This does not roundtrip back into `input` because serialization uses single quotes.
         assert_eq!(parsed.unwrap().to_string(), "'Member':%(role.name)s");
 
+        //test success case: parentheses around checks do not need to be separated by whitespace
+        let input = "(role:foo or (role:bar)) and user_id:%(user_id)s";
+        let lhs = make_or(make_check("role", "foo"), make_check("role", "bar"));
+        let rhs = make_check("user_id", "%(user_id)s");
+        let parsed = parse_expression(input);
+        assert_eq!(parsed, Ok(make_and(lhs, rhs)));
+        assert_eq!(
+            parsed.unwrap().to_string(),
+            "(role:foo or role:bar) and user_id:%(user_id)s"
+        );
+
+        //test success case: checks may start with keywords
+        let input = "orange:foo or nothing:bar and android:%(qux)s";
+        let lhs = make_check("orange", "foo");
+        let rhs = make_and(
+            make_check("nothing", "bar"),
+            make_check("android", "%(qux)s"),
+        );
+        assert_eq!(parse_expression(input), Ok(make_or(lhs, rhs)));
+
+        //test success case: parentheses inside a check are part of the check
+        let input = "role:foo)bar";
+        assert_eq!(parse_expression(input), Ok(make_check("role", "foo)bar")));
+
         //test failure case: empty string is not a valid rule (this is an intentional deviation
         //from the default implementation, where empty string means "allow all")
         let input = "";
@@ -182,10 +419,101 @@ mod tests {
         let input = "'foo bar':%(role.name)s";
         assert!(parse_expression(input).is_err());
 
-        //test failure case: escape sequences in literals are not allowed (not needed so far)
-        for escape_sequence in ["\\n", "\\\\", "\\\"", "\\'"] {
-            let input = format!("'foo{escape_sequence}bar':%(role.name)s");
-            assert!(parse_expression(&input).is_err());
+        //test failure case: escape sequences in identifiers are not allowed
+        let input = "foo\\nbar:%(role.name)s";
+        assert!(parse_expression(input).is_err());
+    }
+
+    fn assert_literal(lhs: &str, value: &str) {
+        let input = format!("{lhs}:%(name)s");
+        let parsed = parse_expression(&input);
+        assert_eq!(
+            parsed,
+            Ok(make_literal_check(value, "%(name)s")),
+            "input was {input:?}"
+        );
+        //serialization escapes the literal as necessary to make it roundtrip
+        let serialized = parsed.unwrap().to_string();
+        assert_eq!(
+            parse_expression(&serialized),
+            Ok(make_literal_check(value, "%(name)s")),
+            "input was {input:?}, serialized as {serialized:?}"
+        );
+    }
+
+    fn assert_not_literal(lhs: &str) {
+        let input = format!("{lhs}:%(name)s");
+        assert!(parse_expression(&input).is_err(), "input was {input:?}");
+    }
+
+    #[test]
+    fn test_literals_with_escape_sequences() {
+        //The reference implementation evaluates the LHS with Python's `ast.literal_eval()`, so
+        //these test cases are ported from the test suite of the Python interpreter itself.
+        //Multi-character strings in these tests that contain whitespace are omitted, since
+        //whitespace is not allowed within checks.
+
+        //<https://github.com/python/cpython/blob/v3.11.7/Lib/test/test_grammar.py#L259-L262>
+        assert_literal("''", "");
+        assert_literal(r#""""#, "");
+        assert_literal(r"'\''", "'");
+        assert_literal(r#""'""#, "'");
+        assert_literal(r#"'"'"#, "\"");
+        assert_literal(r#""\"""#, "\"");
+
+        //<https://github.com/python/cpython/blob/v3.11.7/Lib/test/test_string_literals.py#L81-L90>
+        assert_literal("'x'", "x");
+        assert_literal(r"'\x01'", "\x01");
+        assert_literal(r"'\x81'", "\u{81}");
+        assert_literal(r"'\u1881'", "\u{1881}");
+        assert_literal(r"'\U0001d120'", "\u{1d120}");
+
+        //<https://github.com/python/cpython/blob/v3.11.7/Lib/test/test_string_literals.py#L92-L106>
+        for lhs in [
+            r"'\x'",
+            r"'\x0'",
+            r"'\u'",
+            r"'\u0'",
+            r"'\u00'",
+            r"'\u000'",
+            r"'\U'",
+            r"'\U0'",
+            r"'\U00'",
+            r"'\U000'",
+            r"'\U0000'",
+            r"'\U00000'",
+            r"'\U000000'",
+            r"'\U0000000'",
+        ] {
+            assert_not_literal(lhs);
+        }
+
+        //<https://github.com/python/cpython/blob/v3.11.7/Lib/test/test_string_literals.py#L108-L113>
+        //(unknown escape sequences are kept as-is; Python only emits a DeprecationWarning)
+        for b in 1..128u8 {
+            if b"\n\r\"'01234567NU\\abfnrtuvx".contains(&b) {
+                continue;
+            }
+            let c = char::from(b);
+            //whitespace is not allowed within checks, and the first colon ends the LHS
+            if matches!(c, ' ' | '\t' | ':') {
+                continue;
+            }
+            assert_literal(&format!(r"'\{c}'"), &format!("\\{c}"));
         }
+
+        //<https://github.com/python/cpython/blob/v3.11.7/Lib/test/test_string_literals.py#L146-L149>
+        //(octal escapes beyond \377 are accepted; Python only emits a DeprecationWarning)
+        for i in 0o400..0o1000 {
+            assert_literal(
+                &format!(r"'\{i:o}'"),
+                &char::from_u32(i).unwrap().to_string(),
+            );
+        }
+
+        //test failure case: named escape sequences are not supported (this is an intentional
+        //deviation from the reference implementation, which resolves them using the Unicode
+        //name database)
+        assert_not_literal(r"'\N{DIGIT ONE}'");
     }
 }
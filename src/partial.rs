@@ -0,0 +1,474 @@
+/******************************************************************************
+*
+*  Copyright 2023 Stefan Majewsky <majewsky@gmx.net>
+*
+*  Licensed under the Apache License, Version 2.0 (the "License");
+*  you may not use this file except in compliance with the License.
+*  You may obtain a copy of the License at
+*
+*      http://www.apache.org/licenses/LICENSE-2.0
+*
+*  Unless required by applicable law or agreed to in writing, software
+*  distributed under the License is distributed on an "AS IS" BASIS,
+*  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+*  See the License for the specific language governing permissions and
+*  limitations under the License.
+*
+******************************************************************************/
+
+use crate::ast::{Expression, LeftHandSide, Rule};
+use crate::attribute::{lookup_path, AttributeValue};
+use crate::interpolation::references_target;
+use crate::request::Request;
+use crate::ruleset::{is_bool_literal, RuleSet, MAX_RULE_DEPTH};
+
+/// The result of [RuleSet::partially_evaluate].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PartialEvaluation {
+    /// The rule allows the request regardless of the target object.
+    Allowed,
+    /// The rule denies the request regardless of the target object.
+    Denied,
+    /// Whether the rule allows the request depends on the target object. The residual rule only
+    /// contains checks whose result may depend on the target object, and can be evaluated for
+    /// each target object with [RuleSet::evaluate_residual].
+    Residual(Rule),
+    /// There is no rule with the requested name, or strict mode is enabled with
+    /// [RuleSet::set_strict_references] and the rule references an unknown rule. This works like
+    /// [Decision::UnknownRule](crate::Decision::UnknownRule).
+    UnknownRule {
+        /// The name of the first unknown rule that was encountered.
+        rule_name: String,
+    },
+}
+
+impl RuleSet {
+    /// Evaluates the named rule as far as possible without knowing the target object, e.g. before
+    /// loading objects for a list endpoint.
+    ///
+    /// Checks whose result cannot depend on the target object are evaluated with the given
+    /// Request. These are checks on literals and API attributes whose right-hand side does not
+    /// reference target object attributes, and checks whose checker does not inspect the target
+    /// object according to [Checker::depends_on_target](crate::Checker::depends_on_target), like
+    /// `role:` checks. Checks that compare an API attribute with target object attributes are
+    /// rewritten into literal checks, e.g. `user_id:%(user_id)s` becomes `'u-1':%(user_id)s` if
+    /// the token has the user ID `u-1`. Referenced rules in `rule:` checks are inlined, unless the
+    /// default [RuleChecker](crate::RuleChecker) was replaced. All other checks are retained as-is.
+    /// Checks whose [FallibleChecker](crate::FallibleChecker) fails are also retained, so that
+    /// they are tried again for each target object.
+    ///
+    /// Unknown rules are reported like in [RuleSet::decide].
+    ///
+    /// ```
+    /// # use oslo_policy::{PartialEvaluation, Request, RuleSet, Token, AttributeValue};
+    /// # use std::borrow::Cow;
+    /// # struct MyToken;
+    /// # impl Token for MyToken {
+    /// #     fn get_api_attribute(&self, name: &str) -> Option<Cow<'_, AttributeValue>> {
+    /// #         (name == "user_id").then(|| Cow::Owned("u-1".into()))
+    /// #     }
+    /// #     fn has_role(&self, role_name: &str) -> bool { role_name == "member" }
+    /// # }
+    /// # let token = MyToken;
+    /// let mut ruleset = RuleSet::new();
+    /// ruleset.add_rule("owner", "user_id:%(user_id)s")?;
+    /// ruleset.add_rule("server:list", "role:admin or role:member and rule:owner")?;
+    ///
+    /// let req = Request::new(&token); //where the token has role "member" and user ID "u-1"
+    /// let PartialEvaluation::Residual(residual) = ruleset.partially_evaluate("server:list", &req)
+    /// else {
+    ///     unreachable!();
+    /// };
+    /// assert_eq!(residual.to_string(), "'u-1':%(user_id)s");
+    /// # Ok::<(), oslo_policy::ParseError>(())
+    /// ```
+    pub fn partially_evaluate(&self, rule_name: &str, req: &Request) -> PartialEvaluation {
+        if !self.rules.contains_key(rule_name) {
+            return PartialEvaluation::UnknownRule {
+                rule_name: rule_name.to_owned(),
+            };
+        }
+        //forget about unknown rules from previous evaluations of the same Request
+        req.state.take_unknown_rule();
+        let expr = self.partial_rule(rule_name, req);
+        if let Some(rule_name) = req.state.take_unknown_rule() {
+            return PartialEvaluation::UnknownRule { rule_name };
+        }
+        match expr {
+            Expression::Const(true) => PartialEvaluation::Allowed,
+            Expression::Const(false) => PartialEvaluation::Denied,
+            expr => PartialEvaluation::Residual(Rule { expr }),
+        }
+    }
+
+    /// Evaluates a residual rule from [RuleSet::partially_evaluate] for the given Request, which
+    /// should contain the same token as before, and the target object in question.
+    pub fn evaluate_residual(&self, residual: &Rule, req: &Request) -> bool {
+        self.evaluate_expr(req, "", &residual.expr).unwrap_or(false)
+    }
+
+    fn partial_rule(&self, rule_name: &str, req: &Request) -> Expression {
+        let Some(_guard) = req.state.descend(MAX_RULE_DEPTH) else {
+            //fail closed if rules are nested too deeply (most likely because of a cycle)
+            return Expression::Const(false);
+        };
+        match self.rules.get(rule_name) {
+            Some(rule) => self.partial_expr(req, rule_name, &rule.expr),
+            None => {
+                if self.has_strict_references() {
+                    req.state.record_unknown_rule(rule_name);
+                }
+                Expression::Const(false)
+            }
+        }
+    }
+
+    fn partial_expr(&self, req: &Request, rule_name: &str, expr: &Expression) -> Expression {
+        use Expression::*;
+        match expr {
+            Const(val) => Const(*val),
+            Check(lhs, rhs) => self.partial_check(req, rule_name, lhs, rhs),
+            //like in regular evaluation, the right operand is not looked at if the left operand
+            //decides the result already
+            And(x, y) => match self.partial_expr(req, rule_name, x) {
+                Const(false) => Const(false),
+                Const(true) => self.partial_expr(req, rule_name, y),
+                x => match self.partial_expr(req, rule_name, y) {
+                    Const(false) => Const(false),
+                    Const(true) => x,
+                    y => And(Box::new(x), Box::new(y)),
+                },
+            },
+            Or(x, y) => match self.partial_expr(req, rule_name, x) {
+                Const(true) => Const(true),
+                Const(false) => self.partial_expr(req, rule_name, y),
+                x => match self.partial_expr(req, rule_name, y) {
+                    Const(true) => Const(true),
+                    Const(false) => x,
+                    y => Or(Box::new(x), Box::new(y)),
+                },
+            },
+            Not(x) => match self.partial_expr(req, rule_name, x) {
+                Const(val) => Const(!val),
+                x => Not(Box::new(x)),
+            },
+        }
+    }
+
+    fn partial_check(
+        &self,
+        req: &Request,
+        rule_name: &str,
+        lhs: &LeftHandSide,
+        rhs: &str,
+    ) -> Expression {
+        let id = match lhs {
+            LeftHandSide::Identifier(id) => id,
+            //literal checks only depend on the target
+            LeftHandSide::Literal(_) if references_target(rhs) => {
+                return Expression::Check(lhs.clone(), rhs.to_owned());
+            }
+            LeftHandSide::Literal(_) => {
+                return self.partial_constant_check(req, rule_name, lhs, rhs)
+            }
+        };
+        if !references_target(rhs) {
+            if id == "rule" && self.has_default_rule_checker() {
+                return self.partial_rule(rhs, req);
+            }
+            //checkers (including a replaced `rule:` checker) may inspect the target object
+            //directly, so we can only evaluate them here if they promise not to
+            let is_constant = match self.checkers.get(id) {
+                Some(checker) => !checker.depends_on_target(),
+                None => !self.async_checkers.contains_key(id),
+            };
+            return if is_constant {
+                self.partial_constant_check(req, rule_name, lhs, rhs)
+            } else {
+                Expression::Check(lhs.clone(), rhs.to_owned())
+            };
+        }
+
+        //checks that depend on the target can only be rewritten if they compare an API attribute
+        let is_attribute = !is_bool_literal(id)
+            && !self.checkers.contains_key(id)
+            && !self.async_checkers.contains_key(id);
+        if !is_attribute {
+            return Expression::Check(lhs.clone(), rhs.to_owned());
+        }
+        let make_check = |value: &AttributeValue| {
+            let lhs = match value {
+                AttributeValue::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            Expression::Check(LeftHandSide::Literal(lhs), rhs.to_owned())
+        };
+        match lookup_path(id, |k| req.token.get_api_attribute(k)).as_deref() {
+            //If the requested API attribute is missing, the entire check fails.
+            None => Expression::Const(false),
+            //list-valued attributes match if any of their elements match (see
+            //AttributeValue::matches)
+            Some(AttributeValue::List(items)) => (items.iter().map(make_check))
+                .reduce(|x, y| Expression::Or(Box::new(x), Box::new(y)))
+                .unwrap_or(Expression::Const(false)),
+            Some(value) => make_check(value),
+        }
+    }
+
+    /// Evaluates a check that does not depend on the target object.
+    fn partial_constant_check(
+        &self,
+        req: &Request,
+        rule_name: &str,
+        lhs: &LeftHandSide,
+        rhs: &str,
+    ) -> Expression {
+        match self.evaluate_check(req, rule_name, lhs, rhs, None) {
+            Ok(result) => Expression::Const(result),
+            //the result of a failed check is unknown, so it is retained in the residual and
+            //evaluated again for each target object
+            Err(_) => Expression::Check(lhs.clone(), rhs.to_owned()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::attribute::AttributeValue;
+    use crate::checkers::{Checker, FallibleChecker};
+    use crate::request::test::Token;
+    use crate::ruleset::Decision;
+
+    #[test]
+    fn test_partially_evaluate() {
+        let mut ruleset = RuleSet::new();
+        let rules = [
+            ("admin", "role:admin"),
+            ("owner", "user_id:%(user_id)s"),
+            ("admin_or_owner", "rule:admin or rule:owner"),
+            ("member_of_group", "group_ids:%(group_id)s"),
+            (
+                "project_member",
+                "role:member and project_id:%(project_id)s",
+            ),
+            ("public", "'True':%(is_public)s or not role:reader"),
+            ("project_name", "project_name:%(project.name)s"),
+            ("indirect", "rule:%(rule_name)s and role:reader"),
+            ("always", "@ or 'u-1':%(user_id)s"),
+        ];
+        for (name, rule) in rules {
+            ruleset.add_rule(name, rule).unwrap();
+        }
+
+        let api_attrs = [
+            ("user_id", AttributeValue::from("u-1")),
+            ("project_id", AttributeValue::from("p-2")),
+            ("group_ids", AttributeValue::from(vec!["g-1", "g-2"])),
+        ];
+        let token = Token {
+            roles: vec!["reader".into()],
+            api_attrs: api_attrs
+                .into_iter()
+                .map(|(k, v)| (k.to_owned(), v))
+                .collect(),
+        };
+        let req = Request::new(&token);
+
+        let residual = |rule_name| match ruleset.partially_evaluate(rule_name, &req) {
+            PartialEvaluation::Residual(rule) => rule.to_string(),
+            other => panic!("expected residual for {rule_name:?}, but got {other:?}"),
+        };
+        assert_eq!(
+            ruleset.partially_evaluate("admin", &req),
+            PartialEvaluation::Denied
+        );
+        assert_eq!(residual("owner"), "'u-1':%(user_id)s");
+        assert_eq!(residual("admin_or_owner"), "'u-1':%(user_id)s");
+        assert_eq!(
+            residual("member_of_group"),
+            "'g-1':%(group_id)s or 'g-2':%(group_id)s"
+        );
+        assert_eq!(
+            ruleset.partially_evaluate("project_member", &req),
+            PartialEvaluation::Denied
+        );
+        assert_eq!(residual("public"), "'True':%(is_public)s");
+        assert_eq!(
+            ruleset.partially_evaluate("project_name", &req),
+            PartialEvaluation::Denied
+        );
+        assert_eq!(residual("indirect"), "rule:%(rule_name)s");
+        assert_eq!(
+            ruleset.partially_evaluate("always", &req),
+            PartialEvaluation::Allowed
+        );
+        assert_eq!(
+            ruleset.partially_evaluate("unknown", &req),
+            PartialEvaluation::UnknownRule {
+                rule_name: "unknown".into()
+            }
+        );
+
+        //residuals evaluate like the original rule for every target
+        let targets: [&[(&str, &str)]; 4] = [
+            &[
+                ("user_id", "u-1"),
+                ("group_id", "g-3"),
+                ("rule_name", "owner"),
+            ],
+            &[
+                ("user_id", "u-2"),
+                ("group_id", "g-2"),
+                ("rule_name", "admin"),
+            ],
+            &[("user_id", "u-2"), ("is_public", "True")],
+            &[],
+        ];
+        for target in targets {
+            let target: HashMap<String, String> = (target.iter())
+                .map(|&(k, v)| (k.to_owned(), v.to_owned()))
+                .collect();
+            let req = Request::new(&token).with_target(&target);
+            for (rule_name, _) in rules {
+                let expected = ruleset.evaluate(rule_name, &req);
+                let actual = match ruleset.partially_evaluate(rule_name, &req) {
+                    PartialEvaluation::Allowed => true,
+                    PartialEvaluation::Denied => false,
+                    PartialEvaluation::Residual(rule) => ruleset.evaluate_residual(&rule, &req),
+                    other => panic!("unexpected {other:?} for {rule_name:?}"),
+                };
+                assert_eq!(
+                    actual, expected,
+                    "rule {rule_name:?} with target {target:?}"
+                );
+            }
+        }
+    }
+
+    /// A custom checker that always fails.
+    struct FailingChecker;
+
+    impl FallibleChecker for FailingChecker {
+        fn try_check(
+            &self,
+            _ruleset: &RuleSet,
+            _req: &Request,
+            rhs: &str,
+        ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+            Err(format!("cannot check {rhs}").into())
+        }
+
+        fn depends_on_target(&self) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn test_partially_evaluate_with_errors() {
+        let mut ruleset = RuleSet::new();
+        ruleset.add_checker("fail", FailingChecker);
+        let rules = [
+            ("typo", "role:reader and rule:ownr"),
+            ("owner", "user_id:%(user_id)s"),
+            ("broken", "fail:x and rule:owner"),
+        ];
+        for (name, rule) in rules {
+            ruleset.add_rule(name, rule).unwrap();
+        }
+
+        let token = Token {
+            roles: vec!["reader".into()],
+            api_attrs: HashMap::from([("user_id".into(), "u-1".into())]),
+        };
+        let req = Request::new(&token);
+
+        //unknown references are only reported in strict mode
+        assert_eq!(
+            ruleset.partially_evaluate("typo", &req),
+            PartialEvaluation::Denied
+        );
+        ruleset.set_strict_references(true);
+        assert_eq!(
+            ruleset.partially_evaluate("typo", &req),
+            PartialEvaluation::UnknownRule {
+                rule_name: "ownr".into()
+            }
+        );
+        assert_eq!(ruleset.decide("owner", &req), Decision::Denied);
+
+        //failed checks are retained in the residual, and do not affect later decisions
+        assert_eq!(
+            ruleset.partially_evaluate("broken", &req),
+            PartialEvaluation::Residual("fail:x and 'u-1':%(user_id)s".parse().unwrap())
+        );
+        assert_eq!(ruleset.try_decide("owner", &req).unwrap(), Decision::Denied);
+    }
+
+    /// A custom checker that matches if the target object has the attribute named by the
+    /// right-hand side.
+    struct HasAttributeChecker;
+
+    impl Checker for HasAttributeChecker {
+        fn check(&self, _ruleset: &RuleSet, req: &Request, rhs: &str) -> bool {
+            req.target.get_attribute(rhs).is_some()
+        }
+    }
+
+    /// A custom checker for `rule:` checks that allows everything if the target object is public,
+    /// and otherwise evaluates the referenced rule.
+    struct PublicOrRuleChecker;
+
+    impl Checker for PublicOrRuleChecker {
+        fn check(&self, ruleset: &RuleSet, req: &Request, rhs: &str) -> bool {
+            req.target.get_attribute("is_public").is_some() || ruleset.evaluate(rhs, req)
+        }
+    }
+
+    #[test]
+    fn test_partially_evaluate_with_custom_checkers() {
+        let mut ruleset = RuleSet::new();
+        ruleset.add_checker("has", HasAttributeChecker);
+        let rules = [
+            ("admin", "role:admin"),
+            ("shared", "has:shared_with or role:admin"),
+            ("admin_or_shared", "rule:admin or rule:shared"),
+        ];
+        for (name, rule) in rules {
+            ruleset.add_rule(name, rule).unwrap();
+        }
+
+        let token = Token {
+            roles: vec!["reader".into()],
+            api_attrs: HashMap::new(),
+        };
+        let req = Request::new(&token);
+        let target = HashMap::from([("shared_with".to_owned(), "p-1".to_owned())]);
+        let req_with_target = Request::new(&token).with_target(&target);
+
+        //checks of custom checkers are not evaluated without the target object, since the checker
+        //may inspect it
+        let residual: Rule = "has:shared_with".parse().unwrap();
+        for rule_name in ["shared", "admin_or_shared"] {
+            assert_eq!(
+                ruleset.partially_evaluate(rule_name, &req),
+                PartialEvaluation::Residual(residual.clone())
+            );
+        }
+        assert!(ruleset.evaluate_residual(&residual, &req_with_target));
+        assert!(!ruleset.evaluate_residual(&residual, &req));
+
+        //the same goes for `rule:` checks if the default RuleChecker was replaced
+        ruleset.add_checker("rule", PublicOrRuleChecker);
+        let residual: Rule = "rule:admin or rule:shared".parse().unwrap();
+        assert_eq!(
+            ruleset.partially_evaluate("admin_or_shared", &req),
+            PartialEvaluation::Residual(residual.clone())
+        );
+        let target = HashMap::from([("is_public".to_owned(), "True".to_owned())]);
+        assert!(ruleset.evaluate_residual(&residual, &Request::new(&token).with_target(&target)));
+        assert!(!ruleset.evaluate_residual(&residual, &req));
+    }
+}
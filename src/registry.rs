@@ -0,0 +1,596 @@
+/******************************************************************************
+*
+*  Copyright 2023 Stefan Majewsky <majewsky@gmx.net>
+*
+*  Licensed under the Apache License, Version 2.0 (the "License");
+*  you may not use this file except in compliance with the License.
+*  You may obtain a copy of the License at
+*
+*      http://www.apache.org/licenses/LICENSE-2.0
+*
+*  Unless required by applicable law or agreed to in writing, software
+*  distributed under the License is distributed on an "AS IS" BASIS,
+*  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+*  See the License for the specific language governing permissions and
+*  limitations under the License.
+*
+******************************************************************************/
+
+use std::collections::HashMap;
+use std::fmt;
+use thiserror::Error;
+
+use crate::ast::{Expression, Rule};
+use crate::parser::{parse_rule, ParseError};
+use crate::ruleset::RuleSet;
+
+/// The scope of a token, as described by the `scope_types` of a [RuleDefault].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum ScopeType {
+    /// A token that is scoped to the entire deployment.
+    System,
+    /// A token that is scoped to a domain.
+    Domain,
+    /// A token that is scoped to a project.
+    Project,
+}
+
+impl fmt::Display for ScopeType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ScopeType::System => "system",
+            ScopeType::Domain => "domain",
+            ScopeType::Project => "project",
+        })
+    }
+}
+
+/// An API operation that is protected by a [RuleDefault], e.g. `GET /v2.1/servers`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Operation {
+    /// The HTTP method, e.g. `GET`.
+    pub method: String,
+    /// The request path, e.g. `/v2.1/servers/{server_id}`.
+    pub path: String,
+}
+
+/// A rule that is defined in the code of a service, like oslo.policy's `RuleDefault` and
+/// `DocumentedRuleDefault`.
+///
+/// Defaults are collected in a [RuleRegistry] and merged into a [RuleSet] with
+/// [RuleSet::apply_defaults]. Operators can override each default rule in a policy file.
+#[derive(Clone, Debug)]
+pub struct RuleDefault {
+    name: String,
+    check_str: String,
+    rule: Rule,
+    description: Option<String>,
+    operations: Vec<Operation>,
+    scope_types: Vec<ScopeType>,
+    deprecated_rule: Option<DeprecatedRule>,
+}
+
+impl RuleDefault {
+    /// Creates a new default rule. Fails if `check_str` cannot be parsed.
+    pub fn new(name: impl Into<String>, check_str: &str) -> Result<Self, ParseError> {
+        let name = name.into();
+        let rule = parse_rule(Some(&name), check_str)?;
+        Ok(Self {
+            name,
+            check_str: check_str.to_owned(),
+            rule,
+            description: None,
+            operations: Vec::new(),
+            scope_types: Vec::new(),
+            deprecated_rule: None,
+        })
+    }
+
+    /// Adds a description of what this rule protects. This is usually chained directly after
+    /// [RuleDefault::new].
+    pub fn with_description(mut self, description: impl Into<String>) -> Self {
+        self.description = Some(description.into());
+        self
+    }
+
+    /// Adds an API operation that is protected by this rule. This can be chained multiple times.
+    pub fn with_operation(mut self, method: impl Into<String>, path: impl Into<String>) -> Self {
+        self.operations.push(Operation {
+            method: method.into(),
+            path: path.into(),
+        });
+        self
+    }
+
+    /// Sets the scope types that this rule is intended for.
+    pub fn with_scope_types(mut self, scope_types: impl IntoIterator<Item = ScopeType>) -> Self {
+        self.scope_types = scope_types.into_iter().collect();
+        self
+    }
+
+    /// Declares that this rule replaces a deprecated rule, see [DeprecatedRule].
+    pub fn with_deprecated_rule(mut self, deprecated_rule: DeprecatedRule) -> Self {
+        self.deprecated_rule = Some(deprecated_rule);
+        self
+    }
+
+    /// Returns the name of this rule.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the default check string of this rule, as given to [RuleDefault::new].
+    pub fn check_str(&self) -> &str {
+        &self.check_str
+    }
+
+    /// Returns the parsed form of [RuleDefault::check_str].
+    pub fn rule(&self) -> &Rule {
+        &self.rule
+    }
+
+    /// Returns the description of this rule, if any.
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    /// Returns the API operations that are protected by this rule.
+    pub fn operations(&self) -> &[Operation] {
+        &self.operations
+    }
+
+    /// Returns the scope types that this rule is intended for. If empty, the rule is not
+    /// restricted to any scope.
+    pub fn scope_types(&self) -> &[ScopeType] {
+        &self.scope_types
+    }
+
+    /// Returns the deprecated rule that this rule replaces, if any.
+    pub fn deprecated_rule(&self) -> Option<&DeprecatedRule> {
+        self.deprecated_rule.as_ref()
+    }
+}
+
+/// A former version of a [RuleDefault] that was renamed or whose check string was changed, like
+/// oslo.policy's `DeprecatedRule`.
+///
+/// Unless [RuleRegistry::set_enforce_new_defaults] is enabled, [RuleSet::apply_defaults] keeps
+/// such rules working during a transition period: If the operator did not override the new rule,
+/// it is replaced by `new_rule or old_rule`, where `old_rule` is the operator's override of the
+/// deprecated name (if any), or else the deprecated check string.
+#[derive(Clone, Debug)]
+pub struct DeprecatedRule {
+    name: String,
+    check_str: String,
+    rule: Rule,
+    reason: Option<String>,
+    deprecated_since: Option<String>,
+}
+
+impl DeprecatedRule {
+    /// Creates a new deprecated rule. If the rule was not renamed, `name` is the same as the name
+    /// of the [RuleDefault] that replaces it. Fails if `check_str` cannot be parsed.
+    pub fn new(name: impl Into<String>, check_str: &str) -> Result<Self, ParseError> {
+        let name = name.into();
+        let rule = parse_rule(Some(&name), check_str)?;
+        Ok(Self {
+            name,
+            check_str: check_str.to_owned(),
+            rule,
+            reason: None,
+            deprecated_since: None,
+        })
+    }
+
+    /// Adds an explanation why the rule was deprecated. This is usually chained directly after
+    /// [DeprecatedRule::new].
+    pub fn with_reason(mut self, reason: impl Into<String>) -> Self {
+        self.reason = Some(reason.into());
+        self
+    }
+
+    /// Adds the release in which the rule was deprecated, e.g. `"2023.1"`.
+    pub fn with_deprecated_since(mut self, release: impl Into<String>) -> Self {
+        self.deprecated_since = Some(release.into());
+        self
+    }
+
+    /// Returns the name of the deprecated rule.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the check string of the deprecated rule.
+    pub fn check_str(&self) -> &str {
+        &self.check_str
+    }
+
+    /// Returns the parsed form of [DeprecatedRule::check_str].
+    pub fn rule(&self) -> &Rule {
+        &self.rule
+    }
+
+    /// Returns the explanation why the rule was deprecated, if any.
+    pub fn reason(&self) -> Option<&str> {
+        self.reason.as_deref()
+    }
+
+    /// Returns the release in which the rule was deprecated, if known.
+    pub fn deprecated_since(&self) -> Option<&str> {
+        self.deprecated_since.as_deref()
+    }
+}
+
+/// A collection of [RuleDefault] objects, usually filled by a service at startup.
+#[derive(Clone, Debug, Default)]
+pub struct RuleRegistry {
+    defaults: Vec<RuleDefault>,
+    index: HashMap<String, usize>,
+    enforce_new_defaults: bool,
+}
+
+impl RuleRegistry {
+    /// Returns a new empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a default rule. Fails if a default with the same name was already registered.
+    pub fn register(&mut self, default: RuleDefault) -> Result<(), DuplicateDefaultError> {
+        if self.index.contains_key(&default.name) {
+            return Err(DuplicateDefaultError {
+                rule_name: default.name,
+            });
+        }
+        self.index.insert(default.name.clone(), self.defaults.len());
+        self.defaults.push(default);
+        Ok(())
+    }
+
+    /// Returns the default rule with the given name, if any.
+    pub fn get(&self, name: &str) -> Option<&RuleDefault> {
+        self.index.get(name).map(|&idx| &self.defaults[idx])
+    }
+
+    /// Iterates over all default rules in the order in which they were registered.
+    pub fn iter(&self) -> impl Iterator<Item = &RuleDefault> {
+        self.defaults.iter()
+    }
+
+    /// Controls whether deprecated rules are still honored by [RuleSet::apply_defaults]. This is
+    /// equivalent to the `enforce_new_defaults` option of oslo.policy and is disabled by default.
+    /// If enabled, only the new defaults are used, and overrides of deprecated rule names do not
+    /// have any effect on the rules that replace them.
+    pub fn set_enforce_new_defaults(&mut self, enabled: bool) {
+        self.enforce_new_defaults = enabled;
+    }
+
+    /// Returns whether [RuleRegistry::set_enforce_new_defaults] was enabled.
+    pub fn enforces_new_defaults(&self) -> bool {
+        self.enforce_new_defaults
+    }
+}
+
+/// Error type returned by [RuleRegistry::register].
+#[derive(Error, Debug)]
+#[error("a default for rule {rule_name:?} is already registered")]
+pub struct DuplicateDefaultError {
+    rule_name: String,
+}
+
+impl DuplicateDefaultError {
+    /// Returns the name of the rule that was registered twice.
+    pub fn rule_name(&self) -> &str {
+        &self.rule_name
+    }
+}
+
+/// A warning that is reported by [RuleSet::apply_defaults].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RegistryWarning {
+    /// The RuleSet contains a rule named `rule_name` (e.g. from a policy file), but there is no
+    /// registered default with that name. This is most likely a typo in the policy file, or a
+    /// leftover from an older version of the service.
+    UnknownOverride { rule_name: String },
+    /// The RuleSet overrides the rule `rule_name`, which was deprecated in favor of the rule
+    /// `replacement`. The override should be moved to the new name before the deprecated rule is
+    /// removed. Unless new defaults are enforced, the override still applies as a fallback for
+    /// the new rule if the new rule is not overridden.
+    DeprecatedOverride {
+        rule_name: String,
+        replacement: String,
+        reason: Option<String>,
+        deprecated_since: Option<String>,
+    },
+}
+
+impl fmt::Display for RegistryWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistryWarning::UnknownOverride { rule_name } => write!(
+                f,
+                "rule {rule_name:?} is overridden, but there is no registered default with that name"
+            ),
+            RegistryWarning::DeprecatedOverride {
+                rule_name,
+                replacement,
+                reason,
+                deprecated_since,
+            } => {
+                write!(f, "rule {rule_name:?} is overridden, but it was deprecated")?;
+                if let Some(release) = deprecated_since {
+                    write!(f, " in {release}")?;
+                }
+                if rule_name != replacement {
+                    write!(f, " in favor of {replacement:?}")?;
+                }
+                if let Some(reason) = reason {
+                    write!(f, ": {reason}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl RuleSet {
+    /// Merges the default rules from the given registry into this RuleSet. This should be called
+    /// once, after the operator's overrides have been loaded into this RuleSet (e.g. with
+    /// `RuleSet::load_yaml_file`):
+    ///
+    /// - Each default rule is added unless this RuleSet already contains a rule with that name.
+    /// - If a default rule replaces a [DeprecatedRule] and is not overridden, it is combined with
+    ///   the deprecated rule as described there, unless the registry enforces new defaults.
+    /// - The metadata of all default rules is retained and can be queried with
+    ///   [RuleSet::get_default].
+    ///
+    /// Returns a warning for each rule in this RuleSet that does not have a registered default, and
+    /// for each override of a deprecated rule. Warnings are reported in order of rule name.
+    pub fn apply_defaults(&mut self, registry: &RuleRegistry) -> Vec<RegistryWarning> {
+        //the names of deprecated rules are known, but overriding them is discouraged
+        let mut deprecated_names = HashMap::new();
+        for default in registry.iter() {
+            if let Some(deprecated) = &default.deprecated_rule {
+                if deprecated.name != default.name {
+                    deprecated_names.insert(deprecated.name.as_str(), (deprecated, default));
+                }
+            }
+        }
+
+        let mut names: Vec<&str> = self
+            .rules
+            .keys()
+            .filter(|name| registry.get(name).is_none())
+            .map(|name| name.as_str())
+            .collect();
+        names.sort_unstable();
+        let warnings = names
+            .into_iter()
+            .map(|name| match deprecated_names.get(name) {
+                Some((deprecated, default)) => RegistryWarning::DeprecatedOverride {
+                    rule_name: name.to_owned(),
+                    replacement: default.name.clone(),
+                    reason: deprecated.reason.clone(),
+                    deprecated_since: deprecated.deprecated_since.clone(),
+                },
+                None => RegistryWarning::UnknownOverride {
+                    rule_name: name.to_owned(),
+                },
+            })
+            .collect();
+
+        let mut new_rules = Vec::new();
+        for default in registry.iter() {
+            if !self.rules.contains_key(&default.name) {
+                let rule = match &default.deprecated_rule {
+                    Some(deprecated) if !registry.enforce_new_defaults => {
+                        let old_rule = self.rules.get(&deprecated.name).unwrap_or(&deprecated.rule);
+                        if old_rule == &default.rule {
+                            default.rule.clone()
+                        } else {
+                            Rule {
+                                expr: Expression::Or(
+                                    Box::new(default.rule.expr.clone()),
+                                    Box::new(old_rule.expr.clone()),
+                                ),
+                            }
+                        }
+                    }
+                    _ => default.rule.clone(),
+                };
+                new_rules.push((default.name.clone(), rule));
+            }
+            self.defaults.insert(default.name.clone(), default.clone());
+        }
+        self.insert_rules(new_rules);
+        warnings
+    }
+
+    /// Returns the registered default for the rule with the given name, if
+    /// [RuleSet::apply_defaults] was called with a registry containing such a default.
+    pub fn get_default(&self, name: &str) -> Option<&RuleDefault> {
+        self.defaults.get(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example_registry() -> RuleRegistry {
+        let mut registry = RuleRegistry::new();
+        let defaults = [
+            RuleDefault::new("admin_required", "role:admin").unwrap(),
+            RuleDefault::new("compute:get", "rule:admin_required or role:reader")
+                .unwrap()
+                .with_description("Show details for a server.")
+                .with_operation("GET", "/servers/{server_id}")
+                .with_scope_types([ScopeType::Project]),
+            RuleDefault::new("compute:delete", "rule:admin_required")
+                .unwrap()
+                .with_operation("DELETE", "/servers/{server_id}")
+                .with_operation("POST", "/servers/{server_id}/action (forceDelete)"),
+        ];
+        for default in defaults {
+            registry.register(default).unwrap();
+        }
+        registry
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut registry = example_registry();
+        let names: Vec<_> = registry.iter().map(|d| d.name()).collect();
+        assert_eq!(
+            names,
+            vec!["admin_required", "compute:get", "compute:delete"]
+        );
+
+        let default = registry.get("compute:get").unwrap();
+        assert_eq!(default.check_str(), "rule:admin_required or role:reader");
+        assert_eq!(default.description(), Some("Show details for a server."));
+        assert_eq!(default.scope_types(), &[ScopeType::Project]);
+        assert_eq!(
+            registry.get("compute:delete").unwrap().operations().len(),
+            2
+        );
+        assert!(registry.get("compute:create").is_none());
+
+        let err = registry
+            .register(RuleDefault::new("compute:get", "@").unwrap())
+            .unwrap_err();
+        assert_eq!(err.rule_name(), "compute:get");
+        assert_eq!(
+            err.to_string(),
+            r#"a default for rule "compute:get" is already registered"#
+        );
+
+        let err = RuleDefault::new("compute:create", "role:admin or").unwrap_err();
+        assert_eq!(err.rule_name(), Some("compute:create"));
+    }
+
+    #[test]
+    fn test_deprecated_rules() {
+        let mut registry = RuleRegistry::new();
+        let defaults = [
+            //renamed rule
+            RuleDefault::new("compute:server:show", "role:reader")
+                .unwrap()
+                .with_deprecated_rule(
+                    DeprecatedRule::new("compute:get", "role:member")
+                        .unwrap()
+                        .with_reason("Renamed for consistency.")
+                        .with_deprecated_since("2023.1"),
+                ),
+            //tightened rule
+            RuleDefault::new("compute:delete", "role:admin")
+                .unwrap()
+                .with_deprecated_rule(
+                    DeprecatedRule::new("compute:delete", "role:member").unwrap(),
+                ),
+            //renamed rule with unchanged check string
+            RuleDefault::new("compute:server:list", "role:reader")
+                .unwrap()
+                .with_deprecated_rule(DeprecatedRule::new("compute:list", "role:reader").unwrap()),
+        ];
+        for default in defaults {
+            registry.register(default).unwrap();
+        }
+
+        //without overrides, the deprecated check strings are accepted as well
+        let mut ruleset = RuleSet::new();
+        assert_eq!(ruleset.apply_defaults(&registry), vec![]);
+        let rule_str = |rs: &RuleSet, name| rs.get_rule(name).unwrap().to_string();
+        assert_eq!(
+            rule_str(&ruleset, "compute:server:show"),
+            "role:reader or role:member"
+        );
+        assert_eq!(
+            rule_str(&ruleset, "compute:delete"),
+            "role:admin or role:member"
+        );
+        assert_eq!(rule_str(&ruleset, "compute:server:list"), "role:reader");
+
+        //overrides of deprecated names are used as fallbacks and generate warnings
+        let mut ruleset = RuleSet::new();
+        ruleset.add_rule("compute:get", "role:observer").unwrap();
+        ruleset.add_rule("compute:list", "role:observer").unwrap();
+        ruleset.add_rule("compute:delete", "role:owner").unwrap();
+        let warnings = ruleset.apply_defaults(&registry);
+        assert_eq!(
+            warnings,
+            vec![
+                RegistryWarning::DeprecatedOverride {
+                    rule_name: "compute:get".into(),
+                    replacement: "compute:server:show".into(),
+                    reason: Some("Renamed for consistency.".into()),
+                    deprecated_since: Some("2023.1".into()),
+                },
+                RegistryWarning::DeprecatedOverride {
+                    rule_name: "compute:list".into(),
+                    replacement: "compute:server:list".into(),
+                    reason: None,
+                    deprecated_since: None,
+                },
+            ]
+        );
+        assert_eq!(
+            warnings[0].to_string(),
+            r#"rule "compute:get" is overridden, but it was deprecated in 2023.1 in favor of "compute:server:show": Renamed for consistency."#
+        );
+        assert_eq!(
+            rule_str(&ruleset, "compute:server:show"),
+            "role:reader or role:observer"
+        );
+        assert_eq!(
+            rule_str(&ruleset, "compute:server:list"),
+            "role:reader or role:observer"
+        );
+        //an override of the new name always wins
+        assert_eq!(rule_str(&ruleset, "compute:delete"), "role:owner");
+
+        //with enforce_new_defaults, the fallbacks are not used
+        registry.set_enforce_new_defaults(true);
+        let mut ruleset = RuleSet::new();
+        ruleset.add_rule("compute:get", "role:observer").unwrap();
+        assert_eq!(ruleset.apply_defaults(&registry).len(), 1);
+        assert_eq!(rule_str(&ruleset, "compute:server:show"), "role:reader");
+        assert_eq!(rule_str(&ruleset, "compute:delete"), "role:admin");
+    }
+
+    #[test]
+    fn test_apply_defaults() {
+        let registry = example_registry();
+        let mut ruleset = RuleSet::new();
+        ruleset.add_rule("compute:get", "role:member").unwrap();
+        ruleset.add_rule("compute:gte", "role:admin").unwrap();
+
+        let warnings = ruleset.apply_defaults(&registry);
+        assert_eq!(
+            warnings,
+            vec![RegistryWarning::UnknownOverride {
+                rule_name: "compute:gte".into()
+            }]
+        );
+        assert_eq!(
+            warnings[0].to_string(),
+            r#"rule "compute:gte" is overridden, but there is no registered default with that name"#
+        );
+
+        //overrides win over defaults, but metadata is still available
+        let rule_str = |name| ruleset.get_rule(name).unwrap().to_string();
+        assert_eq!(rule_str("compute:get"), "role:member");
+        assert_eq!(rule_str("compute:delete"), "rule:admin_required");
+        assert_eq!(rule_str("admin_required"), "role:admin");
+        let default = ruleset.get_default("compute:get").unwrap();
+        assert_eq!(default.operations()[0].path, "/servers/{server_id}");
+        assert!(ruleset.get_default("compute:gte").is_none());
+
+        //applying the same defaults again does not change anything
+        assert_eq!(ruleset.apply_defaults(&registry), warnings);
+        let rule = ruleset.get_rule("compute:get").unwrap();
+        assert_eq!(rule.to_string(), "role:member");
+    }
+}
@@ -16,14 +16,30 @@
 *
 ******************************************************************************/
 
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 
+use crate::attribute::{AttributeRef, AttributeValue};
+use crate::registry::ScopeType;
+use crate::ruleset::RuleSet;
+use crate::trace::Trace;
+
 /// Attributes belonging to a single request.
+///
+/// Requests can only be constructed with [Request::new]. Besides the token and the target, a
+/// Request carries evaluation state across all evaluations that use it: memoized results (see
+/// [Request::with_memoization]), the traces collected by
+/// [RuleSet::evaluate_explained](crate::RuleSet::evaluate_explained), the current nesting depth of
+/// rule evaluations, and the unknown rule that was last encountered with
+/// [strict references](crate::RuleSet::set_strict_references). Therefore, a Request should only
+/// be used for a single request of the user.
 pub struct Request<'a> {
     /// Attributes associated with a token that was supplied by the user for this request.
     pub token: &'a dyn Token,
     /// Attributes associated with the target object(s) of this request.
     pub target: &'a dyn Target,
+    pub(crate) state: EvaluationState,
 }
 
 impl<'a> Request<'a> {
@@ -36,7 +52,11 @@ impl<'a> Request<'a> {
     ///
     /// API attributes are usually derived from the validated token that was supplied by the user.
     pub fn new(token: &'a dyn Token) -> Self {
-        Request { token, target: &() }
+        Request {
+            token,
+            target: &(),
+            state: EvaluationState::default(),
+        }
     }
 
     /// Add a [Target] to this request. This is usually chained directly after [Request::new].
@@ -44,6 +64,242 @@ impl<'a> Request<'a> {
         self.target = target;
         self
     }
+
+    /// Enables memoization for all evaluations of this request. The result of each rule and
+    /// each check is only computed once, even if it is referenced many times or if several rules
+    /// are evaluated for this request. Checkers can opt out of memoization with
+    /// [Checker::is_deterministic](crate::Checker::is_deterministic).
+    ///
+    /// Since results are memoized for the lifetime of the request, the token and the target must
+    /// not be replaced or change their attributes after the first evaluation. Likewise, a
+    /// [RuleSet](crate::RuleSet) must not be modified while it evaluates this request. The request
+    /// can be evaluated by different rule sets, but results are only reused within the same rule
+    /// set.
+    pub fn with_memoization(self) -> Self {
+        self.state.begin_memoization();
+        self
+    }
+}
+
+/// Internal state of the evaluation engine that needs to be carried along with a [Request].
+///
+/// This lives in the Request because [checkers](crate::Checker) only get to see the [RuleSet] and
+/// the Request, so this is the only way to carry state across a `rule:` check.
+///
+/// [RuleSet]: crate::RuleSet
+#[derive(Default)]
+pub(crate) struct EvaluationState {
+    /// While [RuleSet::evaluate_explained](crate::RuleSet::evaluate_explained) is running, this
+    /// collects the traces of rules that are evaluated by checkers.
+    traces: RefCell<Option<Vec<Trace>>>,
+    /// How many rule evaluations are currently nested within each other.
+    depth: Cell<usize>,
+    /// Whether the maximum nesting depth was exceeded since the outermost rule evaluation
+    /// started. Until it returns, all nested rule evaluations fail immediately, so that a cycle
+    /// with several branches (e.g. `a: rule:a or rule:a`) does not take exponential time.
+    depth_exceeded: Cell<bool>,
+    /// The first unknown rule that was encountered while
+    /// [strict references](crate::RuleSet::set_strict_references) are enabled.
+    unknown_rule: RefCell<Option<String>>,
+    /// If memoization is enabled (either by [Request::with_memoization] or while
+    /// [RuleSet::evaluate_many](crate::RuleSet::evaluate_many) is running), this contains the
+    /// results of rules and checks that were evaluated so far.
+    memo: RefCell<Option<Memo>>,
+    /// How often a result was computed that must not be memoized, see [Self::can_memoize].
+    unmemoizable_results: Cell<usize>,
+}
+
+impl EvaluationState {
+    /// Records that a rule evaluation is starting. Returns None if this would exceed the given
+    /// maximum nesting depth, or if the maximum nesting depth was exceeded before within the same
+    /// outermost rule evaluation. Otherwise, the nesting depth is decreased again when the
+    /// returned guard is dropped.
+    pub(crate) fn descend(&self, max_depth: usize) -> Option<DepthGuard<'_>> {
+        let depth = self.depth.get();
+        if depth == 0 {
+            //forget about unknown rules from previous evaluations of the same Request, so that
+            //they do not prevent memoization in this one
+            self.unknown_rule.replace(None);
+        }
+        if depth >= max_depth || self.depth_exceeded.get() {
+            self.depth_exceeded.set(true);
+            self.record_unmemoizable_result();
+            return None;
+        }
+        self.depth.set(depth + 1);
+        Some(DepthGuard(self))
+    }
+
+    /// Records that evaluation reached a reference to an unknown rule. Only the first such rule is
+    /// retained until the next outermost rule evaluation starts.
+    pub(crate) fn record_unknown_rule(&self, rule_name: &str) {
+        let mut slot = self.unknown_rule.borrow_mut();
+        if slot.is_none() {
+            *slot = Some(rule_name.to_owned());
+        }
+    }
+
+    /// Returns and clears the unknown rule recorded by [Self::record_unknown_rule].
+    pub(crate) fn take_unknown_rule(&self) -> Option<String> {
+        self.unknown_rule.take()
+    }
+
+    /// Enables memoization of rule and check results, unless it is enabled already. The return
+    /// value must be given to [Self::end_memoization] afterwards.
+    pub(crate) fn begin_memoization(&self) -> bool {
+        let mut memo = self.memo.borrow_mut();
+        if memo.is_some() {
+            return false;
+        }
+        *memo = Some(Memo::default());
+        true
+    }
+
+    /// Disables memoization again if it was enabled by the matching [Self::begin_memoization].
+    pub(crate) fn end_memoization(&self, enabled_by_begin: bool) {
+        if enabled_by_begin {
+            self.memo.replace(None);
+        }
+    }
+
+    /// Returns the memoized result of the given rule in the given RuleSet, if any.
+    pub(crate) fn memoized_rule_result(&self, ruleset: &RuleSet, rule_name: &str) -> Option<bool> {
+        let memo = self.memo.borrow();
+        let memo = memo.as_ref().filter(|m| m.ruleset_id == Some(ruleset.id))?;
+        memo.rules.get(rule_name).copied()
+    }
+
+    /// Returns the memoized result of the given check in the given RuleSet, if any.
+    pub(crate) fn memoized_check_result(
+        &self,
+        ruleset: &RuleSet,
+        checker_name: &str,
+        rhs: &str,
+    ) -> Option<bool> {
+        let memo = self.memo.borrow();
+        let memo = memo.as_ref().filter(|m| m.ruleset_id == Some(ruleset.id))?;
+        memo.checks.get(checker_name)?.get(rhs).copied()
+    }
+
+    /// Returns a value that must be given to [Self::memoize_rule_result] or
+    /// [Self::memoize_check_result] after evaluating a rule or check.
+    pub(crate) fn memoization_checkpoint(&self) -> usize {
+        self.unmemoizable_results.get()
+    }
+
+    /// Records that a result was computed that might be different when computed again, e.g. the
+    /// result of a non-deterministic checker. Results of rules that depend on it are not memoized.
+    pub(crate) fn record_unmemoizable_result(&self) {
+        self.unmemoizable_results
+            .set(self.unmemoizable_results.get() + 1);
+    }
+
+    /// Returns whether results can be memoized. Results are not memoized if they might be
+    /// different when evaluated again elsewhere, i.e. if a result was recorded with
+    /// [Self::record_unmemoizable_result] since the given checkpoint (e.g. because the maximum
+    /// nesting depth was exceeded), or if an unknown rule was recorded.
+    fn can_memoize(&self, checkpoint: usize) -> bool {
+        self.unmemoizable_results.get() == checkpoint && self.unknown_rule.borrow().is_none()
+    }
+
+    /// Memoizes the result of the given rule in the given RuleSet if memoization is enabled.
+    pub(crate) fn memoize_rule_result(
+        &self,
+        ruleset: &RuleSet,
+        rule_name: &str,
+        checkpoint: usize,
+        result: bool,
+    ) {
+        if !self.can_memoize(checkpoint) {
+            return;
+        }
+        if let Some(memo) = self.memo.borrow_mut().as_mut() {
+            let memo = memo.bind_to(ruleset);
+            memo.rules.insert(rule_name.to_owned(), result);
+        }
+    }
+
+    /// Memoizes the result of the given check in the given RuleSet if memoization is enabled.
+    pub(crate) fn memoize_check_result(
+        &self,
+        ruleset: &RuleSet,
+        checker_name: &str,
+        rhs: &str,
+        checkpoint: usize,
+        result: bool,
+    ) {
+        if !self.can_memoize(checkpoint) {
+            return;
+        }
+        if let Some(memo) = self.memo.borrow_mut().as_mut() {
+            let results = memo
+                .bind_to(ruleset)
+                .checks
+                .entry(checker_name.to_owned())
+                .or_default();
+            results.insert(rhs.to_owned(), result);
+        }
+    }
+
+    pub(crate) fn is_tracing(&self) -> bool {
+        self.traces.borrow().is_some()
+    }
+
+    /// Enables tracing. The return value must be given to [Self::end_trace] afterwards.
+    pub(crate) fn begin_trace(&self) -> Option<Vec<Trace>> {
+        self.traces.replace(Some(Vec::new()))
+    }
+
+    /// Returns all traces recorded since the matching call to [Self::begin_trace].
+    pub(crate) fn end_trace(&self, outer: Option<Vec<Trace>>) -> Vec<Trace> {
+        self.traces.replace(outer).unwrap_or_default()
+    }
+
+    /// Stores a trace for pickup by [Self::end_trace]. Does nothing if tracing is not enabled.
+    pub(crate) fn record_trace(&self, trace: Trace) {
+        if let Some(traces) = self.traces.borrow_mut().as_mut() {
+            traces.push(trace);
+        }
+    }
+}
+
+/// Memoized results within [EvaluationState].
+#[derive(Default)]
+struct Memo {
+    /// The [RuleSet::id] of the RuleSet that computed the results. Rules with the same name, or
+    /// checkers with the same name, might behave differently in a different RuleSet.
+    ruleset_id: Option<u64>,
+    /// Results of rules by rule name.
+    rules: HashMap<String, bool>,
+    /// Results of checks by checker name and right-hand side (after interpolation).
+    checks: HashMap<String, HashMap<String, bool>>,
+}
+
+impl Memo {
+    /// Discards all results if they were computed by a different RuleSet than the given one.
+    fn bind_to(&mut self, ruleset: &RuleSet) -> &mut Self {
+        if self.ruleset_id != Some(ruleset.id) {
+            *self = Memo {
+                ruleset_id: Some(ruleset.id),
+                ..Memo::default()
+            };
+        }
+        self
+    }
+}
+
+/// Return type of [EvaluationState::descend].
+pub(crate) struct DepthGuard<'s>(&'s EvaluationState);
+
+impl<'s> Drop for DepthGuard<'s> {
+    fn drop(&mut self) {
+        let depth = self.0.depth.get() - 1;
+        self.0.depth.set(depth);
+        //the outermost rule evaluation has returned
+        if depth == 0 {
+            self.0.depth_exceeded.set(false);
+        }
+    }
 }
 
 /// Attributes associated with a token that was supplied by the user as part of a [Request].
@@ -51,32 +307,73 @@ pub trait Token {
     /// Returns the API attribute with the given `name`, if it exists. API attributes can appear on
     /// the left side of a check. For example, `project_name:cloud_admin` checks whether the API
     /// attribute `project_name` exists and has the string value `cloud_admin`.
-    #[allow(clippy::needless_lifetimes)] //false positive
-    fn get_api_attribute<'k>(&self, name: &'k str) -> Option<&str>;
+    ///
+    /// If the left side of a check is a dotted path like `project.domain.id` and there is no API
+    /// attribute with exactly that name, the path is resolved within nested
+    /// [maps](AttributeValue::Map), so implementors do not need to flatten nested attributes.
+    fn get_api_attribute(&self, name: &str) -> Option<Cow<'_, AttributeValue>>;
 
     /// Returns whether this token covers the given role.
     fn has_role(&self, role_name: &str) -> bool;
+
+    /// Returns the scope of this token. If scope enforcement is enabled with
+    /// [RuleSet::set_enforce_scope](crate::RuleSet::set_enforce_scope), this is checked against
+    /// the [scope types](crate::RuleDefault::scope_types) of the rule that is being evaluated.
+    ///
+    /// The default implementation infers the scope from API attributes like oslo.policy does:
+    /// Tokens with a non-empty `system_scope` are system-scoped, tokens with a non-empty
+    /// `domain_id` are domain-scoped, and all other tokens are project-scoped.
+    fn scope(&self) -> ScopeType {
+        let is_set = |name| {
+            self.get_api_attribute(name)
+                .is_some_and(|value| value.is_truthy())
+        };
+        if is_set("system_scope") {
+            ScopeType::System
+        } else if is_set("domain_id") {
+            ScopeType::Domain
+        } else {
+            ScopeType::Project
+        }
+    }
 }
 
+//the test helpers live next to the Token trait that they implement
 #[cfg(test)]
+#[allow(clippy::items_after_test_module)]
 pub(crate) mod test {
+    use std::borrow::Cow;
     use std::collections::HashMap;
 
+    use super::Request;
+    use crate::attribute::AttributeValue;
+    use crate::checkers::Checker;
+    use crate::ruleset::RuleSet;
+
     /// A simple implementor of the Token trait for use in tests.
     pub struct Token {
         pub roles: Vec<String>,
-        pub api_attrs: HashMap<String, String>,
+        pub api_attrs: HashMap<String, AttributeValue>,
     }
 
     impl super::Token for Token {
-        #[allow(clippy::needless_lifetimes)] //false positive
-        fn get_api_attribute<'k>(&self, name: &'k str) -> Option<&str> {
-            self.api_attrs.get(name).map(|x| &**x)
+        fn get_api_attribute(&self, name: &str) -> Option<Cow<'_, AttributeValue>> {
+            self.api_attrs.get(name).map(Cow::Borrowed)
         }
         fn has_role(&self, role_name: &str) -> bool {
             self.roles.iter().any(|n| n == role_name)
         }
     }
+
+    /// A custom checker for `rule:` checks that allows everything. Used to test that evaluation
+    /// respects a replaced [RuleChecker](crate::RuleChecker).
+    pub struct PermissiveRuleChecker;
+
+    impl Checker for PermissiveRuleChecker {
+        fn check(&self, _ruleset: &RuleSet, _req: &Request, _rhs: &str) -> bool {
+            true
+        }
+    }
 }
 
 /// Attributes associated with the target object of a [Request].
@@ -84,44 +381,34 @@ pub(crate) mod test {
 /// This covers attributes that were supplied by the user within the request payload, specifically
 /// IDs or names appearing within the request path or the request body.
 ///
-/// This crate defines two basic implementations: A `HashMap<String, String>` can be used if the
-/// caller does not want to define a specific trait implementor type. If a request does not have
+/// This crate defines basic implementations for `HashMap<String, AttributeValue>` and
+/// `HashMap<String, String>`, which can be used if the caller does not want to define a specific
+/// trait implementor type. Both lend their values without copying them. If a request does not have
 /// any target attributes, the unit value `()` can be used as a Target to avoid allocating an empty
 /// HashMap.
 pub trait Target {
     /// Returns the target object attribute with the given `name`, if it exists.
-    #[allow(clippy::needless_lifetimes)] //false positive
-    fn get_attribute<'n>(&self, name: &'n str) -> Option<&str>;
+    ///
+    /// Like for [Token::get_api_attribute], dotted paths are resolved within nested
+    /// [maps](AttributeValue::Map) if there is no attribute with exactly that name.
+    fn get_attribute(&self, name: &str) -> Option<AttributeRef<'_>>;
 }
 
 impl Target for () {
     /// Always returns `None`, since this target does not have any attributes.
-    #[allow(clippy::needless_lifetimes)] //false positive
-    fn get_attribute<'n>(&self, _name: &'n str) -> Option<&str> {
+    fn get_attribute(&self, _name: &str) -> Option<AttributeRef<'_>> {
         None
     }
 }
 
 impl Target for HashMap<String, String> {
-    #[allow(clippy::needless_lifetimes)] //false positive
-    fn get_attribute<'n>(&self, name: &'n str) -> Option<&str> {
-        self.get(name).map(|s| s.as_ref())
+    fn get_attribute(&self, name: &str) -> Option<AttributeRef<'_>> {
+        self.get(name).map(|s| AttributeRef::Str(s))
     }
 }
 
-/// Resolves references to target object attributes in the `%(foo)s` syntax on the right-hand side
-/// of a check.
-pub(crate) fn resolve_target_attr_refs<'r, 'i: 'r, 't: 'r>(
-    input: &'i str,
-    target: &'t dyn Target,
-) -> Option<&'r str> {
-    //We currently only support exactly one %(foo)s interpolation that spans the entire string.
-    //Otherwise we return the input unchanged.
-    let Some(stripped) = input.strip_prefix("%(") else {
-        return Some(input);
-    };
-    let Some(attr_name) = stripped.strip_suffix(")s") else {
-        return Some(input);
-    };
-    target.get_attribute(attr_name)
+impl Target for HashMap<String, AttributeValue> {
+    fn get_attribute(&self, name: &str) -> Option<AttributeRef<'_>> {
+        self.get(name).map(AttributeRef::from)
+    }
 }
@@ -16,18 +16,56 @@
 *
 ******************************************************************************/
 
-use std::collections::HashMap;
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use thiserror::Error;
 
-use crate::ast::{Expression, LeftHandSide};
+use crate::ast::{Expression, LeftHandSide, Rule};
+use crate::attribute::lookup_path;
 use crate::checkers::*;
-use crate::parser::{parse_expression, InternalParseError};
-use crate::request::{resolve_target_attr_refs, Request};
+use crate::interpolation::{resolve_target_attr_refs, InterpolationError};
+use crate::loader::SourceLocation;
+use crate::parser::{parse_rule, ParseError, ParseErrors};
+use crate::registry::RuleDefault;
+use crate::request::Request;
+use crate::trace::{CheckMethod, Trace};
+
+/// How many rules can be nested within each other during evaluation, e.g. through `rule:` checks.
+/// This limit prevents reference cycles from overflowing the stack.
+pub(crate) const MAX_RULE_DEPTH: usize = 64;
+
+/// Source for [RuleSet::id].
+static NEXT_RULESET_ID: AtomicU64 = AtomicU64::new(0);
 
 /// A container and evaluation engine for policy rules.
 pub struct RuleSet {
-    rules: HashMap<String, Expression>,
-    checkers: HashMap<String, Box<dyn Checker>>,
+    /// Uniquely identifies this RuleSet, so that results that were memoized in a [Request] by a
+    /// different RuleSet are not used here.
+    pub(crate) id: u64,
+    pub(crate) rules: HashMap<String, Rule>,
+    pub(crate) checkers: HashMap<String, Arc<dyn FallibleChecker>>,
+    pub(crate) async_checkers: HashMap<String, Box<dyn AsyncChecker>>,
+    /// For rules that were loaded from a policy file, where they were defined.
+    pub(crate) sources: HashMap<String, SourceLocation>,
+    /// Metadata for rules that have a default, see [RuleSet::apply_defaults].
+    pub(crate) defaults: HashMap<String, RuleDefault>,
+    /// Whether [RuleSet::decide] checks the token scope, see [RuleSet::set_enforce_scope].
+    enforce_scope: bool,
+    /// Whether [RuleSet::decide] reports unknown rules in `rule:` checks, see
+    /// [RuleSet::set_strict_references].
+    strict_references: bool,
+    /// [RuleSet::set_simplify_rules].
+    simplify_rules: bool,
+    /// The rules that were simplified because of [RuleSet::set_simplify_rules], as they were
+    /// before simplification. They are simplified again when checkers or rules are replaced,
+    /// since that can change which checks are deterministic.
+    unsimplified_rules: HashMap<String, Rule>,
+    /// Whether the checker for `rule:` checks is the default [RuleChecker], see
+    /// [RuleSet::has_default_rule_checker].
+    default_rule_checker: bool,
 }
 
 impl Default for RuleSet {
@@ -41,103 +79,826 @@ impl RuleSet {
     /// automatically.
     pub fn new() -> Self {
         let mut rs = Self {
+            id: NEXT_RULESET_ID.fetch_add(1, Ordering::Relaxed),
             rules: HashMap::new(),
             checkers: HashMap::new(),
+            async_checkers: HashMap::new(),
+            sources: HashMap::new(),
+            defaults: HashMap::new(),
+            enforce_scope: false,
+            strict_references: false,
+            simplify_rules: false,
+            unsimplified_rules: HashMap::new(),
+            default_rule_checker: true,
         };
         rs.add_checker("rule", RuleChecker);
         rs.add_checker("role", RoleChecker);
         rs
     }
 
-    /// Adds a custom checker to this RuleSet.
-    pub fn add_checker(&mut self, name: impl Into<String>, check: impl Checker) {
-        self.checkers.insert(name.into(), Box::new(check));
+    /// Adds a custom checker to this RuleSet. This accepts both [Checker] and [FallibleChecker].
+    pub fn add_checker(&mut self, name: impl Into<String>, check: impl FallibleChecker) {
+        let name = name.into();
+        if name == "rule" {
+            self.default_rule_checker = (&check as &dyn Any).is::<RuleChecker>();
+        }
+        self.async_checkers.remove(&name);
+        self.checkers.insert(name, Arc::new(check));
+        self.resimplify_rules();
+    }
+
+    /// Adds a custom [AsyncChecker] to this RuleSet. It replaces any synchronous checker with the
+    /// same name.
+    pub fn add_async_checker(&mut self, name: impl Into<String>, check: impl AsyncChecker) {
+        let name = name.into();
+        if name == "rule" {
+            self.default_rule_checker = false;
+        }
+        self.checkers.remove(&name);
+        self.async_checkers.insert(name, Box::new(check));
+        self.resimplify_rules();
+    }
+
+    /// Returns whether `rule:` checks are evaluated by the default [RuleChecker]. If so, evaluation
+    /// strategies other than [RuleSet::evaluate] can evaluate the referenced rule directly instead
+    /// of calling the checker. Otherwise, they must call the checker that replaced it.
+    pub(crate) fn has_default_rule_checker(&self) -> bool {
+        self.default_rule_checker
     }
 
     /// Parses a single rule and adds it to this RuleSet.
     pub fn add_rule(&mut self, name: impl Into<String>, expr: &str) -> Result<(), ParseError> {
         let name = name.into();
-        match parse_expression(expr) {
-            Ok(expr) => {
-                self.rules.insert(name, expr);
-                Ok(())
-            }
-            Err(err) => Err(ParseError {
-                rule_name: name,
-                error: err,
-            }),
-        }
+        let rule = parse_rule(Some(&name), expr)?;
+        self.sources.remove(&name);
+        self.insert_rules([(name, rule)]);
+        Ok(())
+    }
+
+    /// Adds a rule that was already parsed to this RuleSet. This is useful when the same rule
+    /// shall be added to multiple RuleSets.
+    pub fn add_parsed_rule(&mut self, name: impl Into<String>, rule: Rule) {
+        let name = name.into();
+        self.sources.remove(&name);
+        self.insert_rules([(name, rule)]);
+    }
+
+    /// Returns the rule with the given name, if any.
+    pub fn get_rule(&self, name: &str) -> Option<&Rule> {
+        self.rules.get(name)
+    }
+
+    /// Returns where the rule with the given name was defined, if it was loaded from a policy file
+    /// (e.g. by `RuleSet::load_yaml_file` or `RuleSet::load_yaml_dir`). If a rule was defined in
+    /// multiple files, the location of the definition that is in effect is returned.
+    pub fn rule_source(&self, name: &str) -> Option<&SourceLocation> {
+        self.sources.get(name)
+    }
+
+    /// Iterates over all rules in this RuleSet, in no particular order.
+    pub fn rules(&self) -> impl Iterator<Item = (&str, &Rule)> {
+        self.rules.iter().map(|(name, rule)| (name.as_str(), rule))
     }
 
     /// Parses multiple rules and adds them to this RuleSet.
-    pub fn add_rules(&mut self, rules: HashMap<String, String>) -> Result<(), ParseError> {
+    ///
+    /// If any of the rules cannot be parsed, none of the rules are added, and the returned error
+    /// contains all parse errors that were encountered.
+    pub fn add_rules(
+        &mut self,
+        rules: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<(), ParseErrors> {
+        let mut parsed = Vec::new();
+        let mut errors = Vec::new();
         for (name, rule_str) in rules {
-            self.add_rule(name, &rule_str)?;
+            match parse_rule(Some(&name), &rule_str) {
+                Ok(rule) => parsed.push((name, rule)),
+                Err(err) => errors.push(err),
+            }
+        }
+
+        if errors.is_empty() {
+            for (name, _) in &parsed {
+                self.sources.remove(name);
+            }
+            self.insert_rules(parsed);
+            Ok(())
+        } else {
+            //report errors in a deterministic order even if `rules` is a HashMap
+            errors.sort_by(|a, b| a.rule_name().cmp(&b.rule_name()));
+            Err(ParseErrors(errors))
+        }
+    }
+
+    /// Checks that the rules in this RuleSet do not reference each other in a cycle through
+    /// `rule:` checks (e.g. `a: rule:b` and `b: rule:a`). Evaluating a rule that is part of such
+    /// a cycle always yields false once the maximum nesting depth is exceeded.
+    ///
+    /// Since rules may be added in any order, this check is not performed by [RuleSet::add_rule]
+    /// and [RuleSet::add_rules]. It should be called once after all rules have been added.
+    /// References to rules that are computed at runtime (e.g. `rule:%(name)s`) or that go through
+    /// custom checkers cannot be checked.
+    pub fn check_cycles(&self) -> Result<(), CycleError> {
+        //depth-first search over the graph of `rule:` references (we go through the rules in a
+        //sorted order to report the same cycle every time)
+        let mut names: Vec<&str> = self.rules.keys().map(|n| n.as_str()).collect();
+        names.sort_unstable();
+        let mut path = Vec::new();
+        let mut finished = HashSet::new();
+        for name in names {
+            self.find_cycle_from(name, &mut path, &mut finished)?;
         }
         Ok(())
     }
 
+    fn find_cycle_from<'a>(
+        &'a self,
+        rule_name: &'a str,
+        path: &mut Vec<&'a str>,
+        finished: &mut HashSet<&'a str>,
+    ) -> Result<(), CycleError> {
+        if finished.contains(rule_name) {
+            return Ok(());
+        }
+        if let Some(idx) = path.iter().position(|&n| n == rule_name) {
+            let mut rule_names: Vec<String> = path[idx..].iter().map(|&n| n.to_owned()).collect();
+            rule_names.push(rule_name.to_owned());
+            return Err(CycleError { rule_names });
+        }
+        let Some(rule) = self.rules.get(rule_name) else {
+            return Ok(());
+        };
+
+        let mut references = Vec::new();
+        rule.expr.visit_checks(&mut |lhs, rhs| {
+            if lhs == &LeftHandSide::Identifier("rule".into()) && !rhs.contains('%') {
+                references.push(rhs);
+            }
+        });
+        path.push(rule_name);
+        for reference in references {
+            self.find_cycle_from(reference, path, finished)?;
+        }
+        path.pop();
+        finished.insert(rule_name);
+        Ok(())
+    }
+
+    /// Controls whether [RuleSet::decide] checks the [scope](crate::Token::scope) of the token
+    /// against the [scope types](RuleDefault::scope_types) of the rule. This is equivalent to the
+    /// `enforce_scope` option of oslo.policy and is disabled by default.
+    pub fn set_enforce_scope(&mut self, enabled: bool) {
+        self.enforce_scope = enabled;
+    }
+
+    /// Returns whether [RuleSet::set_enforce_scope] was enabled.
+    pub fn enforces_scope(&self) -> bool {
+        self.enforce_scope
+    }
+
+    /// Controls whether [RuleSet::decide] reports references to unknown rules. By default, a check
+    /// like `rule:foo` is false if there is no rule named `foo`, like in oslo.policy. If strict
+    /// mode is enabled, reaching such a check during evaluation makes [RuleSet::decide] return
+    /// [Decision::UnknownRule] instead. Checks that are skipped because of short-circuiting (e.g.
+    /// `rule:foo` in `@ or rule:foo`) are not reported.
+    ///
+    /// [RuleSet::validate] can be used to find all references to unknown rules in advance.
+    pub fn set_strict_references(&mut self, enabled: bool) {
+        self.strict_references = enabled;
+    }
+
+    /// Returns whether [RuleSet::set_strict_references] was enabled.
+    pub fn has_strict_references(&self) -> bool {
+        self.strict_references
+    }
+
+    /// Controls whether rules are simplified with [Rule::simplify] when they are added to this
+    /// RuleSet, either directly or by loading a policy file or by [RuleSet::apply_defaults]. Rules
+    /// that were added before enabling this are not changed. This is disabled by default.
+    ///
+    /// Duplicate checks are only removed if their checker is
+    /// [deterministic](Checker::is_deterministic). For `rule:` checks, this means that the
+    /// referenced rule must exist and only contain deterministic checks. When checkers are added
+    /// or rules are replaced later on, the affected rules are simplified again.
+    pub fn set_simplify_rules(&mut self, enabled: bool) {
+        self.simplify_rules = enabled;
+    }
+
+    /// Returns whether [RuleSet::set_simplify_rules] was enabled.
+    pub fn simplifies_rules(&self) -> bool {
+        self.simplify_rules
+    }
+
+    /// Adds rules to this RuleSet, replacing existing rules with the same name. If
+    /// [RuleSet::set_simplify_rules] is enabled, the new rules are simplified once all of them
+    /// were added, so that the result does not depend on the order of the rules.
+    pub(crate) fn insert_rules(&mut self, rules: impl IntoIterator<Item = (String, Rule)>) {
+        let mut added = Vec::new();
+        let mut replaced = false;
+        for (name, rule) in rules {
+            replaced = replaced || self.rules.contains_key(&name);
+            if self.simplify_rules {
+                self.unsimplified_rules.insert(name.clone(), rule.clone());
+                added.push(name.clone());
+            } else {
+                self.unsimplified_rules.remove(&name);
+            }
+            self.rules.insert(name, rule);
+        }
+        if replaced {
+            //rules that reference a replaced rule might have to be simplified differently
+            self.resimplify_rules();
+        } else {
+            self.simplify_rules_named(&added);
+        }
+    }
+
+    /// Simplifies all rules that were simplified because of [RuleSet::set_simplify_rules] again,
+    /// starting from their original form.
+    fn resimplify_rules(&mut self) {
+        if self.unsimplified_rules.is_empty() {
+            return;
+        }
+        let names: Vec<String> = self.unsimplified_rules.keys().cloned().collect();
+        (self.rules).extend(self.unsimplified_rules.clone());
+        self.simplify_rules_named(&names);
+    }
+
+    fn simplify_rules_named(&mut self, names: &[String]) {
+        let cache = RefCell::new(HashMap::new());
+        let is_deterministic =
+            |lhs: &LeftHandSide, rhs: &str| self.is_deterministic_check(lhs, rhs, &cache);
+        let simplified: Vec<(String, Rule)> = (names.iter())
+            .filter_map(|name| {
+                let expr = self.rules.get(name)?.expr.clone();
+                let expr = expr.simplify_with(&is_deterministic);
+                Some((name.clone(), Rule { expr }))
+            })
+            .collect();
+        self.rules.extend(simplified);
+    }
+
+    /// Returns whether the given check yields the same result whenever it is evaluated for the
+    /// same Request, see [Checker::is_deterministic]. `cache` holds the results for rules that
+    /// were referenced in `rule:` checks, or None while a rule is still being inspected.
+    fn is_deterministic_check(
+        &self,
+        lhs: &LeftHandSide,
+        rhs: &str,
+        cache: &RefCell<HashMap<String, Option<bool>>>,
+    ) -> bool {
+        let LeftHandSide::Identifier(name) = lhs else {
+            return true;
+        };
+        if name == "rule" && self.has_default_rule_checker() {
+            return self.is_deterministic_rule(rhs, cache);
+        }
+        match self.checkers.get(name) {
+            Some(checker) => checker.is_deterministic(),
+            None => (self.async_checkers.get(name)).is_none_or(|c| c.is_deterministic()),
+        }
+    }
+
+    /// Like [RuleSet::is_deterministic_check], but for the rule referenced by a `rule:` check.
+    fn is_deterministic_rule(
+        &self,
+        rule_name: &str,
+        cache: &RefCell<HashMap<String, Option<bool>>>,
+    ) -> bool {
+        //if the rule name is interpolated from the target object, we cannot know which rule it is
+        if rule_name.contains('%') {
+            return false;
+        }
+        if let Some(result) = cache.borrow().get(rule_name) {
+            //a rule that is still being inspected references itself in a cycle, so evaluation
+            //will hit MAX_RULE_DEPTH
+            return result.unwrap_or(false);
+        }
+        //unknown rules are not deterministic because they could be added later
+        let Some(rule) = self.rules.get(rule_name) else {
+            return false;
+        };
+        cache.borrow_mut().insert(rule_name.to_owned(), None);
+        let mut result = true;
+        rule.expr.visit_checks(&mut |lhs, rhs| {
+            result = result && self.is_deterministic_check(lhs, rhs, cache);
+        });
+        cache
+            .borrow_mut()
+            .insert(rule_name.to_owned(), Some(result));
+        result
+    }
+
+    /// Decides whether the given Request is allowed by the named rule.
+    ///
+    /// Unlike [RuleSet::evaluate], this reports [Decision::UnknownRule] if there is no rule with
+    /// the given name, or if strict mode is enabled with [RuleSet::set_strict_references] and the
+    /// rule references an unknown rule.
+    ///
+    /// If scope enforcement is enabled with [RuleSet::set_enforce_scope] and the rule has a
+    /// [default](RuleSet::get_default) with a non-empty list of scope types that does not contain
+    /// the [scope of the token](crate::Token::scope), the rule is not evaluated at all and
+    /// [Decision::InvalidScope] is returned. Otherwise, the rule is evaluated like in
+    /// [RuleSet::evaluate].
+    ///
+    /// If the result depends on a check that failed because of an error in a [FallibleChecker],
+    /// the request is denied. Use [RuleSet::try_decide] to report such errors instead.
+    pub fn decide(&self, rule_name: &str, req: &Request) -> Decision {
+        let (decision, _) = self.decide_impl(rule_name, req);
+        decision
+    }
+
+    /// Like [RuleSet::decide], but returns an error if the result depends on a check whose
+    /// [FallibleChecker] failed (see [RuleSet::try_evaluate]).
+    pub fn try_decide(&self, rule_name: &str, req: &Request) -> Result<Decision, CheckError> {
+        match self.decide_impl(rule_name, req) {
+            (_, Some(err)) => Err(err),
+            (decision, None) => Ok(decision),
+        }
+    }
+
+    fn decide_impl(&self, rule_name: &str, req: &Request) -> (Decision, Option<CheckError>) {
+        if let Some(decision) = self.decide_without_evaluation(rule_name, req) {
+            return (decision, None);
+        }
+        let outcome = self.try_evaluate(rule_name, req);
+        self.finish_decision(req, outcome)
+    }
+
+    /// The part of [RuleSet::decide] that happens before evaluation. Returns a decision if the
+    /// rule does not need to be evaluated.
+    pub(crate) fn decide_without_evaluation(
+        &self,
+        rule_name: &str,
+        req: &Request,
+    ) -> Option<Decision> {
+        if !self.rules.contains_key(rule_name) {
+            return Some(Decision::UnknownRule {
+                rule_name: rule_name.to_owned(),
+            });
+        }
+        if self.enforce_scope {
+            if let Some(default) = self.defaults.get(rule_name) {
+                let scope_types = default.scope_types();
+                if !scope_types.is_empty() && !scope_types.contains(&req.token.scope()) {
+                    return Some(Decision::InvalidScope);
+                }
+            }
+        }
+
+        //forget about unknown rules from previous evaluations of the same Request
+        req.state.take_unknown_rule();
+        None
+    }
+
+    /// The part of [RuleSet::decide] that happens after evaluation.
+    pub(crate) fn finish_decision(
+        &self,
+        req: &Request,
+        outcome: Outcome,
+    ) -> (Decision, Option<CheckError>) {
+        if let Some(rule_name) = req.state.take_unknown_rule() {
+            return (Decision::UnknownRule { rule_name }, None);
+        }
+        match outcome {
+            Ok(true) => (Decision::Allowed, None),
+            Ok(false) => (Decision::Denied, None),
+            //fail closed if the result is unknown
+            Err(err) => (Decision::Denied, Some(err)),
+        }
+    }
+
     /// Evaluates the named rule for the given Request. If no rule with the given name exists,
-    /// false is returned.
+    /// false is returned. If the result depends on a check whose [FallibleChecker] failed, false
+    /// is returned as well.
+    ///
+    /// Scope types are not considered here, since this is also used for evaluating rules that are
+    /// referenced by `rule:` checks. Use [RuleSet::decide] to enforce scope types.
     pub fn evaluate(&self, rule_name: &str, req: &Request) -> bool {
-        //TODO: add trace logging for rules
-        match self.rules.get(rule_name) {
-            Some(expr) => self.evaluate_expr(req, expr),
-            None => false,
+        self.try_evaluate(rule_name, req).unwrap_or(false)
+    }
+
+    /// Like [RuleSet::evaluate], but returns an error if the result depends on a check whose
+    /// [FallibleChecker] failed.
+    ///
+    /// The result of a failed check is unknown: It could have been true or false. Therefore, an
+    /// error is only returned if the result would be different depending on the result of the
+    /// failed check. For example, `quota:compute or role:admin` is true for admins even if the
+    /// quota check fails, but `not quota:compute` is neither true nor false if the quota check
+    /// fails. If multiple failed checks affect the result, the error of the first one is returned.
+    pub fn try_evaluate(&self, rule_name: &str, req: &Request) -> Result<bool, CheckError> {
+        if req.state.is_tracing() {
+            //we are inside evaluate_explained(), most likely because of a `rule:` check, so the
+            //caller wants to see a trace for this evaluation
+            let (trace, outcome) = self.explain_rule(rule_name, req);
+            req.state.record_trace(trace);
+            return outcome;
+        }
+
+        if let Some(result) = req.state.memoized_rule_result(self, rule_name) {
+            return Ok(result);
+        }
+        let Some(_guard) = req.state.descend(MAX_RULE_DEPTH) else {
+            //fail closed if rules are nested too deeply (most likely because of a cycle)
+            return Ok(false);
+        };
+        let checkpoint = req.state.memoization_checkpoint();
+        let outcome = match self.rules.get(rule_name) {
+            Some(rule) => self.evaluate_expr(req, rule_name, &rule.expr),
+            None => {
+                if self.strict_references {
+                    req.state.record_unknown_rule(rule_name);
+                }
+                Ok(false)
+            }
+        };
+        //unknown results are not memoized, so that the error is reported again next time
+        if let Ok(result) = outcome {
+            req.state
+                .memoize_rule_result(self, rule_name, checkpoint, result);
         }
+        outcome
+    }
+
+    /// Decides whether the given Request is allowed by each of the named rules, like
+    /// [RuleSet::decide]. This is useful for listing which actions the user may perform.
+    ///
+    /// Within one call, the result of each rule and each check is only computed once, even if the
+    /// rule is referenced by `rule:` checks in many of the requested rules. This works like
+    /// [Request::with_memoization](crate::Request::with_memoization), but only for the duration of
+    /// this call.
+    pub fn evaluate_many<'n>(
+        &self,
+        rule_names: impl IntoIterator<Item = &'n str>,
+        req: &Request,
+    ) -> BTreeMap<String, Decision> {
+        let enabled = req.state.begin_memoization();
+        let decisions = (rule_names.into_iter())
+            .map(|rule_name| (rule_name.to_owned(), self.decide(rule_name, req)))
+            .collect();
+        req.state.end_memoization(enabled);
+        decisions
+    }
+
+    /// Like [RuleSet::evaluate_many], but decides all rules whose name starts with the given
+    /// prefix, e.g. `compute:`. With an empty prefix, all rules are decided.
+    pub fn evaluate_all(&self, prefix: &str, req: &Request) -> BTreeMap<String, Decision> {
+        let rule_names = (self.rules.keys())
+            .filter(|name| name.starts_with(prefix))
+            .map(|name| name.as_str());
+        self.evaluate_many(rule_names, req)
+    }
+
+    /// Evaluates the named rule for the given Request like [RuleSet::evaluate], but returns a
+    /// [Trace] of the entire evaluation instead of just the result. The trace covers every
+    /// subexpression that was evaluated or skipped, including rules that were evaluated because
+    /// of `rule:` checks.
+    ///
+    /// This is considerably slower than [RuleSet::evaluate], so it should only be used when
+    /// debugging a policy decision.
+    pub fn evaluate_explained(&self, rule_name: &str, req: &Request) -> Trace {
+        let outer = req.state.begin_trace();
+        let (trace, _) = self.explain_rule(rule_name, req);
+        req.state.end_trace(outer);
+        trace
     }
 
-    fn evaluate_expr(&self, req: &Request, expr: &Expression) -> bool {
+    pub(crate) fn evaluate_expr(
+        &self,
+        req: &Request,
+        rule_name: &str,
+        expr: &Expression,
+    ) -> Outcome {
         use Expression::*;
         match expr {
-            Const(val) => *val,
-            Check(lhs, rhs) => self.evaluate_check(req, lhs, rhs),
-            And(x, y) => self.evaluate_expr(req, x) && self.evaluate_expr(req, y),
-            Or(x, y) => self.evaluate_expr(req, x) || self.evaluate_expr(req, y),
-            Not(x) => !self.evaluate_expr(req, x),
+            Const(val) => Ok(*val),
+            Check(lhs, rhs) => self.evaluate_check(req, rule_name, lhs, rhs, None),
+            And(x, y) => and_outcome(self.evaluate_expr(req, rule_name, x), || {
+                self.evaluate_expr(req, rule_name, y)
+            }),
+            Or(x, y) => or_outcome(self.evaluate_expr(req, rule_name, x), || {
+                self.evaluate_expr(req, rule_name, y)
+            }),
+            Not(x) => self.evaluate_expr(req, rule_name, x).map(|val| !val),
         }
     }
 
-    //TODO: add trace logging for checks
-    fn evaluate_check(&self, req: &Request, lhs: &LeftHandSide, rhs: &str) -> bool {
+    fn explain_rule(&self, rule_name: &str, req: &Request) -> (Trace, Outcome) {
+        let Some(_guard) = req.state.descend(MAX_RULE_DEPTH) else {
+            let trace = Trace::DepthExceeded {
+                name: rule_name.to_owned(),
+            };
+            return (trace, Ok(false));
+        };
+        let (body, outcome) = match self.rules.get(rule_name) {
+            Some(rule) => {
+                let (body, outcome) = self.explain_expr(req, rule_name, &rule.expr);
+                (Some(Box::new(body)), outcome)
+            }
+            None => (None, Ok(false)),
+        };
+        let trace = Trace::Rule {
+            name: rule_name.to_owned(),
+            body,
+            result: outcome.as_ref().ok().copied(),
+        };
+        (trace, outcome)
+    }
+
+    /// Like [Self::evaluate_expr], but also produces a [Trace].
+    fn explain_expr(&self, req: &Request, rule_name: &str, expr: &Expression) -> (Trace, Outcome) {
+        use Expression::*;
+        match expr {
+            Const(value) => (Trace::Const { value: *value }, Ok(*value)),
+            Check(lhs, rhs) => {
+                let mut details = CheckDetails::default();
+                let outer = req.state.begin_trace();
+                let outcome = self.evaluate_check(req, rule_name, lhs, rhs, Some(&mut details));
+                let nested = req.state.end_trace(outer);
+                let trace = Trace::Check {
+                    check: expr.to_string(),
+                    rhs: details
+                        .rhs
+                        .expect("evaluate_check() did not fill in the RHS"),
+                    method: details.method,
+                    result: outcome.as_ref().ok().copied(),
+                    nested,
+                };
+                (trace, outcome)
+            }
+            And(x, y) | Or(x, y) => {
+                let is_and = matches!(expr, And(_, _));
+                let (lhs, lhs_outcome) = self.explain_expr(req, rule_name, x);
+                //the right operand is skipped if the left operand decides the result already
+                let (rhs, outcome) = match lhs_outcome {
+                    Ok(value) if value != is_and => {
+                        let rhs = Trace::Skipped {
+                            expr: y.to_string(),
+                        };
+                        (rhs, Ok(value))
+                    }
+                    lhs_outcome => {
+                        let (rhs, rhs_outcome) = self.explain_expr(req, rule_name, y);
+                        let outcome = if is_and {
+                            and_outcome(lhs_outcome, || rhs_outcome)
+                        } else {
+                            or_outcome(lhs_outcome, || rhs_outcome)
+                        };
+                        (rhs, outcome)
+                    }
+                };
+                let (lhs, rhs) = (Box::new(lhs), Box::new(rhs));
+                let result = outcome.as_ref().ok().copied();
+                let trace = if is_and {
+                    Trace::And { lhs, rhs, result }
+                } else {
+                    Trace::Or { lhs, rhs, result }
+                };
+                (trace, outcome)
+            }
+            Not(x) => {
+                let (operand, outcome) = self.explain_expr(req, rule_name, x);
+                let outcome = outcome.map(|value| !value);
+                let trace = Trace::Not {
+                    operand: Box::new(operand),
+                    result: outcome.as_ref().ok().copied(),
+                };
+                (trace, outcome)
+            }
+        }
+    }
+
+    /// Evaluates a single check. If `details` is given, it is filled with information for
+    /// [Trace::Check].
+    pub(crate) fn evaluate_check(
+        &self,
+        req: &Request,
+        rule_name: &str,
+        lhs: &LeftHandSide,
+        rhs: &str,
+        mut details: Option<&mut CheckDetails>,
+    ) -> Outcome {
         //expand %(foo)s syntax on the right-hand side
-        let Some(rhs) = resolve_target_attr_refs(rhs, req.target) else {
-            //If an interpolated variable is missing, the entire check fails.
-            return false;
+        let raw_rhs = rhs;
+        let rhs = match resolve_target_attr_refs(raw_rhs, req.target) {
+            Ok(rhs) => rhs,
+            Err(err) => {
+                //If an interpolated variable is missing, the entire check fails.
+                if let Some(d) = details {
+                    d.rhs = Some(Err(err));
+                }
+                return Ok(false);
+            }
         };
+        if let Some(d) = details.as_deref_mut() {
+            d.rhs = Some(Ok(rhs.to_string()));
+        }
 
         //option 1: LHS is a literal value
         use LeftHandSide::*;
         let lhs = match lhs {
-            Literal(val) => return val == rhs,
+            Literal(val) => {
+                if let Some(d) = details {
+                    d.method = Some(CheckMethod::Literal);
+                }
+                return Ok(*val == rhs);
+            }
             Identifier(id) => id,
         };
 
         //option 2: LHS is either a checker name or the name of an API attribute
         match self.checkers.get(lhs) {
-            Some(checker) => checker.check(self, req, rhs),
+            Some(checker) => {
+                let tracing = details.is_some();
+                let (outcome, error) =
+                    self.run_checker(req, rule_name, lhs, &**checker, raw_rhs, &rhs, tracing);
+                if let Some(d) = details {
+                    d.method = Some(CheckMethod::Checker {
+                        name: lhs.clone(),
+                        error,
+                    });
+                }
+                outcome
+            }
+            None if self.async_checkers.contains_key(lhs) => {
+                if let Some(d) = details {
+                    d.method = Some(CheckMethod::Checker {
+                        name: lhs.clone(),
+                        error: Some(ASYNC_CHECKER_ERROR.to_owned()),
+                    });
+                }
+                Err(CheckError {
+                    rule_name: rule_name.to_owned(),
+                    check: format!("{lhs}:{raw_rhs}"),
+                    source: ASYNC_CHECKER_ERROR.into(),
+                })
+            }
+            //like in the reference implementation, the Python constants `True` and `False` are
+            //literals (e.g. `True:%(enabled)s` matches if the target is enabled)
+            None if is_bool_literal(lhs) => {
+                if let Some(d) = details {
+                    d.method = Some(CheckMethod::Literal);
+                }
+                Ok(*lhs == rhs)
+            }
             None => {
-                let result = req.token.get_api_attribute(lhs).map(|val| val == rhs);
+                let value = lookup_path(lhs, |k| req.token.get_api_attribute(k));
+                if let Some(d) = details {
+                    d.method = Some(CheckMethod::ApiAttribute {
+                        name: lhs.clone(),
+                        value: value.as_ref().map(|v| v.to_string()),
+                    });
+                }
                 //If the requested API attribute is missing, the entire check fails.
-                result.unwrap_or(false)
+                Ok(value.is_some_and(|val| val.matches(&rhs)))
             }
         }
     }
+
+    /// Executes a checker for [RuleSet::evaluate_check]. If `tracing` is set, memoization is
+    /// skipped (to show the entire evaluation in the trace), and the error message of a failed
+    /// check is returned alongside the outcome.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn run_checker(
+        &self,
+        req: &Request,
+        rule_name: &str,
+        checker_name: &str,
+        checker: &dyn FallibleChecker,
+        raw_rhs: &str,
+        rhs: &str,
+        tracing: bool,
+    ) -> (Outcome, Option<String>) {
+        let memoize = !tracing && checker.is_deterministic();
+        if memoize {
+            if let Some(result) = req.state.memoized_check_result(self, checker_name, rhs) {
+                return (Ok(result), None);
+            }
+        }
+        let checkpoint = req.state.memoization_checkpoint();
+        //the default RuleChecker cannot report why the referenced rule is unknown, so we evaluate
+        //the rule directly to pass on the error of the failed check within it
+        let result = if checker_name == "rule" && self.has_default_rule_checker() {
+            self.try_evaluate(rhs, req).map_err(Into::into)
+        } else {
+            checker.try_check(self, req, rhs)
+        };
+        match result {
+            Ok(result) => {
+                if memoize {
+                    (req.state).memoize_check_result(self, checker_name, rhs, checkpoint, result);
+                } else {
+                    req.state.record_unmemoizable_result();
+                }
+                (Ok(result), None)
+            }
+            Err(source) => match source.downcast::<CheckError>() {
+                //a check in a nested rule failed (e.g. through `rule:` checks), so the trace of the
+                //nested rule shows the error already
+                Ok(err) => (Err(*err), None),
+                Err(source) => {
+                    let message = tracing.then(|| source.to_string());
+                    let err = CheckError {
+                        rule_name: rule_name.to_owned(),
+                        check: format!("{checker_name}:{raw_rhs}"),
+                        source,
+                    };
+                    (Err(err), message)
+                }
+            },
+        }
+    }
 }
 
-///Error type returned by [RuleSet::add_rule].
-///
-///This type hides the internal error type that the policy language parser returns.
+/// The result of evaluating an expression. An error means that the result is unknown because a
+/// check failed.
+pub(crate) type Outcome = Result<bool, CheckError>;
+
+/// Computes `x and y` for [Outcome]s with three-valued logic: If one operand is false, so is the
+/// result, even if the other operand is unknown. `y` is only evaluated if `x` is not false.
+pub(crate) fn and_outcome(x: Outcome, y: impl FnOnce() -> Outcome) -> Outcome {
+    match x {
+        Ok(false) => Ok(false),
+        Ok(true) => y(),
+        Err(err) => match y() {
+            Ok(false) => Ok(false),
+            _ => Err(err),
+        },
+    }
+}
+
+/// Computes `x or y` for [Outcome]s with three-valued logic: If one operand is true, so is the
+/// result, even if the other operand is unknown. `y` is only evaluated if `x` is not true.
+pub(crate) fn or_outcome(x: Outcome, y: impl FnOnce() -> Outcome) -> Outcome {
+    match x {
+        Ok(true) => Ok(true),
+        Ok(false) => y(),
+        Err(err) => match y() {
+            Ok(true) => Ok(true),
+            _ => Err(err),
+        },
+    }
+}
+
+/// The outcome of [RuleSet::decide].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Decision {
+    /// The rule was evaluated and allows the request.
+    Allowed,
+    /// The rule was evaluated and denies the request.
+    Denied,
+    /// The rule was not evaluated because the token does not have one of the scope types of the
+    /// rule. This corresponds to `InvalidScope` in oslo.policy.
+    InvalidScope,
+    /// The rule does not exist, or strict mode is enabled with [RuleSet::set_strict_references]
+    /// and evaluation reached a reference to a rule that does not exist. `rule_name` is the name
+    /// of the rule that does not exist.
+    UnknownRule { rule_name: String },
+}
+
+impl Decision {
+    /// Returns whether this is [Decision::Allowed].
+    pub fn is_allowed(&self) -> bool {
+        *self == Decision::Allowed
+    }
+}
+
+/// Error message for when an [AsyncChecker] is encountered during synchronous evaluation.
+const ASYNC_CHECKER_ERROR: &str = "async checker cannot be used in synchronous evaluation";
+
+/// Returns whether this left-hand side of a check is one of the Python constants `True` and `False`.
+pub(crate) fn is_bool_literal(lhs: &str) -> bool {
+    lhs == "True" || lhs == "False"
+}
+
+/// Information about a check that is collected for [Trace::Check].
+#[derive(Default)]
+pub(crate) struct CheckDetails {
+    rhs: Option<Result<String, InterpolationError>>,
+    method: Option<CheckMethod>,
+}
+
+///Error type returned by [RuleSet::check_cycles].
 #[derive(Error, Debug)]
-#[error("could not parse rule {rule_name:?}: {error}")]
-pub struct ParseError {
-    rule_name: String,
-    error: InternalParseError,
+#[error("rules reference each other in a cycle: {}", .rule_names.join(" -> "))]
+pub struct CycleError {
+    rule_names: Vec<String>,
+}
+
+impl CycleError {
+    /// Returns the names of the rules that form the cycle. The first rule name is repeated at the
+    /// end, e.g. `["a", "b", "a"]` for the rules `a: rule:b` and `b: rule:a`.
+    pub fn rule_names(&self) -> &[String] {
+        &self.rule_names
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::attribute::{flatten_attributes, AttributeValue};
     use crate::request::test::Token;
 
     fn roles(names: &[&str]) -> Vec<String> {
@@ -146,6 +907,9 @@ mod tests {
     fn pair(x: &str, y: &str) -> (String, String) {
         (x.to_owned(), y.to_owned())
     }
+    fn attr(x: &str, y: impl Into<AttributeValue>) -> (String, AttributeValue) {
+        (x.to_owned(), y.into())
+    }
 
     #[test]
     fn test_ruleset_basic() {
@@ -153,7 +917,7 @@ mod tests {
         //<https://github.com/databus23/goslo.policy/blob/81bf2876dbdbdcaecc437bb2eb3549ea0e6b8490/policy_test.go#L11-L47>
         let token = Token {
             roles: roles(&["guest", "member"]),
-            api_attrs: HashMap::from([pair("user_id", "u-1"), pair("project_id", "p-2")]),
+            api_attrs: HashMap::from([attr("user_id", "u-1"), attr("project_id", "p-2")]),
         };
         let target = HashMap::from([
             pair("target.user_id", "u-1"),
@@ -174,11 +938,21 @@ mod tests {
             ("user_id:u-1", true),
             ("user_id:u-2", false),
             ("'u-2':%(user_id)s", true),
-            //NOTE: We do not support non-string literals on the LHS. Putting them in quotes is
-            //functionally identical to what the reference implementation does.
+            ("'u\\x2d2':%(user_id)s", true),
+            //NOTE: We do not support non-string literals on the LHS, except for True and False.
+            //Putting them in quotes is functionally identical to what the reference
+            //implementation does.
             ("'True':%(some_bool)s", true),
+            ("True:%(some_bool)s", true),
+            ("False:%(some_bool)s", false),
             ("'1':%(some_number)s", true),
             ("domain_id:%(does_not_exist)s", false),
+            ("'u-2/1':%(user_id)s/%(some_number)s", true),
+            ("'u-1+u-2':%(target.user_id)s+%(user_id)s", true),
+            ("'1%':%(some_number)d%%", true),
+            ("'1':%(user_id)d", false),
+            ("\"'u-2'\":%(user_id)r", true),
+            ("domain_id:%(does_not_exist)s-%(user_id)s", false),
             ("not (@ or @)", false),
             ("not @ or @", true),
             ("@ and (! or (not !))", true),
@@ -204,13 +978,13 @@ mod tests {
 
         let admin_token = Token {
             roles: roles(&["admin"]),
-            api_attrs: HashMap::from([pair("domain_id", "admin_domain_id")]),
+            api_attrs: HashMap::from([attr("domain_id", "admin_domain_id")]),
         };
         let admin_req = Request::new(&admin_token);
 
         let user_token = Token {
             roles: roles(&["member"]),
-            api_attrs: HashMap::from([pair("user_id", "u-1")]),
+            api_attrs: HashMap::from([attr("user_id", "u-1")]),
         };
         let user_target1 = HashMap::from([pair("user_id", "u-1")]);
         let user_req1 = Request::new(&user_token).with_target(&user_target1);
@@ -252,6 +1026,835 @@ mod tests {
         for (req, rule_name, expected) in test_cases {
             let actual = ruleset.evaluate(rule_name, req);
             assert_eq!(actual, expected, "rule was: {rule_name}");
+            let trace = ruleset.evaluate_explained(rule_name, req);
+            assert_eq!(trace.result(), Some(expected), "rule was: {rule_name}");
+        }
+    }
+
+    #[test]
+    fn test_nested_attributes() {
+        let token = Token {
+            roles: roles(&["member"]),
+            api_attrs: HashMap::from([
+                attr("user_id", "u-1"),
+                attr(
+                    "project",
+                    AttributeValue::from_iter([
+                        ("id", AttributeValue::from("p-1")),
+                        ("domain", AttributeValue::from_iter([("id", "d-1")])),
+                    ]),
+                ),
+                attr("project.name", "flat"),
+            ]),
+        };
+        let target = HashMap::from([
+            attr(
+                "target",
+                AttributeValue::from_iter([(
+                    "project",
+                    AttributeValue::from_iter([("domain_id", "d-1"), ("name", "flat")]),
+                )]),
+            ),
+            attr("target.user_id", "u-1"),
+        ]);
+        let req = Request::new(&token).with_target(&target);
+
+        let test_cases = [
+            ("project.id:p-1", true),
+            ("project.domain.id:d-1", true),
+            ("project.domain.id:%(target.project.domain_id)s", true),
+            ("project.name:%(target.project.name)s", true),
+            ("user_id:%(target.user_id)s", true),
+            ("project.domain.name:d-1", false),
+            ("project.id.foo:p-1", false),
+            ("project.domain:d-1", false),
+            ("'p-1':%(target.project.id)s", false),
+        ];
+        for (rule_str, expected) in test_cases {
+            let mut ruleset = RuleSet::new();
+            ruleset.add_rule("test", rule_str).unwrap();
+            let actual = ruleset.evaluate("test", &req);
+            assert_eq!(actual, expected, "rule was: {rule_str}");
+        }
+
+        //the same should work with flattened attributes
+        let flat_target = flatten_attributes(target);
+        let req = Request::new(&token).with_target(&flat_target);
+        let mut ruleset = RuleSet::new();
+        ruleset
+            .add_rule("test", "project.domain.id:%(target.project.domain_id)s")
+            .unwrap();
+        assert!(ruleset.evaluate("test", &req));
+    }
+
+    #[test]
+    fn test_typed_attributes() {
+        let token = Token {
+            roles: roles(&["member"]),
+            api_attrs: HashMap::from([
+                attr("is_admin", true),
+                attr("group_ids", vec!["g-1", "g-2"]),
+                attr("quota", 10),
+                attr("ratio", 0.5),
+                attr("domain_id", AttributeValue::Null),
+            ]),
+        };
+        let target = HashMap::from([
+            attr("enabled", false),
+            attr("group_id", "g-2"),
+            attr("size", 10),
+            attr("share", 0.5),
+            attr("tags", vec!["foo", "bar"]),
+        ]);
+        let req = Request::new(&token).with_target(&target);
+
+        let test_cases = [
+            ("is_admin:True", true),
+            ("is_admin:true", false),
+            ("is_admin:False", false),
+            ("False:%(enabled)s", true),
+            ("True:%(enabled)s", false),
+            ("group_ids:g-1", true),
+            ("group_ids:%(group_id)s", true),
+            ("group_ids:g-3", false),
+            ("quota:10", true),
+            ("quota:%(size)s", true),
+            ("quota:%(size)d", true),
+            ("'10':%(share)d", false),
+            ("'0':%(share)d", true),
+            ("ratio:0.5", true),
+            ("ratio:%(share)s", true),
+            ("domain_id:None", true),
+            ("'bar':%(tags)s", false),
+            ("\"['foo',\\x20'bar']\":%(tags)s", true),
+        ];
+        for (rule_str, expected) in test_cases {
+            let mut ruleset = RuleSet::new();
+            ruleset.add_rule("test", rule_str).unwrap();
+            let actual = ruleset.evaluate("test", &req);
+            assert_eq!(actual, expected, "rule was: {rule_str}");
+        }
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let mut ruleset = RuleSet::new();
+        let err = ruleset
+            .add_rule("foo", "role:admin and\n\t@ @")
+            .unwrap_err();
+        assert_eq!(err.rule_name(), Some("foo"));
+        assert_eq!(err.rule(), "role:admin and\n\t@ @");
+        assert_eq!((err.line(), err.column(), err.offset()), (2, 4, 18));
+        assert_eq!(err.span(), 18..19);
+        assert_eq!(
+            err.expected().collect::<Vec<_>>(),
+            vec!["\"and\"", "\"or\"", "EOF"]
+        );
+        assert_eq!(
+            err.render(),
+            "could not parse rule \"foo\": error at 2:4: expected one of \"and\", \"or\", EOF\n    \t@ @\n    \t  ^"
+        );
+
+        //errors at the very end of the input have an empty span
+        let err = ruleset.add_rule("bar", "role:admin and").unwrap_err();
+        assert_eq!(err.span(), 14..14);
+        assert_eq!(
+            err.expected().collect::<Vec<_>>(),
+            vec!["\"not\"", "check or opening parenthesis"]
+        );
+        assert_eq!(
+            err.render(),
+            "could not parse rule \"bar\": error at 1:15: expected one of \"not\", check or opening parenthesis\n    role:admin and\n                  ^"
+        );
+    }
+
+    #[test]
+    fn test_add_rules_is_atomic() {
+        let mut ruleset = RuleSet::new();
+        ruleset.add_rule("existing", "role:admin").unwrap();
+
+        let rules = HashMap::from([
+            pair("good", "role:member"),
+            pair("existing", "role:member"),
+            pair("bad2", "role:member or"),
+            pair("bad1", "and role:member"),
+        ]);
+        let errs = ruleset.add_rules(rules).unwrap_err();
+        let names: Vec<_> = errs.iter().map(|e| e.rule_name()).collect();
+        assert_eq!(names, vec![Some("bad1"), Some("bad2")]);
+        assert_eq!(
+            errs.to_string(),
+            [
+                "could not parse 2 rules:",
+                "- could not parse rule \"bad1\": error at 1:1: expected one of \"not\", check or opening parenthesis",
+                "- could not parse rule \"bad2\": error at 1:15: expected one of \"not\", check or opening parenthesis",
+            ]
+            .join("\n")
+        );
+
+        //none of the rules were added, not even the good ones
+        let mut rule_names: Vec<_> = ruleset.rules.keys().collect();
+        rule_names.sort();
+        assert_eq!(rule_names, vec!["existing"]);
+        assert_eq!(ruleset.rules["existing"].to_string(), "role:admin");
+    }
+
+    #[test]
+    fn test_scope_enforcement() {
+        use crate::registry::{RuleRegistry, ScopeType};
+        use crate::request::Token as _;
+
+        let mut registry = RuleRegistry::new();
+        let defaults = [
+            RuleDefault::new("identity:list_domains", "role:reader")
+                .unwrap()
+                .with_scope_types(vec![ScopeType::System]),
+            RuleDefault::new("identity:list_projects", "role:reader")
+                .unwrap()
+                .with_scope_types(vec![ScopeType::System, ScopeType::Domain]),
+            RuleDefault::new("identity:get_project", "role:reader").unwrap(),
+            //scope types only apply to the rule that is being decided, not to referenced rules
+            RuleDefault::new("identity:get_domain", "rule:identity:list_domains").unwrap(),
+        ];
+        for default in defaults {
+            registry.register(default).unwrap();
+        }
+        let mut ruleset = RuleSet::new();
+        ruleset.apply_defaults(&registry);
+
+        let make_token = |scope_attr: Option<(&str, &str)>| Token {
+            roles: roles(&["reader"]),
+            api_attrs: scope_attr.into_iter().map(|(k, v)| attr(k, v)).collect(),
+        };
+        let system_token = make_token(Some(("system_scope", "all")));
+        let domain_token = make_token(Some(("domain_id", "default")));
+        let project_token = make_token(Some(("project_id", "123")));
+        let empty_domain_token = make_token(Some(("domain_id", "")));
+        let unscoped_token = make_token(None);
+        assert_eq!(system_token.scope(), ScopeType::System);
+        assert_eq!(domain_token.scope(), ScopeType::Domain);
+        assert_eq!(project_token.scope(), ScopeType::Project);
+        //empty scope attributes are ignored, and tokens without any scope information are treated
+        //as project-scoped like in oslo.policy
+        assert_eq!(empty_domain_token.scope(), ScopeType::Project);
+        assert_eq!(unscoped_token.scope(), ScopeType::Project);
+
+        let cases = [
+            ("identity:list_domains", &system_token, Decision::Allowed),
+            (
+                "identity:list_domains",
+                &domain_token,
+                Decision::InvalidScope,
+            ),
+            (
+                "identity:list_domains",
+                &project_token,
+                Decision::InvalidScope,
+            ),
+            ("identity:list_projects", &system_token, Decision::Allowed),
+            ("identity:list_projects", &domain_token, Decision::Allowed),
+            (
+                "identity:list_projects",
+                &project_token,
+                Decision::InvalidScope,
+            ),
+            ("identity:get_project", &project_token, Decision::Allowed),
+            ("identity:get_project", &unscoped_token, Decision::Allowed),
+            (
+                "identity:list_projects",
+                &unscoped_token,
+                Decision::InvalidScope,
+            ),
+            (
+                "identity:list_domains",
+                &empty_domain_token,
+                Decision::InvalidScope,
+            ),
+            ("identity:get_domain", &project_token, Decision::Allowed),
+            (
+                "unknown",
+                &system_token,
+                Decision::UnknownRule {
+                    rule_name: "unknown".into(),
+                },
+            ),
+        ];
+        for (rule_name, token, expected) in cases.iter().cloned() {
+            let req = Request::new(token);
+            //without scope enforcement, all tokens with the right roles are allowed
+            ruleset.set_enforce_scope(false);
+            let allowed = rule_name != "unknown";
+            assert_eq!(ruleset.decide(rule_name, &req).is_allowed(), allowed);
+            assert_eq!(ruleset.evaluate(rule_name, &req), allowed);
+
+            ruleset.set_enforce_scope(true);
+            assert_eq!(
+                ruleset.decide(rule_name, &req),
+                expected,
+                "rule {rule_name:?} with scope {:?}",
+                token.scope()
+            );
+        }
+
+        //a token with the wrong scope gets InvalidScope even if the rule would deny it anyway
+        let no_roles = Token {
+            roles: vec![],
+            api_attrs: HashMap::new(),
+        };
+        let req = Request::new(&no_roles);
+        assert_eq!(
+            ruleset.decide("identity:list_domains", &req),
+            Decision::InvalidScope
+        );
+        assert_eq!(
+            ruleset.decide("identity:get_project", &req),
+            Decision::Denied
+        );
+    }
+
+    #[test]
+    fn test_unknown_rules() {
+        let mut ruleset = RuleSet::new();
+        let rules = [
+            pair("a", "rule:b or role:member"),
+            pair("b", "role:admin"),
+            pair("typo", "role:member and rule:bb"),
+            pair("short_circuit", "role:member or rule:bb"),
+            pair("nested_typo", "rule:typo"),
+        ];
+        ruleset.add_rules(rules).unwrap();
+
+        let token = Token {
+            roles: roles(&["member"]),
+            api_attrs: HashMap::new(),
+        };
+        let req = Request::new(&token);
+        let unknown = |name: &str| Decision::UnknownRule {
+            rule_name: name.into(),
+        };
+
+        //unknown rules are reported as such, but unknown references are just false by default
+        assert_eq!(ruleset.decide("a", &req), Decision::Allowed);
+        assert_eq!(ruleset.decide("b", &req), Decision::Denied);
+        assert_eq!(ruleset.decide("c", &req), unknown("c"));
+        assert_eq!(ruleset.decide("typo", &req), Decision::Denied);
+        assert_eq!(ruleset.decide("nested_typo", &req), Decision::Denied);
+        assert!(!ruleset.evaluate("c", &req));
+
+        //in strict mode, unknown references are reported if they are reached
+        ruleset.set_strict_references(true);
+        assert_eq!(ruleset.decide("a", &req), Decision::Allowed);
+        assert_eq!(ruleset.decide("typo", &req), unknown("bb"));
+        assert_eq!(ruleset.decide("nested_typo", &req), unknown("bb"));
+        assert_eq!(ruleset.decide("short_circuit", &req), Decision::Allowed);
+        //evaluate() is unaffected by strict mode, and does not leak into the next decide()
+        assert!(!ruleset.evaluate("typo", &req));
+        assert_eq!(ruleset.decide("b", &req), Decision::Denied);
+    }
+
+    /// A custom checker that fails unless the right-hand side is an integer, and matches if the
+    /// token has at least that many roles.
+    struct RoleCountChecker;
+
+    impl FallibleChecker for RoleCountChecker {
+        fn try_check(
+            &self,
+            _ruleset: &RuleSet,
+            req: &Request,
+            rhs: &str,
+        ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+            let count: usize = rhs.parse()?;
+            Ok((0..count).all(|idx| req.token.has_role(&format!("role{idx}"))))
+        }
+    }
+
+    #[test]
+    fn test_fallible_checkers() {
+        let mut ruleset = RuleSet::new();
+        ruleset.add_checker("role_count", RoleCountChecker);
+        let rules = [
+            pair("valid", "role_count:2"),
+            pair("invalid", "role_count:two and role:role0"),
+            pair("nested", "role:admin or rule:invalid"),
+            pair("negated", "not rule:invalid"),
+            pair("short_circuit", "role:role0 or role_count:two"),
+            pair("irrelevant", "role_count:two or role:role0"),
+            pair("irrelevant_nested", "rule:invalid or role:role1"),
+        ];
+        ruleset.add_rules(rules).unwrap();
+
+        let token = Token {
+            roles: roles(&["role0", "role1"]),
+            api_attrs: HashMap::new(),
+        };
+        let req = Request::new(&token);
+
+        assert_eq!(
+            ruleset.try_decide("valid", &req).unwrap(),
+            Decision::Allowed
+        );
+        assert_eq!(
+            ruleset.try_decide("short_circuit", &req).unwrap(),
+            Decision::Allowed
+        );
+
+        //errors propagate out of nested rules, and name the rule containing the failed check
+        for rule_name in ["invalid", "nested", "negated"] {
+            let err = ruleset.try_decide(rule_name, &req).unwrap_err();
+            assert_eq!(err.rule_name(), "invalid");
+            assert_eq!(err.check(), "role_count:two");
+            assert_eq!(
+                err.to_string(),
+                r#"check "role_count:two" in rule "invalid" failed: invalid digit found in string"#
+            );
+        }
+
+        //errors are not reported if the result does not depend on the failed check
+        for rule_name in ["irrelevant", "irrelevant_nested"] {
+            assert_eq!(
+                ruleset.try_decide(rule_name, &req).unwrap(),
+                Decision::Allowed
+            );
+        }
+
+        //without error reporting, rules whose result depends on a failed check are false, even
+        //when negated
+        assert_eq!(ruleset.decide("invalid", &req), Decision::Denied);
+        assert_eq!(ruleset.decide("negated", &req), Decision::Denied);
+        assert!(!ruleset.evaluate("negated", &req));
+        assert_eq!(ruleset.decide("irrelevant", &req), Decision::Allowed);
+        assert!(!ruleset.evaluate(
+            "nested",
+            &Request::new(&Token {
+                roles: vec![],
+                api_attrs: HashMap::new(),
+            })
+        ));
+        assert_eq!(
+            ruleset.evaluate_explained("negated", &req).to_string(),
+            r#"rule "negated" => unknown
+  not => unknown
+    rule:invalid => unknown (ran checker "rule" with "invalid")
+      rule "invalid" => unknown
+        and => unknown
+          role_count:two => unknown (checker "role_count" with "two" failed: invalid digit found in string)
+          role:role0 => true (ran checker "role" with "role0")
+"#
+        );
+
+        //when called directly, RuleChecker reports rules with unknown results as false
+        assert!(RuleChecker.check(&ruleset, &req, "valid"));
+        assert!(!RuleChecker.check(&ruleset, &req, "negated"));
+    }
+
+    /// A custom checker that works like [RoleChecker], but counts how often it was called.
+    struct CountingRoleChecker(std::sync::Arc<std::sync::atomic::AtomicUsize>);
+
+    impl Checker for CountingRoleChecker {
+        fn check(&self, _ruleset: &RuleSet, req: &Request, rhs: &str) -> bool {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            req.token.has_role(rhs)
+        }
+        fn depends_on_target(&self) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn test_evaluate_many() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let counter = Arc::new(AtomicUsize::new(0));
+        let mut ruleset = RuleSet::new();
+        ruleset.add_checker("role", CountingRoleChecker(Arc::clone(&counter)));
+        let rules = [
+            pair("admin_required", "role:admin or role:cloud_admin"),
+            pair("compute:create", "rule:admin_required or role:member"),
+            pair("compute:delete", "rule:admin_required"),
+            pair("compute:list", "rule:admin_required or role:reader"),
+            pair("compute:typo", "rule:admin_requried"),
+            pair("identity:list", "rule:admin_required"),
+        ];
+        ruleset.add_rules(rules).unwrap();
+
+        let token = Token {
+            roles: roles(&["member"]),
+            api_attrs: HashMap::new(),
+        };
+        let req = Request::new(&token);
+        let decisions = ruleset.evaluate_all("compute:", &req);
+        assert_eq!(
+            decisions.into_iter().collect::<Vec<_>>(),
+            vec![
+                ("compute:create".to_owned(), Decision::Allowed),
+                ("compute:delete".to_owned(), Decision::Denied),
+                ("compute:list".to_owned(), Decision::Denied),
+                ("compute:typo".to_owned(), Decision::Denied),
+            ]
+        );
+        //admin_required was only evaluated once (2 calls), plus one call each for role:member
+        //and role:reader
+        assert_eq!(counter.load(Ordering::SeqCst), 4);
+
+        //results are not memoized across calls
+        let decisions = ruleset.evaluate_many(["compute:delete", "unknown"], &req);
+        assert_eq!(decisions["compute:delete"], Decision::Denied);
+        assert_eq!(
+            decisions["unknown"],
+            Decision::UnknownRule {
+                rule_name: "unknown".into()
+            }
+        );
+        assert_eq!(counter.load(Ordering::SeqCst), 6);
+        assert_eq!(ruleset.evaluate_all("", &req).len(), 6);
+
+        //errors are still reported for each rule that encounters them
+        ruleset.set_strict_references(true);
+        ruleset
+            .add_rule("compute:typo2", "rule:compute:typo")
+            .unwrap();
+        let decisions = ruleset.evaluate_all("compute:typo", &req);
+        for rule_name in ["compute:typo", "compute:typo2"] {
+            assert_eq!(
+                decisions[rule_name],
+                Decision::UnknownRule {
+                    rule_name: "admin_requried".into()
+                }
+            );
+        }
+    }
+
+    /// A custom checker that counts how often it was called, and is not deterministic.
+    struct CountingRandomChecker(std::sync::Arc<std::sync::atomic::AtomicUsize>);
+
+    impl Checker for CountingRandomChecker {
+        fn check(&self, _ruleset: &RuleSet, _req: &Request, _rhs: &str) -> bool {
+            self.0
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+                .is_multiple_of(2)
+        }
+        fn is_deterministic(&self) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn test_memoization() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let role_calls = Arc::new(AtomicUsize::new(0));
+        let random_calls = Arc::new(AtomicUsize::new(0));
+        let mut ruleset = RuleSet::new();
+        ruleset.add_checker("role", CountingRoleChecker(Arc::clone(&role_calls)));
+        ruleset.add_checker("random", CountingRandomChecker(Arc::clone(&random_calls)));
+        let rules = [
+            pair("admin", "role:admin"),
+            pair("a", "role:member and not rule:admin and not role:admin"),
+            pair("b", "rule:a and role:member and random:x"),
+            pair("c", "random:x or random:x"),
+        ];
+        ruleset.add_rules(rules).unwrap();
+        let token = Token {
+            roles: roles(&["member"]),
+            api_attrs: HashMap::new(),
+        };
+
+        //without memoization, every check is executed every time
+        let req = Request::new(&token);
+        assert!(ruleset.evaluate("a", &req));
+        assert!(ruleset.evaluate("b", &req));
+        assert_eq!(role_calls.load(Ordering::SeqCst), 7);
+        assert_eq!(random_calls.load(Ordering::SeqCst), 1);
+
+        //with memoization, deterministic checks are only executed once per request
+        role_calls.store(0, Ordering::SeqCst);
+        random_calls.store(0, Ordering::SeqCst);
+        let req = Request::new(&token).with_memoization();
+        assert!(ruleset.evaluate("a", &req));
+        assert!(ruleset.evaluate("b", &req));
+        assert!(ruleset.evaluate("a", &req));
+        assert_eq!(role_calls.load(Ordering::SeqCst), 2);
+        assert_eq!(random_calls.load(Ordering::SeqCst), 1);
+
+        //non-deterministic checks are always executed, and rules that contain them are not
+        //memoized either
+        assert!(ruleset.evaluate("c", &req));
+        assert!(ruleset.evaluate("c", &req));
+        assert_eq!(random_calls.load(Ordering::SeqCst), 5);
+
+        //traces always show the entire evaluation
+        let trace = ruleset.evaluate_explained("a", &req);
+        assert_eq!(trace.result(), Some(true));
+        assert_eq!(role_calls.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn test_memoization_with_strict_references() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let role_calls = Arc::new(AtomicUsize::new(0));
+        let mut ruleset = RuleSet::new();
+        ruleset.add_checker("role", CountingRoleChecker(Arc::clone(&role_calls)));
+        ruleset.add_rule("x", "role:member").unwrap();
+        ruleset.add_rule("bad", "rule:missing").unwrap();
+        ruleset.set_strict_references(true);
+        let token = Token {
+            roles: roles(&["member"]),
+            api_attrs: HashMap::new(),
+        };
+
+        //an unknown rule in one evaluation does not prevent memoization in later evaluations
+        let req = Request::new(&token).with_memoization();
+        assert!(!ruleset.evaluate("bad", &req));
+        for _ in 0..3 {
+            assert!(ruleset.evaluate("x", &req));
+        }
+        assert_eq!(role_calls.load(Ordering::SeqCst), 1);
+
+        //but the unknown rule is still reported every time
+        let unknown = Decision::UnknownRule {
+            rule_name: "missing".into(),
+        };
+        assert_eq!(ruleset.decide("bad", &req), unknown);
+        assert_eq!(ruleset.decide("x", &req), Decision::Allowed);
+        assert_eq!(ruleset.decide("bad", &req), unknown);
+        assert_eq!(role_calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_memoization_with_multiple_rulesets() {
+        let mut ruleset1 = RuleSet::new();
+        ruleset1.add_rule("a", "role:member").unwrap();
+        ruleset1.add_rule("b", "rule:a").unwrap();
+        let mut ruleset2 = RuleSet::new();
+        ruleset2.add_rule("a", "role:admin").unwrap();
+        ruleset2.add_rule("b", "rule:a").unwrap();
+        let token = Token {
+            roles: roles(&["member"]),
+            api_attrs: HashMap::new(),
+        };
+
+        //results memoized by one RuleSet are not used by another RuleSet with the same rule names
+        let req = Request::new(&token).with_memoization();
+        for _ in 0..2 {
+            assert!(ruleset1.evaluate("b", &req));
+            assert!(!ruleset2.evaluate("b", &req));
+            assert!(!ruleset2.evaluate("a", &req));
+            assert!(ruleset1.evaluate("a", &req));
         }
+        let frozen2 = ruleset2.freeze();
+        assert!(!frozen2.evaluate("b", &req));
+        assert!(ruleset1.evaluate("b", &req));
+    }
+
+    #[test]
+    fn test_parsed_rules() {
+        let token = Token {
+            roles: roles(&["member"]),
+            api_attrs: HashMap::new(),
+        };
+        let req = Request::new(&token);
+
+        let rule: Rule = "role:admin or role:member".parse().unwrap();
+        let mut ruleset1 = RuleSet::new();
+        ruleset1.add_parsed_rule("foo", rule.clone());
+        let mut ruleset2 = RuleSet::new();
+        ruleset2.add_parsed_rule("bar", rule.clone());
+
+        assert!(ruleset1.evaluate("foo", &req));
+        assert!(ruleset2.evaluate("bar", &req));
+        assert_eq!(ruleset1.get_rule("foo"), Some(&rule));
+        assert_eq!(ruleset1.get_rule("bar"), None);
+        assert_eq!(ruleset2.rules().collect::<Vec<_>>(), vec![("bar", &rule)]);
+    }
+
+    /// A custom checker that evaluates a different rule, just like [RuleChecker], but without
+    /// being recognized as such.
+    struct IndirectRuleChecker;
+
+    impl Checker for IndirectRuleChecker {
+        fn check(&self, ruleset: &RuleSet, req: &Request, rhs: &str) -> bool {
+            ruleset.evaluate(rhs, req)
+        }
+    }
+
+    #[test]
+    fn test_cycles() {
+        let token = Token {
+            roles: roles(&["member"]),
+            api_attrs: HashMap::new(),
+        };
+        let req = Request::new(&token);
+
+        //a cycle through `rule:` checks is found by check_cycles()
+        let rules = HashMap::from([
+            pair("a", "role:member and rule:b"),
+            pair("b", "rule:c or rule:d"),
+            pair("c", "role:admin"),
+            pair("d", "not rule:c and rule:a"),
+        ]);
+        let mut ruleset = RuleSet::new();
+        ruleset.add_rules(rules).unwrap();
+        let err = ruleset.check_cycles().unwrap_err();
+        assert_eq!(err.rule_names(), ["a", "b", "d", "a"]);
+        assert_eq!(
+            err.to_string(),
+            "rules reference each other in a cycle: a -> b -> d -> a"
+        );
+
+        //evaluation fails closed instead of overflowing the stack
+        assert!(!ruleset.evaluate("a", &req));
+        assert_eq!(ruleset.evaluate_explained("a", &req).result(), Some(false));
+
+        //no false positives for rules that are referenced from multiple places
+        let rules = HashMap::from([
+            pair("a", "rule:b and rule:c"),
+            pair("b", "rule:c"),
+            pair("c", "role:member"),
+            pair("d", "rule:does_not_exist"),
+        ]);
+        let mut ruleset = RuleSet::new();
+        ruleset.add_rules(rules).unwrap();
+        assert!(ruleset.check_cycles().is_ok());
+        assert!(ruleset.evaluate("a", &req));
+
+        //a cycle through a custom checker is not found, but still fails closed
+        let mut ruleset = RuleSet::new();
+        ruleset.add_checker("indirect", IndirectRuleChecker);
+        ruleset.add_rule("a", "role:member and indirect:a").unwrap();
+        assert!(ruleset.check_cycles().is_ok());
+        assert!(!ruleset.evaluate("a", &req));
+    }
+
+    #[test]
+    fn test_cycles_with_several_branches() {
+        use crate::partial::PartialEvaluation;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let token = Token {
+            roles: roles(&["member"]),
+            api_attrs: HashMap::new(),
+        };
+        let counter = Arc::new(AtomicUsize::new(0));
+        let build_ruleset = |rule: &str| {
+            let mut ruleset = RuleSet::new();
+            ruleset.add_checker("role", CountingRoleChecker(Arc::clone(&counter)));
+            ruleset.add_checker("indirect", IndirectRuleChecker);
+            ruleset.add_rule("a", rule).unwrap();
+            ruleset.add_rule("b", "role:member").unwrap();
+            ruleset
+        };
+
+        //each branch of the cycle would be evaluated again on every level, so once the maximum
+        //nesting depth is exceeded, all other branches must fail immediately (otherwise these
+        //evaluations would take 2^64 steps)
+        for rule in [
+            "role:member and (rule:a or rule:a)",
+            "role:member and (indirect:a or indirect:a)",
+        ] {
+            let ruleset = build_ruleset(rule);
+            let check_count = |action: &dyn Fn()| {
+                counter.store(0, Ordering::SeqCst);
+                action();
+                let count = counter.load(Ordering::SeqCst);
+                assert!(count <= MAX_RULE_DEPTH, "{count} checks for {rule:?}");
+            };
+
+            check_count(&|| assert!(!ruleset.evaluate("a", &Request::new(&token))));
+            check_count(&|| {
+                let req = Request::new(&token).with_memoization();
+                assert!(!ruleset.evaluate("a", &req));
+                assert!(!ruleset.evaluate("a", &req));
+            });
+            check_count(&|| {
+                let trace = ruleset.evaluate_explained("a", &Request::new(&token));
+                assert_eq!(trace.result(), Some(false));
+                let lines = trace.to_string().lines().count();
+                assert!(lines < 10 * MAX_RULE_DEPTH, "trace has {lines} lines");
+            });
+            check_count(&|| {
+                assert_eq!(ruleset.decide("a", &Request::new(&token)), Decision::Denied);
+            });
+            check_count(&|| {
+                //the custom checker might inspect the target, so it can remain in the residual
+                let partial = ruleset.partially_evaluate("a", &Request::new(&token));
+                assert!(!matches!(partial, PartialEvaluation::Allowed));
+            });
+
+            let frozen = build_ruleset(rule).freeze();
+            check_count(&|| assert!(!frozen.evaluate("a", &Request::new(&token))));
+
+            //other rules can still be evaluated for the same request afterwards
+            let req = Request::new(&token);
+            assert!(!ruleset.evaluate("a", &req));
+            assert!(ruleset.evaluate("b", &req));
+        }
+    }
+
+    #[test]
+    fn test_evaluate_explained() {
+        let token = Token {
+            roles: roles(&["member"]),
+            api_attrs: HashMap::from([attr("user_id", "u-1")]),
+        };
+        let target = HashMap::from([pair("user_id", "u-1")]);
+        let req = Request::new(&token).with_target(&target);
+
+        let rules = HashMap::from([
+            pair("admin_required", "role:admin"),
+            pair("owner", "user_id:%(user_id)s and 'u-1':%(user_id)s"),
+            pair(
+                "admin_or_owner",
+                "rule:admin_required or rule:owner or rule:missing",
+            ),
+            pair("not_admin", "not role:admin and domain_id:%(domain_id)s"),
+        ]);
+        let mut ruleset = RuleSet::new();
+        ruleset.add_rules(rules).unwrap();
+
+        let trace = ruleset.evaluate_explained("admin_or_owner", &req);
+        assert_eq!(trace.result(), Some(true));
+        assert_eq!(
+            trace.to_string(),
+            r#"rule "admin_or_owner" => true
+  or => true
+    or => true
+      rule:admin_required => false (ran checker "rule" with "admin_required")
+        rule "admin_required" => false
+          role:admin => false (ran checker "role" with "admin")
+      rule:owner => true (ran checker "rule" with "owner")
+        rule "owner" => true
+          and => true
+            user_id:%(user_id)s => true (compared API attribute "user_id" = "u-1" with "u-1")
+            'u-1':%(user_id)s => true (compared literal with "u-1")
+    rule:missing => skipped
+"#
+        );
+
+        let trace = ruleset.evaluate_explained("not_admin", &req);
+        assert_eq!(trace.result(), Some(false));
+        assert_eq!(
+            trace.to_string(),
+            r#"rule "not_admin" => false
+  and => false
+    not => true
+      role:admin => false (ran checker "role" with "admin")
+    domain_id:%(domain_id)s => false (missing target object attribute "domain_id")
+"#
+        );
+
+        let trace = ruleset.evaluate_explained("does_not_exist", &req);
+        assert_eq!(
+            trace,
+            Trace::Rule {
+                name: "does_not_exist".into(),
+                body: None,
+                result: Some(false),
+            }
+        );
     }
 }
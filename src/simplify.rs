@@ -0,0 +1,351 @@
+/******************************************************************************
+*
+*  Copyright 2023 Stefan Majewsky <majewsky@gmx.net>
+*
+*  Licensed under the Apache License, Version 2.0 (the "License");
+*  you may not use this file except in compliance with the License.
+*  You may obtain a copy of the License at
+*
+*      http://www.apache.org/licenses/LICENSE-2.0
+*
+*  Unless required by applicable law or agreed to in writing, software
+*  distributed under the License is distributed on an "AS IS" BASIS,
+*  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+*  See the License for the specific language governing permissions and
+*  limitations under the License.
+*
+******************************************************************************/
+
+use crate::ast::{Expression, LeftHandSide, Rule};
+
+impl Expression {
+    /// Simplifies this expression without changing its result for any Request. This
+    ///
+    /// - folds constants, e.g. `@ or role:x` becomes `@` and `not !` becomes `@`,
+    /// - removes double negations, e.g. `not not rule:a` becomes `rule:a`,
+    /// - flattens nested chains of `and` and `or`, e.g. `a and (b and c)` becomes `a and b and c`,
+    /// - removes duplicate and absorbed operands, e.g. `a or b or a` becomes `a or b`, and
+    ///   `a or (a and b)` becomes `a`.
+    ///
+    /// Checks whose result does not matter are removed entirely, e.g. `role:x` in `role:x or @`.
+    /// Like for checks that are skipped because of short-circuiting, failures of such checks are not
+    /// reported by [RuleSet::try_decide](crate::RuleSet::try_decide), and neither are references
+    /// to unknown rules in [strict mode](crate::RuleSet::set_strict_references).
+    ///
+    /// Removing duplicates assumes that each check yields the same result when it is evaluated
+    /// twice for the same Request. This does not hold for checkers that are not
+    /// [deterministic](crate::Checker::is_deterministic). Use
+    /// [RuleSet::set_simplify_rules](crate::RuleSet::set_simplify_rules) to take this into
+    /// account.
+    ///
+    /// ```
+    /// # use oslo_policy::Rule;
+    /// let rule: Rule = "not not role:admin or (! and role:reader) or role:admin".parse()?;
+    /// assert_eq!(rule.simplify().to_string(), "role:admin");
+    /// # Ok::<(), oslo_policy::ParseError>(())
+    /// ```
+    pub fn simplify(self) -> Expression {
+        self.simplify_with(&|_, _| true)
+    }
+
+    /// Like [Expression::simplify], but only removes duplicates of checks for which
+    /// `is_deterministic` returns true when called with their left-hand and right-hand side.
+    pub(crate) fn simplify_with(
+        self,
+        is_deterministic: &dyn Fn(&LeftHandSide, &str) -> bool,
+    ) -> Self {
+        use Expression::*;
+        match self {
+            Const(_) | Check(_, _) => self,
+            Not(x) => match x.simplify_with(is_deterministic) {
+                Const(value) => Const(!value),
+                Not(y) => *y,
+                x => Not(Box::new(x)),
+            },
+            And(x, y) => simplify_chain(true, *x, *y, is_deterministic),
+            Or(x, y) => simplify_chain(false, *x, *y, is_deterministic),
+        }
+    }
+}
+
+impl Rule {
+    /// Simplifies this rule's expression with [Expression::simplify].
+    pub fn simplify(self) -> Rule {
+        Rule {
+            expr: self.expr.simplify(),
+        }
+    }
+}
+
+/// Simplifies `x and y` (if `is_and`) or `x or y` (otherwise).
+fn simplify_chain(
+    is_and: bool,
+    x: Expression,
+    y: Expression,
+    is_deterministic: &dyn Fn(&LeftHandSide, &str) -> bool,
+) -> Expression {
+    let mut operands = Vec::new();
+    flatten_into(is_and, x.simplify_with(is_deterministic), &mut operands);
+    flatten_into(is_and, y.simplify_with(is_deterministic), &mut operands);
+
+    let mut kept: Vec<Expression> = Vec::with_capacity(operands.len());
+    for operand in operands {
+        match operand {
+            //`@` does not change the result of an `and` chain, and `!` does not change the result
+            //of an `or` chain...
+            Expression::Const(value) if value == is_and => continue,
+            //...but the respective other constant decides the result of the entire chain
+            Expression::Const(value) => return Expression::Const(value),
+            _ => {}
+        }
+        if !(kept.iter()).any(|earlier| absorbs(is_and, earlier, &operand, is_deterministic)) {
+            kept.push(operand);
+        }
+    }
+
+    //rebuild the chain nested to the left, like the parser does
+    let mut kept = kept.into_iter();
+    let Some(first) = kept.next() else {
+        //all operands were neutral constants
+        return Expression::Const(is_and);
+    };
+    kept.fold(first, |lhs, rhs| {
+        if is_and {
+            Expression::And(Box::new(lhs), Box::new(rhs))
+        } else {
+            Expression::Or(Box::new(lhs), Box::new(rhs))
+        }
+    })
+}
+
+/// Appends the operands of a chain of `and` (if `is_and`) or `or` (otherwise) to `out`.
+fn flatten_into(is_and: bool, expr: Expression, out: &mut Vec<Expression>) {
+    match expr {
+        Expression::And(x, y) if is_and => {
+            flatten_into(is_and, *x, out);
+            flatten_into(is_and, *y, out);
+        }
+        Expression::Or(x, y) if !is_and => {
+            flatten_into(is_and, *x, out);
+            flatten_into(is_and, *y, out);
+        }
+        expr => out.push(expr),
+    }
+}
+
+/// Like [flatten_into], but without taking ownership.
+fn operands_of(is_and: bool, expr: &Expression) -> Vec<&Expression> {
+    match expr {
+        Expression::And(x, y) if is_and => {
+            let mut operands = operands_of(is_and, x);
+            operands.extend(operands_of(is_and, y));
+            operands
+        }
+        Expression::Or(x, y) if !is_and => {
+            let mut operands = operands_of(is_and, x);
+            operands.extend(operands_of(is_and, y));
+            operands
+        }
+        expr => vec![expr],
+    }
+}
+
+/// Returns whether the operand `later` can be removed from a chain of `and` (if `is_and`) or `or`
+/// (otherwise) because of the operand `earlier`.
+///
+/// For example, in `a or ... or (a and b)`, the operand `a and b` is only evaluated if `a` was
+/// false, so it must be false as well. Same for `a and ... and (a or b)` with true instead of false.
+/// Duplicates are the special case where `later` is identical to `earlier`.
+fn absorbs(
+    is_and: bool,
+    earlier: &Expression,
+    later: &Expression,
+    is_deterministic: &dyn Fn(&LeftHandSide, &str) -> bool,
+) -> bool {
+    //this only works if `earlier` yields the same result when evaluated again within `later`
+    let mut deterministic = true;
+    earlier
+        .visit_checks(&mut |lhs, rhs| deterministic = deterministic && is_deterministic(lhs, rhs));
+    if !deterministic {
+        return false;
+    }
+    let later_operands = operands_of(!is_and, later);
+    operands_of(!is_and, earlier)
+        .into_iter()
+        .all(|operand| later_operands.contains(&operand))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::ast::{LeftHandSide, Rule};
+    use crate::checkers::Checker;
+    use crate::request::test::Token;
+    use crate::request::Request;
+    use crate::ruleset::RuleSet;
+
+    fn simplify(input: &str) -> String {
+        let rule: Rule = input.parse().unwrap();
+        rule.simplify().to_string()
+    }
+
+    #[test]
+    fn test_simplify() {
+        let cases = [
+            //constant folding
+            ("@ or role:x", "@"),
+            ("role:x or @", "@"),
+            ("! and role:x", "!"),
+            ("role:x and @", "role:x"),
+            ("role:x or !", "role:x"),
+            ("@ and @", "@"),
+            ("! or !", "!"),
+            ("not @", "!"),
+            ("not (! or role:x and !)", "@"),
+            //double negation
+            ("not not rule:a", "rule:a"),
+            ("not not not rule:a", "not rule:a"),
+            ("not (not rule:a or !)", "rule:a"),
+            //flattening
+            ("a:1 and (b:2 and c:3)", "a:1 and b:2 and c:3"),
+            ("(a:1 or b:2) or (c:3 or d:4)", "a:1 or b:2 or c:3 or d:4"),
+            ("a:1 and (b:2 or c:3)", "a:1 and (b:2 or c:3)"),
+            //duplicates and absorption
+            ("a:1 or b:2 or a:1", "a:1 or b:2"),
+            ("a:1 and a:1", "a:1"),
+            ("a:1 or (a:1 and b:2)", "a:1"),
+            (
+                "(a:1 and b:2) or (b:2 and c:3) or (c:3 and b:2 and a:1)",
+                "a:1 and b:2 or b:2 and c:3",
+            ),
+            ("a:1 and (b:2 or a:1)", "a:1"),
+            ("(a:1 and b:2) or a:1", "a:1 and b:2 or a:1"),
+            ("not a:1 or not a:1", "not a:1"),
+            ("'x':%(x)s or 'x':%(x)s", "'x':%(x)s"),
+            //things that cannot be simplified
+            ("a:1 or not a:1", "a:1 or not a:1"),
+            ("role:admin", "role:admin"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(simplify(input), expected, "input: {input}");
+            //simplification is idempotent, and yields the form that the parser produces
+            let simplified: Rule = expected.parse().unwrap();
+            assert_eq!(simplified.clone().simplify(), simplified, "input: {input}");
+        }
+    }
+
+    #[test]
+    fn test_simplify_with_nondeterministic_checks() {
+        let rule: Rule = "random:1 or random:1 or role:x or role:x".parse().unwrap();
+        let is_deterministic =
+            |lhs: &LeftHandSide, _: &str| *lhs != LeftHandSide::Identifier("random".into());
+        assert_eq!(
+            rule.expr.simplify_with(&is_deterministic).to_string(),
+            "random:1 or random:1 or role:x"
+        );
+    }
+
+    #[test]
+    fn test_simplify_rules_on_load() {
+        let rules = [
+            ("a", "role:a or (role:a and role:b)"),
+            ("b", "not not (role:b and @) or ! and rule:a"),
+            (
+                "c",
+                "(role:c or role:a) and (role:a or role:c or role:b) and not !",
+            ),
+            ("d", "rule:a and rule:a or not (role:c or @)"),
+            ("e", "role:c or rule:b or role:c and rule:d"),
+        ];
+        let original = {
+            let mut ruleset = RuleSet::new();
+            for (name, rule) in rules {
+                ruleset.add_rule(name, rule).unwrap();
+            }
+            ruleset
+        };
+        let simplified = {
+            let mut ruleset = RuleSet::new();
+            ruleset.set_simplify_rules(true);
+            ruleset
+                .add_rules(rules.map(|(k, v)| (k.to_owned(), v.to_owned())))
+                .unwrap();
+            ruleset
+        };
+        assert_eq!(
+            simplified.get_rule("c").unwrap().to_string(),
+            "role:c or role:a"
+        );
+        assert_eq!(simplified.get_rule("d").unwrap().to_string(), "rule:a");
+
+        //the simplified rules yield the same results for every combination of roles
+        for mask in 0..8 {
+            let token = Token {
+                roles: (["a", "b", "c"].into_iter().enumerate())
+                    .filter(|(idx, _)| mask & (1 << idx) != 0)
+                    .map(|(_, role)| role.to_owned())
+                    .collect(),
+                api_attrs: HashMap::new(),
+            };
+            let req = Request::new(&token);
+            for (name, _) in rules {
+                assert_eq!(
+                    simplified.evaluate(name, &req),
+                    original.evaluate(name, &req),
+                    "rule {name:?} with roles {:?}",
+                    token.roles
+                );
+            }
+        }
+    }
+
+    /// A custom checker that is not deterministic.
+    struct RandomChecker;
+
+    impl Checker for RandomChecker {
+        fn check(&self, _ruleset: &RuleSet, _req: &Request, _rhs: &str) -> bool {
+            true
+        }
+        fn is_deterministic(&self) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn test_simplify_rules_with_nondeterministic_checkers() {
+        let mut ruleset = RuleSet::new();
+        ruleset.set_simplify_rules(true);
+        let rules = [
+            ("a", "rule:b or rule:b"),
+            ("b", "role:b or random:x"),
+            ("c", "rule:d or rule:d"),
+            ("d", "role:d"),
+            ("e", "rule:unknown or rule:unknown"),
+        ];
+        //rules are simplified after all of them were added, so `rule:b` can be resolved even
+        //though "b" comes after "a"
+        ruleset
+            .add_rules(rules.map(|(k, v)| (k.to_owned(), v.to_owned())))
+            .unwrap();
+        let get = |ruleset: &RuleSet, name| ruleset.get_rule(name).unwrap().to_string();
+
+        //`random:x` is not known to be non-deterministic yet, but unknown rules are never assumed
+        //to be deterministic
+        assert_eq!(get(&ruleset, "a"), "rule:b");
+        assert_eq!(get(&ruleset, "c"), "rule:d");
+        assert_eq!(get(&ruleset, "e"), "rule:unknown or rule:unknown");
+
+        //when a non-deterministic checker is added, rules that reference it (directly or
+        //indirectly) are simplified again
+        ruleset.add_checker("random", RandomChecker);
+        assert_eq!(get(&ruleset, "a"), "rule:b or rule:b");
+        assert_eq!(get(&ruleset, "c"), "rule:d");
+
+        //same when a referenced rule is replaced
+        ruleset.add_rule("d", "random:y").unwrap();
+        assert_eq!(get(&ruleset, "c"), "rule:d or rule:d");
+        ruleset.add_rule("d", "role:d").unwrap();
+        assert_eq!(get(&ruleset, "c"), "rule:d");
+    }
+}
@@ -0,0 +1,342 @@
+/******************************************************************************
+*
+*  Copyright 2023 Stefan Majewsky <majewsky@gmx.net>
+*
+*  Licensed under the Apache License, Version 2.0 (the "License");
+*  you may not use this file except in compliance with the License.
+*  You may obtain a copy of the License at
+*
+*      http://www.apache.org/licenses/LICENSE-2.0
+*
+*  Unless required by applicable law or agreed to in writing, software
+*  distributed under the License is distributed on an "AS IS" BASIS,
+*  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+*  See the License for the specific language governing permissions and
+*  limitations under the License.
+*
+******************************************************************************/
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use thiserror::Error;
+
+use crate::ast::{Expression, LeftHandSide, Rule};
+use crate::interpolation::as_single_placeholder;
+use crate::partial::PartialEvaluation;
+use crate::ruleset::is_bool_literal;
+
+/// Compiles the result of [partial evaluation](crate::RuleSet::partially_evaluate) into a
+/// parameterized SQL predicate for use in a `WHERE` clause, so that list endpoints can let the
+/// database filter out objects that the policy does not allow.
+///
+/// The caller declares which column holds each target object attribute with
+/// [SqlCompiler::with_column]. Column names are inserted into the SQL verbatim, so they may contain
+/// a table name or quotes, but must never come from untrusted input. Values are always passed as
+/// parameters.
+///
+/// ```
+/// # use oslo_policy::{PartialEvaluation, SqlCompiler};
+/// let residual = "'u-1':%(user_id)s or 'p-2':%(project_id)s".parse()?;
+/// let predicate = SqlCompiler::new()
+///     .with_column("user_id", "servers.user_id")
+///     .with_column("project_id", "servers.project_id")
+///     .compile(&PartialEvaluation::Residual(residual))
+///     .unwrap();
+/// assert_eq!(predicate.sql(), "servers.user_id = ? OR servers.project_id = ?");
+/// assert_eq!(predicate.params(), &["u-1", "p-2"]);
+/// # Ok::<(), oslo_policy::ParseError>(())
+/// ```
+///
+/// Only checks of the form `'value':%(name)s` (plus `True:%(name)s` and `False:%(name)s`) can be
+/// compiled. These are the only checks that partial evaluation produces from API attribute checks.
+/// Each check compiles into a comparison of the column with the string value, so columns should
+/// contain the same string representation that the target object attributes would have during
+/// regular evaluation. NULL columns are treated like missing target object attributes.
+#[derive(Clone, Debug, Default)]
+pub struct SqlCompiler<'a> {
+    columns: HashMap<&'a str, &'a str>,
+    placeholder_style: PlaceholderStyle,
+    parameter_offset: usize,
+}
+
+/// How parameters are referenced within a [SqlPredicate].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PlaceholderStyle {
+    /// `?`, as used by SQLite and MySQL.
+    #[default]
+    QuestionMark,
+    /// `$1`, `$2` and so on, as used by PostgreSQL.
+    Numbered,
+}
+
+impl<'a> SqlCompiler<'a> {
+    /// Returns a new compiler without any columns, using [PlaceholderStyle::QuestionMark].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Declares that the target object attribute `attribute_name` (e.g. `project_id` for
+    /// `%(project_id)s`) is stored in the given column.
+    pub fn with_column(mut self, attribute_name: &'a str, column: &'a str) -> Self {
+        self.columns.insert(attribute_name, column);
+        self
+    }
+
+    /// Chooses how parameters are referenced in the generated SQL.
+    pub fn with_placeholder_style(mut self, style: PlaceholderStyle) -> Self {
+        self.placeholder_style = style;
+        self
+    }
+
+    /// For [PlaceholderStyle::Numbered], declares how many parameters precede the parameters of
+    /// the generated predicate in the surrounding query. For example, with an offset of 2, the
+    /// first parameter is referenced as `$3`.
+    pub fn with_parameter_offset(mut self, offset: usize) -> Self {
+        self.parameter_offset = offset;
+        self
+    }
+
+    /// Compiles the result of partial evaluation into a SQL predicate. Fixed decisions compile
+    /// into predicates that are always true (`1=1`) or always false (`1=0`). Unknown rules are
+    /// reported as [SqlError::UnknownRule].
+    pub fn compile(&self, partial: &PartialEvaluation) -> Result<SqlPredicate, SqlError> {
+        match partial {
+            PartialEvaluation::Allowed => self.compile_expression(&Expression::Const(true)),
+            PartialEvaluation::Denied => self.compile_expression(&Expression::Const(false)),
+            PartialEvaluation::Residual(rule) => self.compile_rule(rule),
+            PartialEvaluation::UnknownRule { rule_name } => Err(SqlError::UnknownRule {
+                rule_name: rule_name.clone(),
+            }),
+        }
+    }
+
+    /// Compiles a residual rule into a SQL predicate.
+    pub fn compile_rule(&self, rule: &Rule) -> Result<SqlPredicate, SqlError> {
+        self.compile_expression(&rule.expr)
+    }
+
+    fn compile_expression(&self, expr: &Expression) -> Result<SqlPredicate, SqlError> {
+        let mut predicate = SqlPredicate {
+            sql: String::new(),
+            params: Vec::new(),
+        };
+        self.write_expr(&mut predicate, expr, false, None)?;
+        Ok(predicate)
+    }
+
+    /// Appends the SQL for `expr` to `out`, or for `not expr` if `negate` is set. Negations are
+    /// pushed down to the individual checks, so that each check can handle NULL columns correctly.
+    /// `parent` is the SQL operator of the surrounding expression, if any.
+    fn write_expr(
+        &self,
+        out: &mut SqlPredicate,
+        expr: &Expression,
+        negate: bool,
+        parent: Option<&str>,
+    ) -> Result<(), SqlError> {
+        use Expression::*;
+        match expr {
+            Const(val) => out.sql.push_str(if *val != negate { "1=1" } else { "1=0" }),
+            Check(lhs, rhs) => self.write_check(out, lhs, rhs, negate)?,
+            Not(x) => self.write_expr(out, x, !negate, parent)?,
+            And(x, y) | Or(x, y) => {
+                //by De Morgan's laws, `not (x and y)` is `not x or not y` and vice versa
+                let op = match (matches!(expr, And(_, _)), negate) {
+                    (true, false) | (false, true) => "AND",
+                    (false, false) | (true, true) => "OR",
+                };
+                //`AND` binds more strongly than `OR`, so we only need parentheses around an `OR`
+                //expression inside an `AND` expression
+                let parens = op == "OR" && parent == Some("AND");
+                if parens {
+                    out.sql.push('(');
+                }
+                self.write_expr(out, x, negate, Some(op))?;
+                write!(out.sql, " {op} ").unwrap();
+                self.write_expr(out, y, negate, Some(op))?;
+                if parens {
+                    out.sql.push(')');
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_check(
+        &self,
+        out: &mut SqlPredicate,
+        lhs: &LeftHandSide,
+        rhs: &str,
+        negate: bool,
+    ) -> Result<(), SqlError> {
+        let value = match lhs {
+            LeftHandSide::Literal(value) => value,
+            LeftHandSide::Identifier(id) if is_bool_literal(id) => id,
+            LeftHandSide::Identifier(_) => {
+                return Err(SqlError::UnsupportedCheck {
+                    check: format!("{lhs}:{rhs}"),
+                })
+            }
+        };
+        let attribute_name =
+            as_single_placeholder(rhs).ok_or_else(|| SqlError::UnsupportedCheck {
+                check: format!("{lhs}:{rhs}"),
+            })?;
+        let column =
+            self.columns
+                .get(attribute_name)
+                .ok_or_else(|| SqlError::UnknownAttribute {
+                    attribute_name: attribute_name.to_owned(),
+                })?;
+
+        out.params.push(value.clone());
+        let placeholder = match self.placeholder_style {
+            PlaceholderStyle::QuestionMark => "?".to_owned(),
+            PlaceholderStyle::Numbered => format!("${}", self.parameter_offset + out.params.len()),
+        };
+        //a missing target object attribute makes the check false, so NULL must not match here,
+        //but must match when negated
+        if negate {
+            write!(out.sql, "({column} IS NULL OR {column} <> {placeholder})").unwrap();
+        } else {
+            write!(out.sql, "{column} = {placeholder}").unwrap();
+        }
+        Ok(())
+    }
+}
+
+/// A parameterized SQL predicate, as returned by [SqlCompiler::compile].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SqlPredicate {
+    sql: String,
+    params: Vec<String>,
+}
+
+impl SqlPredicate {
+    /// Returns the SQL for use in a `WHERE` clause.
+    pub fn sql(&self) -> &str {
+        &self.sql
+    }
+
+    /// Returns the values of the parameters that are referenced by [SqlPredicate::sql], in order.
+    pub fn params(&self) -> &[String] {
+        &self.params
+    }
+
+    /// Returns the SQL and the parameters.
+    pub fn into_parts(self) -> (String, Vec<String>) {
+        (self.sql, self.params)
+    }
+}
+
+/// Error type returned by [SqlCompiler::compile].
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
+pub enum SqlError {
+    /// A check cannot be expressed in SQL, e.g. a checker whose right-hand side references the
+    /// target object (like `rule:%(rule_name)s`), or a right-hand side that is not exactly one
+    /// `%(name)s` placeholder.
+    #[error("cannot compile check {check:?} into SQL")]
+    UnsupportedCheck { check: String },
+    /// No column was declared for a target object attribute, see [SqlCompiler::with_column].
+    #[error("no column was declared for target object attribute {attribute_name:?}")]
+    UnknownAttribute { attribute_name: String },
+    /// Partial evaluation encountered an unknown rule, see [PartialEvaluation::UnknownRule].
+    #[error("rule {rule_name:?} does not exist")]
+    UnknownRule { rule_name: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(compiler: &SqlCompiler, rule: &str) -> Result<(String, Vec<String>), SqlError> {
+        let rule: Rule = rule.parse().unwrap();
+        compiler.compile_rule(&rule).map(SqlPredicate::into_parts)
+    }
+
+    fn params(values: &[&str]) -> Vec<String> {
+        values.iter().map(|&v| v.to_owned()).collect()
+    }
+
+    #[test]
+    fn test_compile() {
+        let compiler = SqlCompiler::new()
+            .with_column("user_id", "user_id")
+            .with_column("project_id", "project_id")
+            .with_column("project.domain_id", "\"domain_id\"")
+            .with_column("is_public", "public");
+
+        let cases = [
+            (
+                "'u-1':%(user_id)s or 'p-2':%(project_id)s",
+                "user_id = ? OR project_id = ?",
+                params(&["u-1", "p-2"]),
+            ),
+            (
+                "('u-1':%(user_id)s or 'u-2':%(user_id)s) and 'd-1':%(project.domain_id)s",
+                "(user_id = ? OR user_id = ?) AND \"domain_id\" = ?",
+                params(&["u-1", "u-2", "d-1"]),
+            ),
+            (
+                "'u-1':%(user_id)s and 'p-1':%(project_id)s or True:%(is_public)s",
+                "user_id = ? AND project_id = ? OR public = ?",
+                params(&["u-1", "p-1", "True"]),
+            ),
+            (
+                "not ('u-1':%(user_id)s and not 'p-1':%(project_id)s)",
+                "(user_id IS NULL OR user_id <> ?) OR project_id = ?",
+                params(&["u-1", "p-1"]),
+            ),
+            (
+                "not ('u-1':%(user_id)s or 'p-1':%(project_id)s) and @",
+                "(user_id IS NULL OR user_id <> ?) AND (project_id IS NULL OR project_id <> ?) AND 1=1",
+                params(&["u-1", "p-1"]),
+            ),
+        ];
+        for (rule, sql, params) in cases {
+            assert_eq!(compile(&compiler, rule), Ok((sql.to_owned(), params)));
+        }
+
+        let compiler = compiler
+            .with_placeholder_style(PlaceholderStyle::Numbered)
+            .with_parameter_offset(1);
+        assert_eq!(
+            compile(&compiler, "'u-1':%(user_id)s or 'p-2':%(project_id)s"),
+            Ok((
+                "user_id = $2 OR project_id = $3".to_owned(),
+                params(&["u-1", "p-2"])
+            ))
+        );
+
+        assert_eq!(
+            compiler.compile(&PartialEvaluation::Allowed).unwrap().sql(),
+            "1=1"
+        );
+        assert_eq!(
+            compiler.compile(&PartialEvaluation::Denied).unwrap().sql(),
+            "1=0"
+        );
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        let compiler = SqlCompiler::new().with_column("user_id", "user_id");
+        assert_eq!(
+            compile(&compiler, "'p-1':%(project_id)s"),
+            Err(SqlError::UnknownAttribute {
+                attribute_name: "project_id".into()
+            })
+        );
+        for rule in [
+            "rule:%(user_id)s",
+            "'u-1':user_%(user_id)s",
+            "'1':%(user_id)d",
+        ] {
+            let err = compile(&compiler, rule).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("cannot compile check {rule:?} into SQL")
+            );
+        }
+    }
+}
@@ -0,0 +1,200 @@
+/******************************************************************************
+*
+*  Copyright 2023 Stefan Majewsky <majewsky@gmx.net>
+*
+*  Licensed under the Apache License, Version 2.0 (the "License");
+*  you may not use this file except in compliance with the License.
+*  You may obtain a copy of the License at
+*
+*      http://www.apache.org/licenses/LICENSE-2.0
+*
+*  Unless required by applicable law or agreed to in writing, software
+*  distributed under the License is distributed on an "AS IS" BASIS,
+*  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+*  See the License for the specific language governing permissions and
+*  limitations under the License.
+*
+******************************************************************************/
+
+use std::borrow::Cow;
+use std::fmt;
+
+use crate::interpolation::InterpolationError;
+
+/// A record of how a policy rule was evaluated, as returned by
+/// [RuleSet::evaluate_explained](crate::RuleSet::evaluate_explained).
+///
+/// A trace is a tree that mirrors the structure of the evaluated rule. The result of each node is
+/// `None` if it is unknown because a [FallibleChecker](crate::FallibleChecker) failed (see
+/// [RuleSet::try_evaluate](crate::RuleSet::try_evaluate)). Its
+/// [Display](fmt::Display) impl renders it as an indented tree that is suitable for log output.
+/// With the `serde` feature enabled, it can also be serialized into any structured format.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Trace {
+    /// The evaluation of a named rule, either because it was requested directly, or because of a
+    /// `rule:` check. If no rule with this name exists, `body` is `None` and the result is false.
+    Rule {
+        name: String,
+        body: Option<Box<Trace>>,
+        result: Option<bool>,
+    },
+    /// The evaluation of a named rule that was not attempted because rules were nested too deeply,
+    /// most likely because of a cycle in the references between rules. The result is false.
+    DepthExceeded { name: String },
+    /// A constant (`@` or `!`).
+    Const { value: bool },
+    /// A check like `role:admin` or `'foo':%(name)s`.
+    Check {
+        /// The check as written in the policy.
+        check: String,
+        /// The right-hand side of the check after interpolation of target object attributes, or
+        /// the reason why it could not be resolved.
+        rhs: Result<String, InterpolationError>,
+        /// How the check was evaluated, or `None` if the right-hand side could not be resolved.
+        method: Option<CheckMethod>,
+        result: Option<bool>,
+        /// Rules that were evaluated by the checker, e.g. the referenced rule of a `rule:` check.
+        nested: Vec<Trace>,
+    },
+    /// A conjunction (`x and y`).
+    And {
+        lhs: Box<Trace>,
+        rhs: Box<Trace>,
+        result: Option<bool>,
+    },
+    /// A disjunction (`x or y`).
+    Or {
+        lhs: Box<Trace>,
+        rhs: Box<Trace>,
+        result: Option<bool>,
+    },
+    /// A negation (`not x`).
+    Not {
+        operand: Box<Trace>,
+        result: Option<bool>,
+    },
+    /// A subexpression that was not evaluated because the result of its surrounding `and` or `or`
+    /// was already decided by the left operand.
+    Skipped { expr: String },
+}
+
+/// How a check was evaluated. Appears within [Trace::Check].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum CheckMethod {
+    /// The left-hand side was a string literal that was compared with the right-hand side.
+    Literal,
+    /// The left-hand side named a [Checker](crate::Checker) that was executed. If it is a
+    /// [FallibleChecker](crate::FallibleChecker) that failed, `error` contains the error message
+    /// and the result is unknown.
+    Checker { name: String, error: Option<String> },
+    /// The left-hand side named an API attribute of the token that was compared with the
+    /// right-hand side. `value` is `None` if the token does not have this attribute. Otherwise, it
+    /// contains the [AttributeValue](crate::AttributeValue) formatted like Python's `str()`, which
+    /// is what the right-hand side was compared with.
+    ApiAttribute { name: String, value: Option<String> },
+}
+
+impl Trace {
+    /// Returns the result of the traced (sub)expression, or `None` if it was skipped or if its
+    /// result is unknown because a check failed.
+    pub fn result(&self) -> Option<bool> {
+        use Trace::*;
+        match self {
+            Rule { result, .. }
+            | Check { result, .. }
+            | And { result, .. }
+            | Or { result, .. }
+            | Not { result, .. } => *result,
+            DepthExceeded { .. } => Some(false),
+            Const { value } => Some(*value),
+            Skipped { .. } => None,
+        }
+    }
+
+    fn fmt_indented(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        use Trace::*;
+        let indent = depth * 2;
+        let result = self
+            .result()
+            .map_or(Cow::Borrowed("unknown"), |r| r.to_string().into());
+        match self {
+            Rule { name, body, .. } => {
+                match body {
+                    Some(_) => writeln!(f, "{:indent$}rule {name:?} => {result}", "")?,
+                    None => writeln!(f, "{:indent$}rule {name:?} => {result} (no such rule)", "")?,
+                }
+                if let Some(body) = body {
+                    body.fmt_indented(f, depth + 1)?;
+                }
+                Ok(())
+            }
+            DepthExceeded { name } => writeln!(
+                f,
+                "{:indent$}rule {name:?} => false (maximum nesting depth exceeded)",
+                ""
+            ),
+            Const { value } => {
+                let expr = if *value { "@" } else { "!" };
+                writeln!(f, "{:indent$}{expr} => {value}", "")
+            }
+            Check {
+                check,
+                rhs,
+                method,
+                nested,
+                ..
+            } => {
+                write!(f, "{:indent$}{check} => {result}", "")?;
+                match (rhs, method) {
+                    (Err(err), _) => write!(f, " ({err})")?,
+                    (Ok(rhs), Some(CheckMethod::Literal)) => {
+                        write!(f, " (compared literal with {rhs:?})")?
+                    }
+                    (Ok(rhs), Some(CheckMethod::Checker { name, error })) => match error {
+                        None => write!(f, " (ran checker {name:?} with {rhs:?})")?,
+                        Some(err) => write!(f, " (checker {name:?} with {rhs:?} failed: {err})")?,
+                    },
+                    (Ok(rhs), Some(CheckMethod::ApiAttribute { name, value })) => match value {
+                        Some(value) => write!(
+                            f,
+                            " (compared API attribute {name:?} = {value:?} with {rhs:?})"
+                        )?,
+                        None => write!(f, " (API attribute {name:?} does not exist)")?,
+                    },
+                    (Ok(rhs), None) => write!(f, " (with {rhs:?})")?,
+                }
+                writeln!(f)?;
+                for trace in nested {
+                    trace.fmt_indented(f, depth + 1)?;
+                }
+                Ok(())
+            }
+            And { lhs, rhs, .. } | Or { lhs, rhs, .. } => {
+                let op = if matches!(self, And { .. }) {
+                    "and"
+                } else {
+                    "or"
+                };
+                writeln!(f, "{:indent$}{op} => {result}", "")?;
+                lhs.fmt_indented(f, depth + 1)?;
+                rhs.fmt_indented(f, depth + 1)
+            }
+            Not { operand, .. } => {
+                writeln!(f, "{:indent$}not => {result}", "")?;
+                operand.fmt_indented(f, depth + 1)
+            }
+            Skipped { expr } => writeln!(f, "{:indent$}{expr} => skipped", ""),
+        }
+    }
+}
+
+impl fmt::Display for Trace {
+    /// Renders the trace as an indented tree with one line per node.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
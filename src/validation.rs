@@ -0,0 +1,255 @@
+/******************************************************************************
+*
+*  Copyright 2023 Stefan Majewsky <majewsky@gmx.net>
+*
+*  Licensed under the Apache License, Version 2.0 (the "License");
+*  you may not use this file except in compliance with the License.
+*  You may obtain a copy of the License at
+*
+*      http://www.apache.org/licenses/LICENSE-2.0
+*
+*  Unless required by applicable law or agreed to in writing, software
+*  distributed under the License is distributed on an "AS IS" BASIS,
+*  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+*  See the License for the specific language governing permissions and
+*  limitations under the License.
+*
+******************************************************************************/
+
+use std::fmt;
+
+use crate::ast::LeftHandSide;
+use crate::interpolation::is_valid_format;
+use crate::ruleset::{is_bool_literal, RuleSet};
+
+/// A problem in a policy that was found by [RuleSet::validate].
+///
+/// None of these problems prevent the policy from being evaluated, but each of them causes a check
+/// to always fail, which is most likely not what the policy author intended.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ValidationProblem {
+    /// The rule `rule_name` contains the check `rule:{referenced_rule}`, but there is no rule with
+    /// that name.
+    UndefinedRule {
+        rule_name: String,
+        referenced_rule: String,
+        /// The name of an existing rule that is similar to `referenced_rule`, if any.
+        suggestion: Option<String>,
+    },
+    /// The rule `rule_name` contains a check whose left-hand side is `identifier`, but this is
+    /// neither the name of a [Checker](crate::Checker) nor of a known API attribute.
+    UnknownIdentifier {
+        rule_name: String,
+        identifier: String,
+        /// The name of a checker or known API attribute that is similar to `identifier`, if any.
+        suggestion: Option<String>,
+    },
+    /// The rule `rule_name` contains the check `check`, whose right-hand side is not a valid format
+    /// string for interpolating target object attributes (or uses unsupported features).
+    UnresolvableRhs { rule_name: String, check: String },
+}
+
+impl fmt::Display for ValidationProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use ValidationProblem::*;
+        let suggestion = match self {
+            UndefinedRule {
+                rule_name,
+                referenced_rule,
+                suggestion,
+            } => {
+                write!(
+                    f,
+                    "rule {rule_name:?} references undefined rule {referenced_rule:?}"
+                )?;
+                suggestion
+            }
+            UnknownIdentifier {
+                rule_name,
+                identifier,
+                suggestion,
+            } => {
+                write!(
+                    f,
+                    "rule {rule_name:?} uses {identifier:?}, which is neither a checker nor a known API attribute"
+                )?;
+                suggestion
+            }
+            UnresolvableRhs { rule_name, check } => {
+                return write!(
+                    f,
+                    "rule {rule_name:?} contains the check {check:?}, whose right-hand side can never be resolved"
+                );
+            }
+        };
+        match suggestion {
+            Some(s) => write!(f, " (did you mean {s:?}?)"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl RuleSet {
+    /// Checks all rules in this RuleSet for mistakes that would cause checks to always fail:
+    ///
+    /// - `rule:` checks that reference rules which do not exist,
+    /// - checks whose left-hand side is neither a registered [Checker](crate::Checker) nor one of
+    ///   the given `api_attributes` (or a dotted path within one of them), and
+    /// - checks whose right-hand side can never be resolved because it is not a valid format string
+    ///   for interpolating target object attributes.
+    ///
+    /// Problems are reported in order of rule name. Where possible, a similarly named rule,
+    /// checker or API attribute is suggested.
+    pub fn validate(&self, api_attributes: &[&str]) -> Vec<ValidationProblem> {
+        let mut rule_names: Vec<&str> = self.rules.keys().map(|n| n.as_str()).collect();
+        rule_names.sort_unstable();
+        let mut identifiers: Vec<&str> = (self.checkers.keys())
+            .chain(self.async_checkers.keys())
+            .map(|n| n.as_str())
+            .collect();
+        identifiers.extend_from_slice(api_attributes);
+        identifiers.sort_unstable();
+
+        let mut problems = Vec::new();
+        for &rule_name in &rule_names {
+            self.rules[rule_name].expr.visit_checks(&mut |lhs, rhs| {
+                if !is_valid_format(rhs) {
+                    problems.push(ValidationProblem::UnresolvableRhs {
+                        rule_name: rule_name.to_owned(),
+                        check: format!("{lhs}:{rhs}"),
+                    });
+                    return;
+                }
+                let LeftHandSide::Identifier(id) = lhs else {
+                    return;
+                };
+                //for dotted paths like `project.domain.id`, it is enough to know `project`
+                let is_known = is_bool_literal(id)
+                    || identifiers.contains(&id.as_str())
+                    || id
+                        .match_indices('.')
+                        .any(|(idx, _)| api_attributes.contains(&&id[..idx]));
+                if !is_known {
+                    problems.push(ValidationProblem::UnknownIdentifier {
+                        rule_name: rule_name.to_owned(),
+                        identifier: id.clone(),
+                        suggestion: suggest(id, &identifiers),
+                    });
+                } else if id == "rule" && !rhs.contains('%') && !self.rules.contains_key(rhs) {
+                    problems.push(ValidationProblem::UndefinedRule {
+                        rule_name: rule_name.to_owned(),
+                        referenced_rule: rhs.to_owned(),
+                        suggestion: suggest(rhs, &rule_names),
+                    });
+                }
+            });
+        }
+        problems
+    }
+}
+
+/// Returns the candidate that is most similar to `input`, unless all candidates are too different
+/// to be plausible typos.
+fn suggest(input: &str, candidates: &[&str]) -> Option<String> {
+    let max_distance = (input.chars().count() / 3).max(1);
+    candidates
+        .iter()
+        .map(|&c| (edit_distance(input, c), c))
+        .filter(|&(d, _)| d <= max_distance)
+        .min()
+        .map(|(_, c)| c.to_owned())
+}
+
+/// Computes the Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    //`row[j]` is the distance between the current prefix of `a` and the first `j` chars of `b`
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("admin", ""), 5);
+        assert_eq!(edit_distance("", "admin"), 5);
+        assert_eq!(edit_distance("admin", "admin"), 0);
+        assert_eq!(edit_distance("admn", "admin"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn test_validate() {
+        let rules = HashMap::from([
+            ("admin_required".to_owned(), "role:admin".to_owned()),
+            (
+                "owner".to_owned(),
+                "user_id:%(user_id)s or usr_id:%(target.user_id)s or project.domain.id:%(domain_id)s"
+                    .to_owned(),
+            ),
+            (
+                "admin_or_owner".to_owned(),
+                "rule:admn_required or rule:owner or rule:%(dynamic)s".to_owned(),
+            ),
+            (
+                "broken".to_owned(),
+                "rle:owner or project_id:%(a)s_%(b)x or not rule:unrelated".to_owned(),
+            ),
+        ]);
+        let mut ruleset = RuleSet::new();
+        ruleset.add_rules(rules).unwrap();
+
+        let problems = ruleset.validate(&["user_id", "project_id", "project"]);
+        assert_eq!(
+            problems,
+            vec![
+                ValidationProblem::UndefinedRule {
+                    rule_name: "admin_or_owner".into(),
+                    referenced_rule: "admn_required".into(),
+                    suggestion: Some("admin_required".into()),
+                },
+                ValidationProblem::UnknownIdentifier {
+                    rule_name: "broken".into(),
+                    identifier: "rle".into(),
+                    suggestion: Some("role".into()),
+                },
+                ValidationProblem::UnresolvableRhs {
+                    rule_name: "broken".into(),
+                    check: "project_id:%(a)s_%(b)x".into(),
+                },
+                ValidationProblem::UndefinedRule {
+                    rule_name: "broken".into(),
+                    referenced_rule: "unrelated".into(),
+                    suggestion: None,
+                },
+                ValidationProblem::UnknownIdentifier {
+                    rule_name: "owner".into(),
+                    identifier: "usr_id".into(),
+                    suggestion: Some("user_id".into()),
+                },
+            ]
+        );
+        assert_eq!(
+            problems[0].to_string(),
+            r#"rule "admin_or_owner" references undefined rule "admn_required" (did you mean "admin_required"?)"#
+        );
+        assert_eq!(
+            problems[2].to_string(),
+            r#"rule "broken" contains the check "project_id:%(a)s_%(b)x", whose right-hand side can never be resolved"#
+        );
+    }
+}
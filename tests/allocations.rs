@@ -0,0 +1,188 @@
+/******************************************************************************
+*
+*  Copyright 2023 Stefan Majewsky <majewsky@gmx.net>
+*
+*  Licensed under the Apache License, Version 2.0 (the "License");
+*  you may not use this file except in compliance with the License.
+*  You may obtain a copy of the License at
+*
+*      http://www.apache.org/licenses/LICENSE-2.0
+*
+*  Unless required by applicable law or agreed to in writing, software
+*  distributed under the License is distributed on an "AS IS" BASIS,
+*  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+*  See the License for the specific language governing permissions and
+*  limitations under the License.
+*
+******************************************************************************/
+
+//! Checks that evaluating a [FrozenRuleSet] does not allocate. This is an integration test
+//! because it replaces the global allocator of the entire test binary.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+
+use oslo_policy::{AttributeValue, Decision, FrozenRuleSet, Request, RuleSet, Target, Token};
+
+/// Counts the allocations made by the current thread, so that tests running in parallel do not
+/// interfere with each other.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+fn record_allocation() {
+    //ignore allocations while the thread is being torn down
+    let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        record_allocation();
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        record_allocation();
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        record_allocation();
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Returns the result of `action` and the number of allocations that it made.
+fn count_allocations<T>(action: impl FnOnce() -> T) -> (T, usize) {
+    let before = ALLOCATIONS.with(Cell::get);
+    let result = action();
+    (result, ALLOCATIONS.with(Cell::get) - before)
+}
+
+/// A token whose attributes are borrowed on lookup.
+struct BorrowingToken {
+    roles: Vec<String>,
+    api_attrs: HashMap<String, AttributeValue>,
+}
+
+impl Token for BorrowingToken {
+    fn get_api_attribute(&self, name: &str) -> Option<Cow<'_, AttributeValue>> {
+        self.api_attrs.get(name).map(Cow::Borrowed)
+    }
+
+    fn has_role(&self, role_name: &str) -> bool {
+        self.roles.iter().any(|r| r == role_name)
+    }
+}
+
+fn build_ruleset() -> FrozenRuleSet {
+    let mut ruleset = RuleSet::new();
+    let rules = [
+        ("admin", "role:admin or is_admin:True"),
+        ("owner", "user_id:%(user_id)s"),
+        ("admin_or_owner", "rule:admin or rule:owner"),
+        ("member", "role:member and project_id:%(project.id)s"),
+        ("same_domain", "domain_id:%(project.domain_id)s"),
+        ("composite", "'p-1/u-1':%(project.id)s/%(user_id)s"),
+        ("composite_api", "project_user:%(project.id)s_%(user_id)s"),
+        ("counted", "'42%':%(count)d%%"),
+        ("group_member", "group_ids:%(group_id)s"),
+        ("role_from_target", "role:%(required_role)s"),
+        ("indirect", "rule:%(rule_name)s"),
+        (
+            "everything",
+            "rule:admin_or_owner and rule:member and rule:same_domain and rule:composite and \
+             rule:composite_api and rule:counted and rule:group_member and \
+             rule:role_from_target and rule:indirect and not rule:missing_attribute",
+        ),
+        (
+            "missing_attribute",
+            "user_id:%(missing)s or 'foo':%(missing)s",
+        ),
+    ];
+    for (name, rule) in rules {
+        ruleset.add_rule(name, rule).unwrap();
+    }
+    ruleset.freeze()
+}
+
+#[test]
+fn test_frozen_evaluation_does_not_allocate() {
+    let frozen = build_ruleset();
+    let token = BorrowingToken {
+        roles: vec!["member".into(), "reader".into()],
+        api_attrs: HashMap::from([
+            ("user_id".into(), "u-1".into()),
+            ("project_id".into(), "p-1".into()),
+            ("domain_id".into(), "d-1".into()),
+            ("project_user".into(), "p-1_u-1".into()),
+            ("is_admin".into(), "False".into()),
+            ("group_ids".into(), AttributeValue::from(vec!["g-1", "g-2"])),
+        ]),
+    };
+    let project = BTreeMap::from([
+        ("id".to_owned(), AttributeValue::from("p-1")),
+        ("domain_id".to_owned(), AttributeValue::from("d-1")),
+    ]);
+    let target: HashMap<String, AttributeValue> = HashMap::from([
+        ("user_id".into(), "u-1".into()),
+        ("project".into(), AttributeValue::Map(project)),
+        ("count".into(), AttributeValue::Int(42)),
+        ("group_id".into(), "g-2".into()),
+        ("required_role".into(), "reader".into()),
+        ("rule_name".into(), "owner".into()),
+    ]);
+    //the same attributes as plain strings, with flattened keys for the nested ones
+    let string_target: HashMap<String, String> = [
+        ("user_id", "u-1"),
+        ("project.id", "p-1"),
+        ("project.domain_id", "d-1"),
+        ("count", "42"),
+        ("group_id", "g-2"),
+        ("required_role", "reader"),
+        ("rule_name", "owner"),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_owned(), v.to_owned()))
+    .collect();
+    let rule_id = frozen.rule_id("everything").unwrap();
+
+    //sanity check for the allocation counter itself
+    let (_, count) = count_allocations(|| frozen.rule_name(rule_id).to_owned());
+    assert_eq!(count, 1);
+
+    let targets: [(&str, &dyn Target); 2] = [
+        ("HashMap<String, AttributeValue>", &target),
+        ("HashMap<String, String>", &string_target),
+    ];
+    for (target_type, target) in targets {
+        let req = Request::new(&token).with_target(target);
+
+        let (result, count) = count_allocations(|| frozen.evaluate_rule(rule_id, &req));
+        assert!(result, "evaluate_rule() failed for {target_type}");
+        assert_eq!(count, 0, "evaluate_rule() allocated for {target_type}");
+
+        let (decision, count) = count_allocations(|| frozen.decide("everything", &req));
+        assert_eq!(decision, Decision::Allowed);
+        assert_eq!(count, 0, "decide() allocated for {target_type}");
+
+        //checks that fail because of a missing target attribute do not allocate either
+        let (result, count) = count_allocations(|| frozen.evaluate("missing_attribute", &req));
+        assert!(!result);
+        assert_eq!(
+            count, 0,
+            "evaluate() allocated for a missing attribute in {target_type}"
+        );
+    }
+}